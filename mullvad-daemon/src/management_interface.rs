@@ -5,7 +5,10 @@ use mullvad_management_interface::{
     Code, Request, Response, Status,
 };
 use mullvad_paths;
-use mullvad_rpc::{rest::Error as RestError, StatusCode};
+use mullvad_rpc::{
+    rest::{ApiErrorCode, Error as RestError},
+    StatusCode,
+};
 #[cfg(not(target_os = "android"))]
 use mullvad_types::settings::DnsOptions;
 use mullvad_types::{
@@ -569,12 +572,16 @@ impl ManagementService for ManagementServiceImpl {
                 })
             })
             .map_err(|e| match e {
-                RestError::ApiError(StatusCode::BAD_REQUEST, message) => match &message.as_str() {
-                    &mullvad_rpc::INVALID_VOUCHER => {
+                RestError::ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    code,
+                    ..
+                } => match code {
+                    ApiErrorCode::InvalidVoucher => {
                         Status::new(Code::NotFound, INVALID_VOUCHER_MESSAGE)
                     }
 
-                    &mullvad_rpc::VOUCHER_USED => {
+                    ApiErrorCode::VoucherUsed => {
                         Status::new(Code::ResourceExhausted, USED_VOUCHER_MESSAGE)
                     }
 
@@ -1620,10 +1627,10 @@ impl Drop for ManagementInterfaceEventBroadcaster {
 // Converts a REST API error for an account into a tonic status.
 fn map_rest_account_error(error: RestError) -> Status {
     match error {
-        RestError::ApiError(status, message)
+        RestError::ApiError { status, code, .. }
             if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
         {
-            Status::new(Code::Unauthenticated, message)
+            Status::new(Code::Unauthenticated, code.to_string())
         }
         _ => Status::internal("internal error"),
     }
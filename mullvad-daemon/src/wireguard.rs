@@ -138,9 +138,7 @@ impl KeyManager {
         async move {
             match rpc.get_wireguard_key(account, &key).await {
                 Ok(_) => Ok(true),
-                Err(mullvad_rpc::rest::Error::ApiError(status, _code))
-                    if status == mullvad_rpc::StatusCode::NOT_FOUND =>
-                {
+                Err(err) if err.api_status() == Some(mullvad_rpc::StatusCode::NOT_FOUND) => {
                     Ok(false)
                 }
                 Err(err) => Err(Self::map_rpc_error(err)),
@@ -173,11 +171,8 @@ impl KeyManager {
                 match response {
                     Ok(addresses) => Ok(addresses),
                     Err(err) => {
-                        let should_retry = if let RestError::ApiError(_status, code) = &err {
-                            code != mullvad_rpc::KEY_LIMIT_REACHED
-                        } else {
-                            true
-                        };
+                        let should_retry = err.api_error_code()
+                            != Some(mullvad_rpc::rest::ApiErrorCode::KeyLimitReached);
                         let _ = error_tx.send(InternalDaemonEvent::WgKeyEvent((
                             error_account,
                             Err(Self::map_rpc_error(err)),
@@ -280,12 +275,11 @@ impl KeyManager {
     fn map_rpc_error(err: mullvad_rpc::rest::Error) -> Error {
         match &err {
             // TODO: Consider handling the invalid account case too.
-            mullvad_rpc::rest::Error::ApiError(status, message)
-                if *status == mullvad_rpc::StatusCode::BAD_REQUEST
-                    && message == mullvad_rpc::KEY_LIMIT_REACHED =>
-            {
-                Error::TooManyKeys
-            }
+            mullvad_rpc::rest::Error::ApiError {
+                status,
+                code: mullvad_rpc::rest::ApiErrorCode::KeyLimitReached,
+                ..
+            } if *status == mullvad_rpc::StatusCode::BAD_REQUEST => Error::TooManyKeys,
             _ => Error::RestError(err),
         }
     }
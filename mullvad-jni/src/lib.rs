@@ -20,7 +20,10 @@ use jnix::{
 use mullvad_daemon::{
     exception_logging, logging, runtime::new_runtime_builder, version, Daemon, DaemonCommandChannel,
 };
-use mullvad_rpc::{rest::Error as RestError, StatusCode};
+use mullvad_rpc::{
+    rest::{ApiErrorCode, Error as RestError},
+    StatusCode,
+};
 use mullvad_types::{
     account::{AccountData, VoucherSubmission},
     settings::DnsOptions,
@@ -77,7 +80,7 @@ impl From<Result<AccountData, daemon_interface::Error>> for GetAccountDataResult
         match result {
             Ok(account_data) => GetAccountDataResult::Ok(account_data),
             Err(error) => match error {
-                daemon_interface::Error::RpcError(RestError::ApiError(status, _code))
+                daemon_interface::Error::RpcError(RestError::ApiError { status, .. })
                     if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
                 {
                     GetAccountDataResult::InvalidAccount
@@ -103,10 +106,10 @@ impl From<Result<VoucherSubmission, daemon_interface::Error>> for VoucherSubmiss
     fn from(result: Result<VoucherSubmission, daemon_interface::Error>) -> Self {
         match result {
             Ok(submission) => VoucherSubmissionResult::Ok(submission),
-            Err(daemon_interface::Error::RpcError(RestError::ApiError(_, code))) => {
-                match code.as_str() {
-                    mullvad_rpc::INVALID_VOUCHER => VoucherSubmissionResult::InvalidVoucher,
-                    mullvad_rpc::VOUCHER_USED => VoucherSubmissionResult::VoucherAlreadyUsed,
+            Err(daemon_interface::Error::RpcError(RestError::ApiError { code, .. })) => {
+                match code {
+                    ApiErrorCode::InvalidVoucher => VoucherSubmissionResult::InvalidVoucher,
+                    ApiErrorCode::VoucherUsed => VoucherSubmissionResult::VoucherAlreadyUsed,
                     _ => VoucherSubmissionResult::RpcError,
                 }
             }
@@ -1000,7 +1003,7 @@ pub extern "system" fn Java_net_mullvad_mullvadvpn_dataproxy_MullvadProblemRepor
 
 fn log_request_error(request: &str, error: &daemon_interface::Error) {
     match error {
-        daemon_interface::Error::RpcError(RestError::Aborted(_)) => {
+        daemon_interface::Error::RpcError(RestError::Cancelled(_)) => {
             log::debug!("Request to {} cancelled", request);
         }
         error => {
@@ -0,0 +1,94 @@
+//! Compares deserializing many small responses with `deserialize_body`, which allocates a new
+//! buffer for every response, and `deserialize_body_into`, which reuses a buffer. The number of
+//! allocations per response is printed before the timings.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use mullvad_rpc::rest::{deserialize_body, deserialize_body_into, Response};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::runtime::{Builder, Runtime};
+
+const BODY: &str = r#"{"ipv4":["193.138.218.78:443","185.65.135.117:443"],"count":2}"#;
+const POLLS: usize = 1000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[derive(serde::Deserialize)]
+struct Addresses {
+    ipv4: [String; 2],
+    count: u32,
+}
+
+fn response() -> Response {
+    Response::new(hyper::Body::from(BODY))
+}
+
+fn runtime() -> Runtime {
+    Builder::new().basic_scheduler().build().unwrap()
+}
+
+async fn poll_with_new_buffers() {
+    for _ in 0..POLLS {
+        let addresses: Addresses = deserialize_body(response()).await.unwrap();
+        assert_eq!(addresses.count, 2);
+    }
+}
+
+async fn poll_with_reused_buffer() {
+    let mut buffer = Vec::new();
+    for _ in 0..POLLS {
+        let addresses: Addresses = deserialize_body_into(response(), &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(addresses.ipv4.len(), 2);
+    }
+}
+
+fn count_allocations(runtime: &mut Runtime, name: &str, future: impl std::future::Future) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    runtime.block_on(future);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{}: {:.2} allocations per response",
+        name,
+        allocations as f64 / POLLS as f64
+    );
+}
+
+fn bench_deserialize_body(c: &mut Criterion) {
+    let mut runtime = runtime();
+    count_allocations(&mut runtime, "deserialize_body", poll_with_new_buffers());
+    count_allocations(
+        &mut runtime,
+        "deserialize_body_into",
+        poll_with_reused_buffer(),
+    );
+
+    c.bench_function("deserialize_body", |b| {
+        b.iter(|| runtime.block_on(poll_with_new_buffers()))
+    });
+    c.bench_function("deserialize_body_into", |b| {
+        b.iter(|| runtime.block_on(poll_with_reused_buffer()))
+    });
+}
+
+criterion_group!(benches, bench_deserialize_body);
+criterion_main!(benches);
@@ -1,16 +1,25 @@
 use super::API_ADDRESS;
-use rand::seq::SliceRandom;
+use chrono::{offset::Utc, DateTime};
+use futures::channel::mpsc;
+use rand::{seq::SliceRandom, Rng};
 use std::{
-    io,
-    net::SocketAddr,
+    collections::{BTreeMap, VecDeque},
+    fmt, io,
+    net::{IpAddr, SocketAddr},
     path::Path,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
     fs,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, AsyncWriteExt},
 };
 
+/// Time window in which dispatched requests are counted by `AddressCache::dispatch_stats`.
+const DISPATCH_STATS_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Version of the format written by `AddressCache::save`.
+const CACHE_FILE_VERSION: u32 = 1;
+
 #[derive(err_derive::Error, Debug)]
 #[error(no_from)]
 pub enum Error {
@@ -22,6 +31,147 @@ pub enum Error {
 
     #[error(display = "The address cache is empty")]
     EmptyAddressCache,
+
+    #[error(display = "Failed to parse the address cache file")]
+    ParseAddressCache(#[error(source)] serde_json::Error),
+
+    #[error(display = "Failed to serialize the address cache")]
+    SerializeAddressCache(#[error(source)] serde_json::Error),
+
+    #[error(display = "Failed to write the address cache file")]
+    WriteAddressCache(#[error(source)] io::Error),
+}
+
+/// The contents of a file written by `AddressCache::save`. Fields that are added by later
+/// versions of the format are ignored.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheFile<A> {
+    version: u32,
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+    addresses: Vec<A>,
+}
+
+/// Decides when failed requests cause the address cache to move on to the next address.
+#[derive(Debug, Clone, Copy)]
+pub struct FailurePolicy {
+    /// Number of failures for the current address, within `window`, that are needed before the
+    /// next address is used.
+    pub threshold: usize,
+    /// Time window in which failures are counted.
+    pub window: Duration,
+}
+
+impl Default for FailurePolicy {
+    /// Moves on to the next address after a single failure.
+    fn default() -> Self {
+        Self {
+            threshold: 1,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Decides when an address that keeps failing is skipped for a while, so that requests don't
+/// spend their whole timeout on it every time.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerPolicy {
+    /// Number of consecutive failures within `window` that open the breaker of an address.
+    pub failure_threshold: usize,
+    /// Time window in which failures are counted.
+    pub window: Duration,
+    /// Time for which an open breaker skips its address before it half-opens.
+    pub cooldown: Duration,
+}
+
+/// The state of the circuit breaker of an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are sent to the address. Contains the number of consecutive failures within the
+    /// window of the policy.
+    Closed { failures: usize },
+    /// The address is skipped until the cooldown has elapsed.
+    Open,
+    /// The cooldown has elapsed and requests are sent to the address again, to probe it. The next
+    /// failure opens the breaker again, and the next success closes it.
+    HalfOpen,
+}
+
+/// The failures of an address that count towards opening its circuit breaker.
+#[derive(Default)]
+struct Breaker {
+    failures: VecDeque<Instant>,
+    opened: Option<Instant>,
+}
+
+impl Breaker {
+    fn state(&self, policy: &CircuitBreakerPolicy, now: Instant) -> BreakerState {
+        match self.opened {
+            Some(opened) if now.duration_since(opened) < policy.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+            None => BreakerState::Closed {
+                failures: self
+                    .failures
+                    .iter()
+                    .filter(|failure| now.duration_since(**failure) < policy.window)
+                    .count(),
+            },
+        }
+    }
+}
+
+/// Decides how newly fetched addresses are tried on a fraction of the requests before they
+/// replace the current addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct CanaryPolicy {
+    /// Fraction of requests, between 0 and 1, that are sent to the canary address.
+    pub fraction: f64,
+    /// Number of requests that must be sent to the canary address before it's evaluated.
+    pub sample_size: usize,
+    /// Fraction of the canary requests, between 0 and 1, that must succeed for the new addresses
+    /// to be promoted. Otherwise, they're discarded.
+    pub success_threshold: f64,
+}
+
+/// New addresses that are staged while one of them is being tried.
+struct Canary {
+    addresses: Vec<SocketAddr>,
+    address: SocketAddr,
+    successes: usize,
+    failures: usize,
+}
+
+/// Emitted when the address used for new requests changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressChanged {
+    pub old: SocketAddr,
+    pub new: SocketAddr,
+    pub reason: AddressChangeReason,
+}
+
+/// Describes why the address used for new requests changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressChangeReason {
+    /// New addresses were fetched from the API.
+    Fetched,
+    /// Requests to the old address failed.
+    Failed,
+    /// Every cached address failed, so the bundled default address is used.
+    Fallback,
+    /// Staged canary addresses were promoted.
+    CanaryPromoted,
+}
+
+impl fmt::Display for AddressChangeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            AddressChangeReason::Fetched => "new addresses were fetched",
+            AddressChangeReason::Failed => "the previous address failed",
+            AddressChangeReason::Fallback => "all cached addresses failed",
+            AddressChangeReason::CanaryPromoted => "new addresses passed the canary test",
+        };
+        f.write_str(description)
+    }
 }
 
 #[derive(Clone)]
@@ -45,30 +195,247 @@ impl AddressCache {
         Ok(address_cache)
     }
 
-    /// Initialize cache using `read_path`, and write changes to `cache_path`.
+    /// Initialize cache using `read_path`, and write changes to `cache_path` in the format written
+    /// by `save`. `read_path` may also contain one address per line, which is the format of the
+    /// bundled address list and of caches written by older versions.
     pub async fn from_file(read_path: &Path, cache_path: Option<Box<Path>>) -> Result<Self, Error> {
         log::debug!("Loading API addresses from {:?}", read_path);
         Self::new(read_address_file(read_path).await?, cache_path)
     }
 
+    /// Initialize cache using a file written by `save`. Malformed addresses in the file are
+    /// skipped. Changes to the cache are not written anywhere until `save` is called again.
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        log::debug!("Loading API addresses from {:?}", path);
+        let contents = fs::read(path).await.map_err(Error::ReadAddressCache)?;
+        let file = serde_json::from_slice(&contents).map_err(Error::ParseAddressCache)?;
+        Self::new(parse_cache_file(file), None)
+    }
+
+    /// Writes the cached addresses to `path`, in a format that can be read by `load`.
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let addresses = self.inner.lock().unwrap().addresses.clone();
+        write_cache_file(path, addresses).await
+    }
+
+    /// Returns the address to send the next request to. If the circuit breaker of the current
+    /// address is open, the next address in the rotation whose breaker isn't open is returned
+    /// instead, without moving on from the current address. If every breaker is open, the current
+    /// address is returned regardless.
     pub fn get_address(&self) -> SocketAddr {
         let mut inner = self.inner.lock().unwrap();
+        if let Some(address) = inner.pick_canary() {
+            return address;
+        }
         inner.last_try = Some(inner.choice);
 
-        Self::get_address_inner(&inner)
+        let now = Instant::now();
+        let current = Self::get_address_inner(&inner);
+        if inner.breaker_state(current, now) != BreakerState::Open {
+            return current;
+        }
+        let next = inner
+            .preferred_addresses(inner.rotation_len())
+            .into_iter()
+            .find(|address| inner.breaker_state(*address, now) != BreakerState::Open);
+        match next {
+            Some(address) => {
+                log::debug!(
+                    "Circuit breaker of API address {} is open. Using {} instead",
+                    current,
+                    address
+                );
+                address
+            }
+            None => current,
+        }
     }
 
-    fn get_address_inner(inner: &AddressCacheInner) -> SocketAddr {
-        if inner.addresses.is_empty() {
-            return API_ADDRESS.into();
+    /// Returns up to `count` addresses in the order in which they will be used, starting with the
+    /// current one.
+    pub fn preferred_addresses(&self, count: usize) -> Vec<SocketAddr> {
+        self.inner.lock().unwrap().preferred_addresses(count)
+    }
+
+    /// Returns every address in the rotation, including the bundled default address, in the order
+    /// in which they will be used, starting with the current one.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let inner = self.inner.lock().unwrap();
+        inner.preferred_addresses(inner.rotation_len())
+    }
+
+    /// Returns the number of requests that were dispatched to each IP address within the last
+    /// five minutes, ordered by IP address. Addresses that weren't sent any requests in that
+    /// window are left out.
+    pub fn dispatch_stats(&self) -> Vec<(IpAddr, u64)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune_dispatches(Instant::now());
+        let mut stats = BTreeMap::new();
+        for (_, address) in &inner.dispatches {
+            *stats.entry(*address).or_insert(0) += 1;
         }
-        *inner
-            .addresses
-            .get(inner.choice % inner.addresses.len())
-            .unwrap_or(&API_ADDRESS.into())
+        stats.into_iter().collect()
+    }
+
+    /// Registers that a request is being sent to `addr`.
+    pub(crate) fn register_dispatch(&self, addr: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.prune_dispatches(now);
+        inner.dispatches.push_back((now, addr.ip()));
+    }
+
+    fn get_address_inner(inner: &AddressCacheInner) -> SocketAddr {
+        inner.address_at(inner.choice)
+    }
+
+    /// Sets a callback that is called whenever the cache falls back on the bundled default
+    /// address, because every address in the cache has failed. The callback receives the number
+    /// of cached addresses that were skipped.
+    pub fn set_fallback_callback(&self, callback: impl Fn(usize) + Send + Sync + 'static) {
+        self.inner.lock().unwrap().fallback_callback = Some(Arc::new(callback));
+    }
+
+    /// Sets the policy that decides how many failures are tolerated before the next address is
+    /// used.
+    pub fn set_failure_policy(&self, policy: FailurePolicy) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failure_policy = policy;
+        inner.recent_failures.clear();
+    }
+
+    /// Returns whether a request to `addr` failed within the window of the failure policy, without
+    /// a successful request to it since.
+    pub fn has_recently_failed(&self, addr: SocketAddr) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .failed_addresses
+            .get(&addr)
+            .map(|failed| failed.elapsed() < inner.failure_policy.window)
+            .unwrap_or(false)
+    }
+
+    /// Sets the policy of the circuit breakers that skip addresses that keep failing. With `None`,
+    /// the default, addresses are never skipped. The failures counted so far are forgotten.
+    pub fn set_circuit_breaker_policy(&self, policy: Option<CircuitBreakerPolicy>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.breaker_policy = policy;
+        inner.breakers.clear();
+    }
+
+    /// Returns the state of the circuit breaker of `addr`. Without a circuit breaker policy, every
+    /// breaker is closed.
+    pub fn breaker_state(&self, addr: SocketAddr) -> BreakerState {
+        self.inner
+            .lock()
+            .unwrap()
+            .breaker_state(addr, Instant::now())
+    }
+
+    /// Returns the addresses whose circuit breakers are not closed, and the states of their
+    /// breakers, ordered by address.
+    pub fn tripped_breakers(&self) -> Vec<(SocketAddr, BreakerState)> {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .breakers
+            .keys()
+            .map(|addr| (*addr, inner.breaker_state(*addr, now)))
+            .filter(|(_, state)| !matches!(state, BreakerState::Closed { .. }))
+            .collect()
+    }
+
+    /// Returns a stream of events that are emitted whenever the address used for new requests
+    /// changes. Events are buffered until they're read, so the stream should be read continuously
+    /// or dropped.
+    pub fn subscribe_address_changes(&self) -> mpsc::UnboundedReceiver<AddressChanged> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.lock().unwrap().change_subscribers.push(tx);
+        rx
+    }
+
+    /// Returns a stream of the cached addresses, which emits the new addresses whenever they're
+    /// replaced by a different set of addresses. Updates that don't change the set of addresses
+    /// are not emitted. Like `subscribe_address_changes`, the stream should be read continuously
+    /// or dropped.
+    pub fn subscribe_addresses(&self) -> mpsc::UnboundedReceiver<Vec<SocketAddr>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.lock().unwrap().address_subscribers.push(tx);
+        rx
+    }
+
+    /// Sets the policy for trying out new addresses before they replace the current ones. With
+    /// `None`, the default, new addresses are used immediately. Any staged addresses are
+    /// discarded.
+    pub fn set_canary_policy(&self, policy: Option<CanaryPolicy>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.canary_policy = policy;
+        inner.canary = None;
+    }
+
+    /// Registers a successful request to `addr`. This evaluates canary addresses, and forgets the
+    /// recent failures of the current address if it is `addr`, so that a brief outage doesn't
+    /// count towards moving on from an otherwise working address.
+    pub fn register_success(&self, addr: SocketAddr) {
+        let promoted = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.canary.as_mut() {
+                Some(canary) if canary.address == addr => canary.successes += 1,
+                _ => {
+                    inner.failed_addresses.remove(&addr);
+                    inner.clear_failures(addr);
+                    if inner.breakers.remove(&addr).is_some() {
+                        log::debug!("Closed the circuit breaker of API address {}", addr);
+                    }
+                    return;
+                }
+            }
+            inner.evaluate_canary()
+        };
+        self.save_promoted(promoted);
     }
 
     pub fn register_failure(&self, failed_addr: SocketAddr, err: &dyn std::error::Error) {
+        let promoted = {
+            let mut inner = self.inner.lock().unwrap();
+            let now = Instant::now();
+            let window = inner.failure_policy.window;
+            inner
+                .failed_addresses
+                .retain(|_, failed| now.duration_since(*failed) < window);
+            inner.failed_addresses.insert(failed_addr, now);
+            inner.register_breaker_failure(failed_addr, now);
+            match inner.canary.as_mut() {
+                Some(canary) if canary.address == failed_addr => {
+                    log::debug!(
+                        "HTTP request to canary API address {} failed: {}",
+                        failed_addr,
+                        err
+                    );
+                    canary.failures += 1;
+                    Some(inner.evaluate_canary())
+                }
+                _ => None,
+            }
+        };
+        if let Some(promoted) = promoted {
+            self.save_promoted(promoted);
+            return;
+        }
+
+        let fallback = self.register_failure_inner(failed_addr, err);
+        if let Some((callback, skipped)) = fallback {
+            callback(skipped);
+        }
+    }
+
+    /// Returns the fallback callback and the number of skipped addresses if the cache fell back
+    /// on the bundled default address.
+    fn register_failure_inner(
+        &self,
+        failed_addr: SocketAddr,
+        err: &dyn std::error::Error,
+    ) -> Option<(Arc<FallbackCallback>, usize)> {
         let mut inner = self.inner.lock().unwrap();
 
         let current_address = Self::get_address_inner(&inner);
@@ -79,30 +446,72 @@ impl AddressCache {
                 .map(|last_try| last_try == inner.choice)
                 .unwrap_or(false)
         {
+            let now = Instant::now();
+            let policy = inner.failure_policy;
+            inner
+                .recent_failures
+                .retain(|failure| now.duration_since(*failure) < policy.window);
+            inner.recent_failures.push(now);
+            if inner.recent_failures.len() < policy.threshold {
+                log::debug!(
+                    "HTTP request failed: {}, using address {}. Tolerating failure {} of {}",
+                    err,
+                    failed_addr,
+                    inner.recent_failures.len(),
+                    policy.threshold
+                );
+                return None;
+            }
+
+            inner.recent_failures.clear();
             inner.choice = inner.choice.wrapping_add(1);
             let new_address = Self::get_address_inner(&inner);
+            let reason = if inner.is_using_fallback() {
+                AddressChangeReason::Fallback
+            } else {
+                AddressChangeReason::Failed
+            };
+            inner.notify_change(current_address, reason);
             log::error!(
                 "HTTP request failed: {}, using address {}. Trying next API address: {}",
                 err,
                 failed_addr,
                 new_address
             );
+
+            if inner.is_using_fallback() {
+                let skipped = inner.addresses.len();
+                log::warn!(
+                    "All {} cached API addresses failed. Falling back on the bundled default address {}",
+                    skipped,
+                    new_address
+                );
+                return inner
+                    .fallback_callback
+                    .clone()
+                    .map(|callback| (callback, skipped));
+            }
         }
+        None
     }
 
-    pub async fn set_addresses(&self, mut addresses: Vec<SocketAddr>) -> io::Result<()> {
+    /// Replaces the cached addresses. If a canary policy is set and `addresses` contains new
+    /// addresses, they're staged and only replace the current addresses once one of the new
+    /// addresses has been tried successfully.
+    pub async fn set_addresses(&self, mut addresses: Vec<SocketAddr>) -> Result<(), Error> {
         let should_update = {
             let mut inner = self.inner.lock().unwrap();
             addresses.sort();
             let mut current_sorted = inner.addresses.clone();
             current_sorted.sort();
-            if addresses != current_sorted {
-                inner.addresses = addresses.clone();
-                inner.shuffle();
-                inner.choice = 0;
-                true
-            } else {
+            if addresses == current_sorted {
+                inner.canary = None;
                 false
+            } else if inner.stage_canary(addresses.clone()) {
+                false
+            } else {
+                inner.apply_addresses(addresses.clone(), AddressChangeReason::Fetched);
+                true
             }
         };
         if should_update {
@@ -112,21 +521,23 @@ impl AddressCache {
         Ok(())
     }
 
-    async fn save_to_disk(&self, addresses: Vec<SocketAddr>) -> io::Result<()> {
-        if let Some(cache_path) = self.cache_path.as_ref() {
-            let mut file = fs::File::create(cache_path).await?;
-            let mut contents = addresses
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<String>>()
-                .join("\n");
-            contents += "\n";
-
-            file.write_all(contents.as_bytes()).await?;
-            file.sync_data().await?;
+    async fn save_to_disk(&self, addresses: Vec<SocketAddr>) -> Result<(), Error> {
+        match self.cache_path.as_ref() {
+            Some(cache_path) => write_cache_file(cache_path, addresses).await,
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Saves promoted canary addresses to disk in the background.
+    fn save_promoted(&self, promoted: Option<Vec<SocketAddr>>) {
+        if let (Some(addresses), Some(_)) = (promoted, &self.cache_path) {
+            let cache = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = cache.save_to_disk(addresses).await {
+                    log::error!("Failed to save promoted API addresses: {}", error);
+                }
+            });
+        }
     }
 }
 
@@ -138,13 +549,32 @@ impl crate::rest::AddressProvider for AddressCache {
     fn clone_box(&self) -> Box<dyn crate::rest::AddressProvider> {
         Box::new(self.clone())
     }
+
+    /// The cache is exhausted once every cached address has failed and it has fallen back on the
+    /// bundled default address.
+    fn is_exhausted(&self) -> bool {
+        self.inner.lock().unwrap().is_using_fallback()
+    }
 }
 
 
+type FallbackCallback = dyn Fn(usize) + Send + Sync;
+
 struct AddressCacheInner {
     addresses: Vec<SocketAddr>,
     choice: usize,
     last_try: Option<usize>,
+    failure_policy: FailurePolicy,
+    recent_failures: Vec<Instant>,
+    failed_addresses: BTreeMap<SocketAddr, Instant>,
+    breaker_policy: Option<CircuitBreakerPolicy>,
+    breakers: BTreeMap<SocketAddr, Breaker>,
+    fallback_callback: Option<Arc<FallbackCallback>>,
+    canary_policy: Option<CanaryPolicy>,
+    canary: Option<Canary>,
+    change_subscribers: Vec<mpsc::UnboundedSender<AddressChanged>>,
+    address_subscribers: Vec<mpsc::UnboundedSender<Vec<SocketAddr>>>,
+    dispatches: VecDeque<(Instant, IpAddr)>,
 }
 
 impl AddressCacheInner {
@@ -156,11 +586,209 @@ impl AddressCacheInner {
             addresses,
             choice: 0,
             last_try: None,
+            failure_policy: FailurePolicy::default(),
+            recent_failures: vec![],
+            failed_addresses: BTreeMap::new(),
+            breaker_policy: None,
+            breakers: BTreeMap::new(),
+            fallback_callback: None,
+            canary_policy: None,
+            canary: None,
+            change_subscribers: vec![],
+            address_subscribers: vec![],
+            dispatches: VecDeque::new(),
         };
         cache.shuffle();
         Ok(cache)
     }
 
+    /// Returns the number of positions in the address rotation. After every address in the cache
+    /// has been tried, the bundled default address is used, unless it's already in the cache.
+    fn rotation_len(&self) -> usize {
+        if self.addresses.contains(&API_ADDRESS.into()) {
+            self.addresses.len()
+        } else {
+            self.addresses.len() + 1
+        }
+    }
+
+    /// Forgets requests that were dispatched before `DISPATCH_STATS_WINDOW`.
+    fn prune_dispatches(&mut self, now: Instant) {
+        while let Some((dispatched, _)) = self.dispatches.front() {
+            if now.duration_since(*dispatched) < DISPATCH_STATS_WINDOW {
+                break;
+            }
+            self.dispatches.pop_front();
+        }
+    }
+
+    fn address_at(&self, choice: usize) -> SocketAddr {
+        self.addresses
+            .get(choice % self.rotation_len())
+            .copied()
+            .unwrap_or_else(|| API_ADDRESS.into())
+    }
+
+    fn preferred_addresses(&self, count: usize) -> Vec<SocketAddr> {
+        (0..count.min(self.rotation_len()))
+            .map(|offset| self.address_at(self.choice.wrapping_add(offset)))
+            .collect()
+    }
+
+    fn apply_addresses(&mut self, addresses: Vec<SocketAddr>, reason: AddressChangeReason) {
+        let old = self.address_at(self.choice);
+        self.addresses = addresses;
+        self.shuffle();
+        self.choice = 0;
+        self.recent_failures.clear();
+        self.canary = None;
+        self.notify_change(old, reason);
+
+        let addresses = &self.addresses;
+        self.address_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(addresses.clone()).is_ok());
+    }
+
+    /// Forgets the recent failures of the current address if it is `addr`.
+    fn clear_failures(&mut self, addr: SocketAddr) {
+        if self.address_at(self.choice) != addr || self.recent_failures.is_empty() {
+            return;
+        }
+        log::debug!(
+            "HTTP request to API address {} succeeded. Forgetting {} recent failures",
+            addr,
+            self.recent_failures.len()
+        );
+        self.recent_failures.clear();
+    }
+
+    fn breaker_state(&self, addr: SocketAddr, now: Instant) -> BreakerState {
+        match (&self.breaker_policy, self.breakers.get(&addr)) {
+            (Some(policy), Some(breaker)) => breaker.state(policy, now),
+            _ => BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    /// Counts a failure of `addr` towards opening its circuit breaker, and opens it once the
+    /// threshold is reached. A failure while the breaker is half-open opens it again.
+    fn register_breaker_failure(&mut self, addr: SocketAddr, now: Instant) {
+        let policy = match self.breaker_policy {
+            Some(policy) => policy,
+            None => return,
+        };
+        let breaker = self.breakers.entry(addr).or_default();
+        match breaker.state(&policy, now) {
+            BreakerState::Open => return,
+            BreakerState::HalfOpen => breaker.opened = Some(now),
+            BreakerState::Closed { .. } => {
+                while let Some(failure) = breaker.failures.front() {
+                    if now.duration_since(*failure) < policy.window {
+                        break;
+                    }
+                    breaker.failures.pop_front();
+                }
+                breaker.failures.push_back(now);
+                if breaker.failures.len() < policy.failure_threshold.max(1) {
+                    return;
+                }
+                breaker.failures.clear();
+                breaker.opened = Some(now);
+            }
+        }
+        log::warn!(
+            "Opened the circuit breaker of API address {} for {:?}",
+            addr,
+            policy.cooldown
+        );
+    }
+
+    /// Notifies the subscribers if the current address is no longer `old`.
+    fn notify_change(&mut self, old: SocketAddr, reason: AddressChangeReason) {
+        let new = self.address_at(self.choice);
+        if old == new || self.change_subscribers.is_empty() {
+            return;
+        }
+        let event = AddressChanged { old, new, reason };
+        self.change_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Stages `addresses` if a canary policy is set and they contain an address that isn't
+    /// already cached. Returns whether the addresses were staged.
+    fn stage_canary(&mut self, addresses: Vec<SocketAddr>) -> bool {
+        if self.canary_policy.is_none() {
+            return false;
+        }
+        let new_addresses: Vec<_> = addresses
+            .iter()
+            .filter(|address| !self.addresses.contains(address))
+            .copied()
+            .collect();
+        let address = match new_addresses.choose(&mut rand::thread_rng()) {
+            Some(address) => *address,
+            None => return false,
+        };
+        log::debug!("Trying new API addresses with canary address {}", address);
+        self.canary = Some(Canary {
+            addresses,
+            address,
+            successes: 0,
+            failures: 0,
+        });
+        true
+    }
+
+    /// Returns the canary address if the request should be sent to it.
+    fn pick_canary(&self) -> Option<SocketAddr> {
+        let fraction = self.canary_policy?.fraction.clamp(0.0, 1.0);
+        let canary = self.canary.as_ref()?;
+        if rand::thread_rng().gen_bool(fraction) {
+            Some(canary.address)
+        } else {
+            None
+        }
+    }
+
+    /// Promotes or discards the staged addresses once enough canary requests have been made.
+    /// Returns the new addresses if they were promoted.
+    fn evaluate_canary(&mut self) -> Option<Vec<SocketAddr>> {
+        let policy = self.canary_policy?;
+        let canary = self.canary.as_ref()?;
+        let total = canary.successes + canary.failures;
+        if total < policy.sample_size.max(1) {
+            return None;
+        }
+
+        let success_rate = canary.successes as f64 / total as f64;
+        let canary = self.canary.take()?;
+        if success_rate >= policy.success_threshold {
+            log::info!(
+                "Canary API address {} succeeded in {} of {} requests. Using new API addresses",
+                canary.address,
+                canary.successes,
+                total
+            );
+            log::trace!("API address cache: {:?}", canary.addresses);
+            self.apply_addresses(
+                canary.addresses.clone(),
+                AddressChangeReason::CanaryPromoted,
+            );
+            Some(canary.addresses)
+        } else {
+            log::warn!(
+                "Canary API address {} succeeded in only {} of {} requests. Discarding new API addresses",
+                canary.address,
+                canary.successes,
+                total
+            );
+            None
+        }
+    }
+
+    fn is_using_fallback(&self) -> bool {
+        self.choice % self.rotation_len() >= self.addresses.len()
+    }
+
     fn shuffle(&mut self) {
         let mut rng = rand::thread_rng();
         (&mut self.addresses[..]).shuffle(&mut rng);
@@ -168,17 +796,19 @@ impl AddressCacheInner {
 }
 
 async fn read_address_file(path: &Path) -> Result<Vec<SocketAddr>, Error> {
-    let file = fs::File::open(path)
+    let mut file = fs::File::open(path)
         .await
         .map_err(|error| Error::OpenAddressCache(error))?;
-    let mut lines = BufReader::new(file).lines();
-    let mut addresses = vec![];
-    while let Some(line) = lines
-        .next_line()
+    let mut contents = vec![];
+    file.read_to_end(&mut contents)
         .await
-        .map_err(|error| Error::ReadAddressCache(error))?
-    {
-        // for line in lines.next_line() {
+        .map_err(|error| Error::ReadAddressCache(error))?;
+    if let Ok(file) = serde_json::from_slice(&contents) {
+        return Ok(parse_cache_file(file));
+    }
+
+    let mut addresses = vec![];
+    for line in String::from_utf8_lossy(&contents).lines() {
         match line.trim().parse() {
             Ok(address) => addresses.push(address),
             Err(err) => {
@@ -188,3 +818,345 @@ async fn read_address_file(path: &Path) -> Result<Vec<SocketAddr>, Error> {
     }
     Ok(addresses)
 }
+
+/// Returns the well-formed addresses in a file written by `AddressCache::save`.
+fn parse_cache_file(file: CacheFile<serde_json::Value>) -> Vec<SocketAddr> {
+    if file.version > CACHE_FILE_VERSION {
+        log::debug!(
+            "Address cache file has version {}, newer than {}",
+            file.version,
+            CACHE_FILE_VERSION
+        );
+    }
+    if let Some(timestamp) = file.timestamp {
+        log::debug!("Address cache file was saved at {}", timestamp);
+    }
+
+    file.addresses
+        .into_iter()
+        .filter_map(|address| {
+            let parsed = address.as_str().and_then(|address| address.parse().ok());
+            if parsed.is_none() {
+                log::error!("Skipping malformed cached address: {}", address);
+            }
+            parsed
+        })
+        .collect()
+}
+
+async fn write_cache_file(path: &Path, addresses: Vec<SocketAddr>) -> Result<(), Error> {
+    let file = CacheFile {
+        version: CACHE_FILE_VERSION,
+        timestamp: Some(Utc::now()),
+        addresses,
+    };
+    let contents = serde_json::to_vec_pretty(&file).map_err(Error::SerializeAddressCache)?;
+
+    let mut file = fs::File::create(path)
+        .await
+        .map_err(Error::WriteAddressCache)?;
+    file.write_all(&contents)
+        .await
+        .map_err(Error::WriteAddressCache)?;
+    file.sync_data().await.map_err(Error::WriteAddressCache)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn canary_cache() -> AddressCache {
+        let cache = AddressCache::new(vec!["10.0.0.1:443".parse().unwrap()], None).unwrap();
+        cache.set_canary_policy(Some(CanaryPolicy {
+            fraction: 1.0,
+            sample_size: 2,
+            success_threshold: 0.5,
+        }));
+        cache
+    }
+
+    #[tokio::test]
+    async fn test_canary_is_promoted() {
+        let cache = canary_cache();
+        let canary: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        cache.set_addresses(vec![canary]).await.unwrap();
+
+        assert_eq!(cache.get_address(), canary);
+        assert!(!cache.addresses().contains(&canary));
+
+        cache.register_success(canary);
+        cache.register_failure(canary, &io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(cache.addresses()[0], canary);
+    }
+
+    #[tokio::test]
+    async fn test_address_changes_are_emitted() {
+        let first: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let cache = AddressCache::new(vec![first], None).unwrap();
+        let mut changes = cache.subscribe_address_changes();
+
+        cache.set_addresses(vec![first]).await.unwrap();
+        cache.set_addresses(vec![second]).await.unwrap();
+        assert_eq!(
+            changes.try_next().unwrap(),
+            Some(AddressChanged {
+                old: first,
+                new: second,
+                reason: AddressChangeReason::Fetched,
+            })
+        );
+
+        cache.get_address();
+        cache.register_failure(second, &io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(
+            changes.try_next().unwrap(),
+            Some(AddressChanged {
+                old: second,
+                new: API_ADDRESS.into(),
+                reason: AddressChangeReason::Fallback,
+            })
+        );
+        assert!(changes.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_address_sets_are_emitted() {
+        let first: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let cache = AddressCache::new(vec![first, second], None).unwrap();
+        let mut addresses = cache.subscribe_addresses();
+
+        cache.set_addresses(vec![second, first]).await.unwrap();
+        assert!(addresses.try_next().is_err());
+
+        cache.set_addresses(vec![second]).await.unwrap();
+        assert_eq!(addresses.try_next().unwrap(), Some(vec![second]));
+        cache.set_addresses(vec![second]).await.unwrap();
+        assert!(addresses.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_canary_is_discarded() {
+        let cache = canary_cache();
+        let current = cache.get_address();
+        let canary: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        cache.set_addresses(vec![canary]).await.unwrap();
+
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+        cache.register_failure(canary, &error);
+        cache.register_failure(canary, &error);
+        assert_eq!(cache.get_address(), current);
+        assert!(!cache.addresses().contains(&canary));
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api-ip-addresses.json");
+        let addresses: Vec<SocketAddr> = vec![
+            "10.0.0.1:443".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        ];
+        let cache = AddressCache::new(addresses.clone(), None).unwrap();
+        cache.save(&path).await.unwrap();
+
+        let mut loaded = AddressCache::load(&path).await.unwrap().addresses();
+        loaded.sort();
+        let mut expected = addresses;
+        expected.push(API_ADDRESS.into());
+        expected.sort();
+        assert_eq!(loaded, expected);
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_written_in_cache_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundled_path = dir.path().join("api-ip-address.txt");
+        let cache_path = dir.path().join("api-ip-address-cache.txt");
+        std::fs::write(&bundled_path, "10.0.0.1:443\nmalformed\n").unwrap();
+
+        let cache = AddressCache::from_file(&bundled_path, Some(cache_path.clone().into()))
+            .await
+            .unwrap();
+        assert_eq!(cache.get_address(), "10.0.0.1:443".parse().unwrap());
+
+        let new_address: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        cache.set_addresses(vec![new_address]).await.unwrap();
+        let cache = AddressCache::load(&cache_path).await.unwrap();
+        assert_eq!(cache.get_address(), new_address);
+        let cache = AddressCache::from_file(&cache_path, None).await.unwrap();
+        assert_eq!(cache.get_address(), new_address);
+    }
+
+    #[tokio::test]
+    async fn test_load_future_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api-ip-addresses.json");
+        let contents = r#"{
+            "version": 7,
+            "timestamp": "2030-01-01T00:00:00Z",
+            "addresses": ["10.0.0.1:443"],
+            "weights": [1.0]
+        }"#;
+        std::fs::write(&path, contents).unwrap();
+
+        let cache = AddressCache::load(&path).await.unwrap();
+        assert_eq!(cache.get_address(), "10.0.0.1:443".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_load_skips_malformed_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api-ip-addresses.json");
+        let contents = r#"{
+            "version": 1,
+            "addresses": ["10.0.0.1:443", "10.0.0.300:443", 17, null, "10.0.0.2"]
+        }"#;
+        std::fs::write(&path, contents).unwrap();
+
+        let cache = AddressCache::load(&path).await.unwrap();
+        assert_eq!(cache.get_address(), "10.0.0.1:443".parse().unwrap());
+
+        std::fs::write(&path, r#"{"version": 1, "addresses": ["garbage"]}"#).unwrap();
+        assert!(matches!(
+            AddressCache::load(&path).await,
+            Err(Error::EmptyAddressCache)
+        ));
+        std::fs::write(&path, "10.0.0.1:443\n").unwrap();
+        assert!(matches!(
+            AddressCache::load(&path).await,
+            Err(Error::ParseAddressCache(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_clears_failures() {
+        let first: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let cache = AddressCache::new(vec![first, second], None).unwrap();
+        cache.set_failure_policy(FailurePolicy {
+            threshold: 2,
+            window: Duration::from_secs(60),
+        });
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+
+        let current = cache.get_address();
+        let other = if current == first { second } else { first };
+        cache.register_failure(current, &error);
+        cache.register_success(other);
+        assert_eq!(cache.inner.lock().unwrap().recent_failures.len(), 1);
+
+        cache.register_success(current);
+        assert!(cache.inner.lock().unwrap().recent_failures.is_empty());
+        cache.register_failure(current, &error);
+        assert_eq!(cache.get_address(), current);
+
+        cache.register_failure(current, &error);
+        assert_eq!(cache.get_address(), other);
+    }
+
+    #[test]
+    fn test_dispatch_stats() {
+        let first: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let cache = AddressCache::new(vec![first, second], None).unwrap();
+        assert!(cache.dispatch_stats().is_empty());
+
+        cache.register_dispatch(second);
+        cache.register_dispatch(first);
+        cache.register_dispatch(second);
+        cache.register_dispatch("10.0.0.2:80".parse().unwrap());
+        assert_eq!(
+            cache.dispatch_stats(),
+            vec![(first.ip(), 1), (second.ip(), 3)]
+        );
+
+        let expired = Instant::now() - DISPATCH_STATS_WINDOW;
+        cache
+            .inner
+            .lock()
+            .unwrap()
+            .dispatches
+            .push_front((expired, first.ip()));
+        assert_eq!(
+            cache.dispatch_stats(),
+            vec![(first.ip(), 1), (second.ip(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let first: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let cache = AddressCache::new(vec![first, second], None).unwrap();
+        // Never move on from the current address, so that only the breaker skips it
+        cache.set_failure_policy(FailurePolicy {
+            threshold: 100,
+            window: Duration::from_secs(60),
+        });
+        let cooldown = Duration::from_millis(100);
+        cache.set_circuit_breaker_policy(Some(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            window: Duration::from_secs(60),
+            cooldown,
+        }));
+        let current = cache.get_address();
+        let other = if current == first { second } else { first };
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+
+        cache.register_failure(current, &error);
+        assert_eq!(
+            cache.breaker_state(current),
+            BreakerState::Closed { failures: 1 }
+        );
+        assert_eq!(cache.get_address(), current);
+
+        cache.register_failure(current, &error);
+        assert_eq!(cache.breaker_state(current), BreakerState::Open);
+        assert_eq!(
+            cache.tripped_breakers(),
+            vec![(current, BreakerState::Open)]
+        );
+        assert_eq!(cache.get_address(), other);
+
+        // A failed probe opens the breaker again
+        std::thread::sleep(cooldown);
+        assert_eq!(cache.breaker_state(current), BreakerState::HalfOpen);
+        assert_eq!(cache.get_address(), current);
+        cache.register_failure(current, &error);
+        assert_eq!(cache.breaker_state(current), BreakerState::Open);
+        assert_eq!(cache.get_address(), other);
+
+        // A successful probe closes it
+        std::thread::sleep(cooldown);
+        assert_eq!(cache.get_address(), current);
+        cache.register_success(current);
+        assert_eq!(
+            cache.breaker_state(current),
+            BreakerState::Closed { failures: 0 }
+        );
+        assert!(cache.tripped_breakers().is_empty());
+    }
+
+    #[test]
+    fn test_all_breakers_open() {
+        let address: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let cache = AddressCache::new(vec![address, API_ADDRESS.into()], None).unwrap();
+        cache.set_failure_policy(FailurePolicy {
+            threshold: 100,
+            window: Duration::from_secs(60),
+        });
+        cache.set_circuit_breaker_policy(Some(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        }));
+
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+        let current = cache.get_address();
+        cache.register_failure(address, &error);
+        cache.register_failure(API_ADDRESS.into(), &error);
+        assert_eq!(cache.get_address(), current);
+    }
+}
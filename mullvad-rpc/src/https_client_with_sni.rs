@@ -1,16 +1,33 @@
+use crate::{
+    socks::Socks5Proxy,
+    tls::{self, PinVerificationError, SpkiPin},
+};
 use http::uri::Scheme;
-use hyper::{client::HttpConnector, service::Service, Uri};
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    service::Service,
+    Uri,
+};
 use hyper_rustls::MaybeHttpsStream;
 use std::{
     fmt,
     future::Future,
     io::{self, BufReader},
+    net::IpAddr,
     pin::Pin,
-    str,
-    sync::Arc,
+    str::{self, FromStr},
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
-use tokio_rustls::rustls;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::rustls::{self, CipherSuite, ProtocolVersion};
 use webpki::DNSNameRef;
 
 // Old LetsEncrypt root certificate
@@ -18,12 +35,55 @@ const OLD_ROOT_CERT: &[u8] = include_bytes!("../old_le_root_cert.pem");
 // New LetsEncrypt root certificate
 const NEW_ROOT_CERT: &[u8] = include_bytes!("../new_le_root_cert.pem");
 
+/// Number of TLS sessions to keep for resumption, which is the rustls default.
+const SESSION_CACHE_SIZE: usize = 32;
+
+/// Message of the `TLSError` that connections fail with when no certificate matches the pins.
+const PIN_MISMATCH_MESSAGE: &str = "No certificate matches the pinned public keys";
+
+/// A TLS protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol_version(self) -> ProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => ProtocolVersion::TLSv1_2,
+            TlsVersion::Tls13 => ProtocolVersion::TLSv1_3,
+        }
+    }
+}
+
+/// Errors from configuring the TLS versions and cipher suites of `HttpsConnectorWithSni`.
+#[derive(err_derive::Error, Debug)]
+pub enum TlsConfigError {
+    #[error(display = "The cipher suite {:?} is not supported", _0)]
+    UnsupportedCipherSuite(CipherSuite),
+
+    #[error(
+        display = "None of the allowed cipher suites can be used with {:?}",
+        _0
+    )]
+    NoUsableCipherSuite(TlsVersion),
+}
+
+/// The certificate of the server is not valid for the hostname that the connection was made for.
+#[derive(err_derive::Error, Debug)]
+#[error(display = "The server certificate is not valid for {}", _0)]
+pub struct HostnameVerificationError(pub String);
+
 /// A Connector for the `https` scheme.
 #[derive(Clone)]
 pub struct HttpsConnectorWithSni {
     sni_hostname: Option<String>,
     http: HttpConnector,
     tls: Arc<rustls::ClientConfig>,
+    verify_hostname: bool,
+    spki_pins: Option<Arc<[SpkiPin]>>,
+    socks5_proxy: Arc<Mutex<Option<Socks5Proxy>>>,
 }
 
 impl HttpsConnectorWithSni {
@@ -33,9 +93,12 @@ impl HttpsConnectorWithSni {
     ///
     /// This uses hyper's default `HttpConnector`, and default `TlsConnector`.
     /// If you wish to use something besides the defaults, use `From::from`.
+    /// `TCP_NODELAY` is enabled, since API requests are small and would otherwise be delayed by
+    /// Nagle's algorithm while waiting for the acknowledgement of previous segments.
     pub fn new() -> Self {
         let mut http = HttpConnector::new();
         http.enforce_http(false);
+        http.set_nodelay(true);
 
         let mut config = rustls::ClientConfig::new();
         config.enable_sni = true;
@@ -72,14 +135,144 @@ impl HttpsConnectorWithSni {
     pub fn set_sni_hostname(&mut self, hostname: Option<String>) {
         self.sni_hostname = hostname;
     }
+
+    /// Configure the minimum TLS version and the cipher suites that are allowed for connections.
+    ///
+    /// By default, TLS 1.2 and 1.3 are allowed, together with all cipher suites supported by
+    /// rustls. These all provide forward secrecy and authenticated encryption, but hardened
+    /// deployments may want to require TLS 1.3, which also encrypts more of the handshake and
+    /// removes legacy negotiation, and to restrict the cipher suites further. Use `None` to allow
+    /// all supported cipher suites.
+    ///
+    /// Fails if a cipher suite isn't supported, or if none of the allowed cipher suites can be
+    /// used with the allowed TLS versions.
+    pub fn set_tls_options(
+        &mut self,
+        min_version: TlsVersion,
+        cipher_suites: Option<&[CipherSuite]>,
+    ) -> Result<(), TlsConfigError> {
+        let versions: Vec<ProtocolVersion> = [TlsVersion::Tls13, TlsVersion::Tls12]
+            .iter()
+            .filter(|version| **version >= min_version)
+            .map(|version| version.protocol_version())
+            .collect();
+
+        let supported_suites = match cipher_suites {
+            Some(cipher_suites) => cipher_suites
+                .iter()
+                .map(|suite| {
+                    rustls::ALL_CIPHERSUITES
+                        .iter()
+                        .find(|supported| supported.suite == *suite)
+                        .copied()
+                        .ok_or(TlsConfigError::UnsupportedCipherSuite(*suite))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => rustls::ALL_CIPHERSUITES.to_vec(),
+        };
+        let ciphersuites: Vec<_> = supported_suites
+            .into_iter()
+            .filter(|suite| {
+                versions
+                    .iter()
+                    .any(|version| suite.usable_for_version(*version))
+            })
+            .collect();
+        if ciphersuites.is_empty() {
+            return Err(TlsConfigError::NoUsableCipherSuite(min_version));
+        }
+
+        let config = Arc::make_mut(&mut self.tls);
+        config.versions = versions;
+        config.ciphersuites = ciphersuites;
+        Ok(())
+    }
+
+    /// Configure whether the certificate of the server is explicitly verified against the
+    /// hostname, which is the SNI hostname if one is set and the host of the URI otherwise. Since
+    /// connections are made to IP addresses, this never falls back on the IP address. Connections
+    /// to servers whose certificates aren't valid for the hostname fail with a
+    /// `HostnameVerificationError`.
+    ///
+    /// This guards against TLS configurations that skip the verification, and must be enabled in
+    /// production. Enabled by default.
+    pub fn set_verify_hostname(&mut self, verify_hostname: bool) {
+        self.verify_hostname = verify_hostname;
+        self.update_verifier();
+    }
+
+    /// Configure the public keys that the certificate chain of the server must contain one of,
+    /// in addition to being trusted. Connections to servers that don't match any of the pins fail
+    /// with a `PinVerificationError`. Use `None`, the default, to not pin any keys.
+    pub fn set_spki_pins(&mut self, pins: Option<Vec<SpkiPin>>) {
+        self.spki_pins = pins.map(Arc::from);
+        self.update_verifier();
+    }
+
+    /// Installs a certificate verifier with the current hostname and pin settings. Stored TLS
+    /// sessions were verified with the previous settings, so they are forgotten rather than
+    /// resumed.
+    fn update_verifier(&mut self) {
+        let verifier = ApiCertVerifier::new(self.verify_hostname, self.spki_pins.clone());
+        let config = Arc::make_mut(&mut self.tls);
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+        config.session_persistence = rustls::ClientSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    }
+
+    /// Configure a SOCKS5 proxy to tunnel new connections through, or `None` to connect directly.
+    ///
+    /// Unlike the other options, the proxy is shared with all clones of the connector, including
+    /// the ones used by request services that have already been created, so that the proxy can be
+    /// switched without recreating them. Existing connections are not affected. Connections are
+    /// still made to the API addresses from the point of view of the request service, so failures
+    /// to connect through the proxy are registered for the API address.
+    pub fn set_socks5_proxy(&self, proxy: Option<Socks5Proxy>) {
+        *self.socks5_proxy.lock().unwrap() = proxy;
+    }
+
+    /// Configure a local address to bind outgoing connections to.
+    ///
+    /// Binding to an unspecified IPv4 or IPv6 address restricts connections to that address
+    /// family. Use `None` to let the OS choose.
+    pub fn set_local_address(&mut self, address: Option<IpAddr>) {
+        self.http.set_local_address(address);
+    }
+
+    /// Configure whether `TCP_NODELAY` is set on outgoing connections, disabling Nagle's
+    /// algorithm. Enabled by default.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.http.set_nodelay(nodelay);
+    }
+
+    /// Configure whether `SO_REUSEADDR` is set on outgoing sockets, which allows quickly
+    /// reconnecting from a local address that was recently used. This mainly matters when
+    /// binding to a local address with `set_local_address`. Disabled by default.
+    pub fn set_reuse_address(&mut self, reuse_address: bool) {
+        self.http.set_reuse_address(reuse_address);
+    }
+}
+
+impl Default for HttpsConnectorWithSni {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl From<(HttpConnector, rustls::ClientConfig)> for HttpsConnectorWithSni {
     fn from(args: (HttpConnector, rustls::ClientConfig)) -> HttpsConnectorWithSni {
+        let (http, mut config) = args;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(ApiCertVerifier::new(true, None)));
         HttpsConnectorWithSni {
             sni_hostname: None,
-            http: args.0,
-            tls: Arc::new(args.1),
+            http,
+            tls: Arc::new(config),
+            verify_hostname: true,
+            spki_pins: None,
+            socks5_proxy: Arc::new(Mutex::new(None)),
         }
     }
 }
@@ -90,8 +283,64 @@ impl fmt::Debug for HttpsConnectorWithSni {
     }
 }
 
+/// Time spent establishing a connection. It's added to the extensions of every response that is
+/// received over a connection made by `HttpsConnectorWithSni`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTiming {
+    /// Time it took to establish the TCP connection.
+    pub tcp_connect: Duration,
+    /// Time it took to complete the TLS handshake, after the TCP connection was established.
+    pub tls_handshake: Duration,
+}
+
+/// A stream that carries the time it took to establish it.
+pub struct TimedStream {
+    stream: MaybeHttpsStream<TcpStream>,
+    timing: ConnectionTiming,
+}
+
+impl TimedStream {
+    pub fn timing(&self) -> ConnectionTiming {
+        self.timing
+    }
+}
+
+impl Connection for TimedStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected().extra(self.timing)
+    }
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
 impl Service<Uri> for HttpsConnectorWithSni {
-    type Response = MaybeHttpsStream<tokio::net::TcpStream>;
+    type Response = TimedStream;
     type Error = io::Error;
     type Future =
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
@@ -103,6 +352,9 @@ impl Service<Uri> for HttpsConnectorWithSni {
     fn call(&mut self, uri: Uri) -> Self::Future {
         let tls_connector: tokio_rustls::TlsConnector = self.tls.clone().into();
         let mut http = self.http.clone();
+        let verify_hostname = self.verify_hostname;
+        let socks5_proxy = self.socks5_proxy.lock().unwrap().clone();
+        let connected_peer = crate::rest::connected_peer();
         let sni_hostname = self
             .sni_hostname
             .clone()
@@ -122,26 +374,384 @@ impl Service<Uri> for HttpsConnectorWithSni {
             let hostname = sni_hostname?;
             let host = DNSNameRef::try_from_ascii_str(&hostname)
                 .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid hostname"))?;
-            let connection = http
-                .call(uri)
-                .await
-                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-            let tls_connection = tls_connector.connect(host, connection).await?;
+            let start = Instant::now();
+            let connection = match socks5_proxy {
+                Some(proxy) => {
+                    let proxy_uri = Uri::from_str(&format!("http://{}/", proxy.address()))
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                    let mut connection = http
+                        .call(proxy_uri)
+                        .await
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                    let target_host = uri.host().unwrap_or_default();
+                    let target_port = uri.port_u16().unwrap_or(443);
+                    proxy
+                        .handshake(&mut connection, target_host, target_port)
+                        .await?;
+                    connection
+                }
+                None => {
+                    let connection = http
+                        .call(uri)
+                        .await
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                    if let (Some(connected_peer), Ok(peer)) =
+                        (connected_peer, connection.peer_addr())
+                    {
+                        *connected_peer.lock().unwrap() = Some(peer);
+                    }
+                    connection
+                }
+            };
+            let tcp_connect = start.elapsed();
+            let tls_connection =
+                tls_connector
+                    .connect(host, connection)
+                    .await
+                    .map_err(|error| {
+                        if verify_hostname && is_hostname_mismatch(&error) {
+                            hostname_verification_error(&hostname)
+                        } else if is_pin_mismatch(&error) {
+                            io::Error::new(io::ErrorKind::InvalidData, PinVerificationError)
+                        } else {
+                            error
+                        }
+                    })?;
+            let tls_handshake = start.elapsed() - tcp_connect;
 
-            Ok(MaybeHttpsStream::Https(tls_connection))
+            Ok(TimedStream {
+                stream: MaybeHttpsStream::Https(tls_connection),
+                timing: ConnectionTiming {
+                    tcp_connect,
+                    tls_handshake,
+                },
+            })
         };
 
 
-        Box::pin(fut)
+        Box::pin(crate::rest::with_connect_timeout(fut))
     }
 }
 
+fn hostname_verification_error(hostname: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        HostnameVerificationError(hostname.to_owned()),
+    )
+}
+
+/// Returns whether the TLS handshake failed because the certificate is not valid for the hostname.
+fn is_hostname_mismatch(error: &io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<rustls::TLSError>()),
+        Some(rustls::TLSError::WebPKIError(
+            webpki::Error::CertNotValidForName
+        ))
+    )
+}
+
+/// Returns whether the TLS handshake failed because no certificate matches the pinned keys.
+fn is_pin_mismatch(error: &io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<rustls::TLSError>()),
+        Some(rustls::TLSError::General(message)) if message == PIN_MISMATCH_MESSAGE
+    )
+}
+
+/// Verifies the certificate chain of the server like `WebPKIVerifier`, and then explicitly
+/// against the hostname and the pinned public keys, if enabled. The checks run as part of every
+/// full handshake, so resumed sessions have already been verified and session resumption can stay
+/// enabled.
+struct ApiCertVerifier {
+    webpki: rustls::WebPKIVerifier,
+    verify_hostname: bool,
+    spki_pins: Option<Arc<[SpkiPin]>>,
+}
+
+impl ApiCertVerifier {
+    fn new(verify_hostname: bool, spki_pins: Option<Arc<[SpkiPin]>>) -> Self {
+        Self {
+            webpki: rustls::WebPKIVerifier::new(),
+            verify_hostname,
+            spki_pins,
+        }
+    }
+}
+
+impl rustls::ServerCertVerifier for ApiCertVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let verified =
+            self.webpki
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        if self.verify_hostname && !is_valid_for_hostname(presented_certs, dns_name) {
+            return Err(rustls::TLSError::WebPKIError(
+                webpki::Error::CertNotValidForName,
+            ));
+        }
+        if let Some(pins) = &self.spki_pins {
+            let certificates = presented_certs
+                .iter()
+                .map(|certificate| certificate.0.as_slice());
+            if !tls::matches_pins(pins, certificates) {
+                return Err(rustls::TLSError::General(PIN_MISMATCH_MESSAGE.to_owned()));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+/// Returns whether the end-entity certificate presented by the server is valid for `hostname`.
+fn is_valid_for_hostname(certificates: &[rustls::Certificate], hostname: DNSNameRef<'_>) -> bool {
+    certificates
+        .first()
+        .and_then(|certificate| webpki::EndEntityCert::from(&certificate.0).ok())
+        .map(|certificate| certificate.verify_is_valid_for_dns_name(hostname).is_ok())
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
-mod test {
-    use super::HttpsConnectorWithSni;
+pub(crate) mod test {
+    use super::*;
+    use crate::{
+        address_cache::AddressCache,
+        rest::{test::spawn_service, Error, RestRequest},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::server::TlsStream;
+
+    /// Returns a server config that serves the self-signed `certificate`.
+    pub(crate) fn server_config(certificate: &rcgen::Certificate) -> rustls::ServerConfig {
+        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        config
+            .set_single_cert(
+                vec![rustls::Certificate(certificate.serialize_der().unwrap())],
+                rustls::PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+        config
+    }
+
+    /// Spawns a TLS server using `config`, which hands every established connection to `serve`,
+    /// and returns its address.
+    pub(crate) async fn spawn_tls_server<F, Fut>(
+        config: rustls::ServerConfig,
+        serve: F,
+    ) -> std::net::SocketAddr
+    where
+        F: Fn(TlsStream<TcpStream>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let serve = Arc::new(serve);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let serve = serve.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        serve(stream).await;
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    /// Returns a connector that trusts only `certificate`, and expects it to be valid for
+    /// `localhost`.
+    pub(crate) fn trusting_connector(certificate: &rcgen::Certificate) -> HttpsConnectorWithSni {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&rustls::Certificate(certificate.serialize_der().unwrap()))
+            .unwrap();
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let mut connector = HttpsConnectorWithSni::from((http, config));
+        connector.set_sni_hostname(Some("localhost".to_owned()));
+        connector
+    }
 
     #[test]
     fn test_cert_loading() {
         let _certs = HttpsConnectorWithSni::read_cert_store();
     }
+
+    #[test]
+    fn test_tls_options() {
+        let mut connector = HttpsConnectorWithSni::new();
+        connector
+            .set_tls_options(
+                TlsVersion::Tls13,
+                Some(&[CipherSuite::TLS13_AES_256_GCM_SHA384]),
+            )
+            .unwrap();
+        assert_eq!(connector.tls.versions, vec![ProtocolVersion::TLSv1_3]);
+        assert_eq!(connector.tls.ciphersuites.len(), 1);
+
+        assert!(matches!(
+            connector.set_tls_options(
+                TlsVersion::Tls13,
+                Some(&[CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]),
+            ),
+            Err(TlsConfigError::NoUsableCipherSuite(TlsVersion::Tls13))
+        ));
+        assert!(matches!(
+            connector.set_tls_options(
+                TlsVersion::Tls12,
+                Some(&[CipherSuite::TLS_RSA_WITH_AES_128_CBC_SHA]),
+            ),
+            Err(TlsConfigError::UnsupportedCipherSuite(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tls12_server_is_rejected() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let mut config = server_config(&certificate);
+        config.versions = vec![ProtocolVersion::TLSv1_2];
+        let address = spawn_tls_server(config, |_stream| async {}).await;
+        let uri: Uri = format!("https://{}/", address).parse().unwrap();
+
+        let mut connector = HttpsConnectorWithSni::new();
+        connector.set_sni_hostname(Some("localhost".to_owned()));
+        connector.set_tls_options(TlsVersion::Tls13, None).unwrap();
+        let error = match connector.call(uri).await {
+            Ok(_) => panic!("Connected to a TLS 1.2 server"),
+            Err(error) => error,
+        };
+        assert!(error.to_string().contains("ProtocolVersion"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_nodelay_is_applied() {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri: Uri = format!("https://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let mut connector = HttpsConnectorWithSni::new();
+        let stream = connector.http.call(uri.clone()).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        connector.set_nodelay(false);
+        let stream = connector.http.call(uri).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_connections_are_verified() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let address = spawn_tls_server(server_config(&certificate), |mut stream| async move {
+            let _ = stream.shutdown().await;
+        })
+        .await;
+        let uri: Uri = format!("https://{}/", address).parse().unwrap();
+        let mut connector = trusting_connector(&certificate);
+
+        // Reading the first connection receives a session ticket, which the second connection
+        // resumes without a certificate
+        let mut stream = connector.call(uri.clone()).await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+        let mut stream = connector.call(uri.clone()).await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+
+        // Sessions that were verified before the pins changed must not be resumed
+        let other_certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let other_pin =
+            SpkiPin::from_certificate(&other_certificate.serialize_der().unwrap()).unwrap();
+        connector.set_spki_pins(Some(vec![other_pin]));
+        let error = match connector.call(uri).await {
+            Ok(_) => panic!("Resumed a session that doesn't match the pins"),
+            Err(error) => error,
+        };
+        assert!(
+            error
+                .get_ref()
+                .map(|error| error.is::<PinVerificationError>())
+                .unwrap_or(false),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_session_resumption_is_kept() {
+        let mut config = rustls::ClientConfig::new();
+        config.enable_tickets = true;
+        let connector = HttpsConnectorWithSni::from((HttpConnector::new(), config));
+        assert!(connector.tls.enable_tickets);
+    }
+
+    #[tokio::test]
+    async fn test_hostname_mismatch_is_rejected() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["other.example".to_owned()]).unwrap();
+        let address = spawn_tls_server(server_config(&certificate), |_stream| async {}).await;
+
+        // Trust the certificate, so that only the hostname is wrong
+        let connector = trusting_connector(&certificate);
+        let handle = spawn_service(connector, AddressCache::new(vec![address], None).unwrap());
+
+        let request = RestRequest::get(&format!("https://{}/", address)).unwrap();
+        let result = handle.request(request).await;
+        assert!(
+            matches!(
+                &result,
+                Err(Error::HostnameVerificationFailed(hostname)) if hostname == "localhost"
+            ),
+            "{:?}",
+            result.map(|response| response.status())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_registered_against_connected_peer() {
+        // The server accepts TCP connections but fails every TLS handshake
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        for uri in &[
+            format!("https://{}/", address),
+            format!("https://localhost:{}/", address.port()),
+        ] {
+            let other_address = "127.0.0.1:1".parse().unwrap();
+            let address_cache = AddressCache::new(vec![other_address], None).unwrap();
+            let handle = spawn_service(HttpsConnectorWithSni::new(), address_cache.clone());
+
+            let request = RestRequest::get(uri).unwrap();
+            let result = handle.request(request).await;
+            assert!(
+                matches!(result, Err(Error::HyperError(_))),
+                "{:?}",
+                result.map(|response| response.status())
+            );
+            assert!(address_cache.has_recently_failed(address), "{}", uri);
+            assert!(!address_cache.has_recently_failed(other_address));
+        }
+    }
 }
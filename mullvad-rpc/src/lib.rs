@@ -1,7 +1,7 @@
 #![deny(rust_2018_idioms)]
 
 use chrono::{offset::Utc, DateTime};
-use hyper::Method;
+use hyper::{client::connect::Connect, Method};
 use mullvad_types::{
     account::{AccountToken, VoucherSubmission},
     version::AppVersion,
@@ -11,6 +11,8 @@ use std::{
     future::Future,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::Path,
+    sync::Arc,
+    time::Duration,
 };
 use talpid_types::{net::wireguard, ErrorExt};
 
@@ -18,13 +20,28 @@ use talpid_types::{net::wireguard, ErrorExt};
 pub mod rest;
 
 mod https_client_with_sni;
-use crate::https_client_with_sni::HttpsConnectorWithSni;
+pub mod tls;
 
 mod address_cache;
+mod reachability;
 mod relay_list;
+mod response_cache;
+mod socks;
+mod warm_pool;
 use address_cache::AddressCache;
+pub use address_cache::{
+    AddressChangeReason, AddressChanged, BreakerState, CanaryPolicy, CircuitBreakerPolicy,
+    FailurePolicy,
+};
+pub use https_client_with_sni::{
+    ConnectionTiming, HostnameVerificationError, HttpsConnectorWithSni, TlsConfigError, TlsVersion,
+};
 pub use hyper::StatusCode;
+pub use reachability::{ApiReachability, Reachability};
 pub use relay_list::RelayListProxy;
+pub use response_cache::{CacheOutcome, CachedResponse, ResponseCache};
+pub use socks::{Socks5Error, Socks5Proxy};
+pub use tokio_rustls::rustls::CipherSuite;
 
 /// Error code returned by the Mullvad API if the voucher has alreaby been used.
 pub const VOUCHER_USED: &str = "VOUCHER_USED";
@@ -32,6 +49,12 @@ pub const VOUCHER_USED: &str = "VOUCHER_USED";
 /// Error code returned by the Mullvad API if the voucher code is invalid.
 pub const INVALID_VOUCHER: &str = "INVALID_VOUCHER";
 
+/// Error code returned by the Mullvad API if the account number is invalid.
+pub const INVALID_ACCOUNT: &str = "INVALID_ACCOUNT";
+
+/// Error code returned by the Mullvad API if the account has too many devices.
+pub const TOO_MANY_DEVICES: &str = "TOO_MANY_DEVICES";
+
 const API_HOST: &str = "api.mullvad.net";
 pub const API_IP_CACHE_FILENAME: &str = "api-ip-address.txt";
 const API_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(193, 138, 218, 78));
@@ -43,6 +66,10 @@ pub struct MullvadRpcRuntime {
     https_connector: HttpsConnectorWithSni,
     handle: tokio::runtime::Handle,
     address_cache: AddressCache,
+    warm_pool_size: usize,
+    default_request_timeout: Duration,
+    address_fetcher_config: rest::AddressFetcherConfig,
+    service_builder: rest::RequestServiceBuilder,
 }
 
 #[derive(err_derive::Error, Debug)]
@@ -57,10 +84,18 @@ pub enum Error {
 impl MullvadRpcRuntime {
     /// Create a new `MullvadRpcRuntime`.
     pub fn new(handle: tokio::runtime::Handle) -> Result<Self, Error> {
+        let address_cache = AddressCache::new(vec![API_ADDRESS.into()], None)?;
         Ok(MullvadRpcRuntime {
             https_connector: HttpsConnectorWithSni::new(),
+            service_builder: rest::RequestServiceBuilder::new(
+                handle.clone(),
+                address_cache.clone(),
+            ),
             handle,
-            address_cache: AddressCache::new(vec![API_ADDRESS.into()], None)?,
+            address_cache,
+            warm_pool_size: 0,
+            default_request_timeout: rest::DEFAULT_TIMEOUT,
+            address_fetcher_config: rest::AddressFetcherConfig::default(),
         })
     }
 
@@ -100,21 +135,185 @@ impl MullvadRpcRuntime {
 
         Ok(MullvadRpcRuntime {
             https_connector,
+            service_builder: rest::RequestServiceBuilder::new(
+                handle.clone(),
+                address_cache.clone(),
+            ),
             handle,
             address_cache,
+            warm_pool_size: 0,
+            default_request_timeout: rest::DEFAULT_TIMEOUT,
+            address_fetcher_config: rest::AddressFetcherConfig::default(),
         })
     }
 
+    /// Keep connections to the `size` most preferred API addresses established ahead of time, for
+    /// handles subsequently created by `mullvad_rest_handle`. This makes failing over to another
+    /// address faster. A size of 0, the default, disables the warm pool.
+    pub fn set_warm_pool_size(&mut self, size: usize) {
+        self.warm_pool_size = size;
+    }
+
+    /// Sets the timeout of requests built by handles subsequently created by `mullvad_rest_handle`,
+    /// unless the requests set their own. Defaults to `rest::DEFAULT_TIMEOUT`.
+    pub fn set_default_request_timeout(&mut self, timeout: Duration) {
+        self.default_request_timeout = timeout;
+    }
+
+    /// Sets when handles subsequently created by `mullvad_rest_handle` fetch new API addresses.
+    /// `AddressFetcherConfig::first_fetch_delay` controls how soon the first fetch is made.
+    pub fn set_address_fetcher_config(&mut self, config: rest::AddressFetcherConfig) {
+        self.address_fetcher_config = config;
+    }
+
+    /// Sets the limits on the headers of responses received by subsequently created request
+    /// services. Responses that exceed them fail with `rest::Error::TooManyHeaders`.
+    pub fn set_response_header_limits(&mut self, limits: rest::HeaderLimits) {
+        self.service_builder.header_limits(limits);
+    }
+
+    /// Sets the maximum number of idle connections that subsequently created request services keep
+    /// alive per API address. Every service has its own connection pool. Defaults to 0, which
+    /// means that every request uses a new connection.
+    pub fn set_pool_max_idle_per_host(&mut self, pool_max_idle_per_host: usize) {
+        self.service_builder
+            .pool_max_idle_per_host(pool_max_idle_per_host);
+    }
+
+    /// Sets how long idle connections are kept alive by subsequently created request services, if
+    /// connections are kept at all. Defaults to hyper's default.
+    pub fn set_pool_idle_timeout(&mut self, pool_idle_timeout: Option<Duration>) {
+        self.service_builder.pool_idle_timeout(pool_idle_timeout);
+    }
+
+    /// Limits the number of requests that each subsequently created request service has in flight
+    /// at once. Further requests are queued. Defaults to no limit.
+    pub fn set_max_concurrent_requests(&mut self, max_concurrent: Option<usize>) {
+        self.service_builder.max_concurrent(max_concurrent);
+    }
+
+    /// Sets the timer that measures the timeouts of requests sent by subsequently created request
+    /// services. Defaults to `rest::TokioTimer`.
+    pub fn set_request_timer(&mut self, timer: Arc<dyn rest::Timer>) {
+        self.service_builder.timer(timer);
+    }
+
+    /// Sets the observer that is notified when the requests of subsequently created request
+    /// services start and end, e.g. to record metrics. Defaults to `None`.
+    pub fn set_request_observer(&mut self, observer: Option<Arc<dyn rest::RequestObserver>>) {
+        self.service_builder.observer(observer);
+    }
+
+    /// Configures the minimum TLS version and the allowed cipher suites of connections made by
+    /// subsequently created request services. By default, TLS 1.2 and 1.3 and all cipher suites
+    /// supported by rustls are allowed. Fails if the combination can't be used.
+    pub fn set_tls_options(
+        &mut self,
+        min_version: TlsVersion,
+        cipher_suites: Option<&[CipherSuite]>,
+    ) -> Result<(), TlsConfigError> {
+        self.https_connector
+            .set_tls_options(min_version, cipher_suites)
+    }
+
+    /// Configures whether `TCP_NODELAY` is set on connections made by subsequently created request
+    /// services. Enabled by default, since API requests are small and latency-sensitive.
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.https_connector.set_nodelay(nodelay);
+    }
+
+    /// Configures whether `SO_REUSEADDR` is set on sockets created by subsequently created request
+    /// services. Disabled by default.
+    pub fn set_reuse_address(&mut self, reuse_address: bool) {
+        self.https_connector.set_reuse_address(reuse_address);
+    }
+
+    /// Configures whether subsequently created request services verify that the certificate of
+    /// the API is valid for its hostname. This must be enabled in production. Enabled by default.
+    pub fn set_verify_hostname(&mut self, verify_hostname: bool) {
+        self.https_connector.set_verify_hostname(verify_hostname);
+    }
+
+    /// Configures the public keys that the certificate of the API must match one of, for
+    /// subsequently created request services. See `HttpsConnectorWithSni::set_spki_pins`. By
+    /// default, no keys are pinned.
+    pub fn set_spki_pins(&mut self, pins: Option<Vec<tls::SpkiPin>>) {
+        self.https_connector.set_spki_pins(pins);
+    }
+
+    /// Tunnels new connections of all request services created by this runtime through a SOCKS5
+    /// proxy, including services that have already been created. Use `None`, the default, to
+    /// connect directly.
+    pub fn set_socks5_proxy(&self, proxy: Option<Socks5Proxy>) {
+        self.https_connector.set_socks5_proxy(proxy);
+    }
+
+    /// Sets the policy that decides how many failed requests are tolerated before the next API
+    /// address is used.
+    pub fn set_address_failure_policy(&mut self, policy: FailurePolicy) {
+        self.address_cache.set_failure_policy(policy);
+    }
+
+    /// Sets the policy of the circuit breakers that temporarily skip API addresses that keep
+    /// failing. With `None`, the default, addresses are never skipped.
+    pub fn set_address_circuit_breaker_policy(&mut self, policy: Option<CircuitBreakerPolicy>) {
+        self.address_cache.set_circuit_breaker_policy(policy);
+    }
+
+    /// Returns the API addresses whose circuit breakers are open or half-open, and their states.
+    pub fn tripped_address_breakers(&self) -> Vec<(SocketAddr, BreakerState)> {
+        self.address_cache.tripped_breakers()
+    }
+
+    /// Sets the policy for trying out newly fetched API addresses on a fraction of the requests
+    /// before they replace the current addresses. With `None`, the default, new addresses are used
+    /// immediately.
+    pub fn set_address_canary_policy(&mut self, policy: Option<CanaryPolicy>) {
+        self.address_cache.set_canary_policy(policy);
+    }
+
+    /// Returns a stream of events that are emitted whenever the API address used for new requests
+    /// changes.
+    pub fn subscribe_address_changes(
+        &self,
+    ) -> futures::channel::mpsc::UnboundedReceiver<AddressChanged> {
+        self.address_cache.subscribe_address_changes()
+    }
+
+    /// Returns the number of requests that were dispatched to each API IP address within the last
+    /// five minutes.
+    pub fn api_dispatch_stats(&self) -> Vec<(std::net::IpAddr, u64)> {
+        self.address_cache.dispatch_stats()
+    }
+
+    /// Sets a callback that is called whenever every cached API address has failed and the
+    /// bundled default address is used instead. The callback receives the number of cached
+    /// addresses that were skipped.
+    pub fn set_address_fallback_callback(
+        &mut self,
+        callback: impl Fn(usize) + Send + Sync + 'static,
+    ) {
+        self.address_cache.set_fallback_callback(callback);
+    }
+
     /// Creates a new request service and returns a handle to it.
     fn new_request_service(&mut self, sni_hostname: Option<String>) -> rest::RequestServiceHandle {
         let mut https_connector = self.https_connector.clone();
-        https_connector.set_sni_hostname(sni_hostname);
+        https_connector.set_sni_hostname(sni_hostname.clone());
 
-        let service = rest::RequestService::new(
-            https_connector,
-            self.handle.clone(),
-            self.address_cache.clone(),
-        );
+        self.spawn_request_service(https_connector, sni_hostname, None)
+    }
+
+    fn spawn_request_service<C: Connect + Clone + Send + Sync + 'static>(
+        &mut self,
+        connector: C,
+        name: Option<String>,
+        connection_closer: Option<rest::ConnectionCloser>,
+    ) -> rest::RequestServiceHandle {
+        let mut service = self.service_builder.clone().name(name).build(connector);
+        if let Some(closer) = connection_closer {
+            service.set_connection_closer(closer);
+        }
         let handle = service.handle();
         self.handle.spawn(service.into_future());
         handle
@@ -122,14 +321,48 @@ impl MullvadRpcRuntime {
 
     /// Returns a request factory initialized to create requests for the master API
     pub fn mullvad_rest_handle(&mut self) -> rest::MullvadRestHandle {
-        let service = self.new_request_service(Some(API_HOST.to_owned()));
-        let factory = rest::RequestFactory::new(
+        let service = if self.warm_pool_size > 0 {
+            let mut https_connector = self.https_connector.clone();
+            https_connector.set_sni_hostname(Some(API_HOST.to_owned()));
+            let connector = warm_pool::WarmPoolConnector::new(
+                https_connector,
+                self.address_cache.clone(),
+                self.warm_pool_size,
+            );
+            let warm_pool = connector.clone();
+            self.spawn_request_service(
+                connector,
+                Some(API_HOST.to_owned()),
+                Some(Box::new(move |address| {
+                    warm_pool.close_connections(address)
+                })),
+            )
+        } else {
+            self.new_request_service(Some(API_HOST.to_owned()))
+        };
+        let factory = rest::RequestFactory::with_timeout(
             API_HOST.to_owned(),
             Box::new(self.address_cache.clone()),
             Some("app".to_owned()),
+            self.default_request_timeout,
         );
 
-        rest::MullvadRestHandle::new(service, factory, self.address_cache.clone())
+        rest::MullvadRestHandle::new(
+            service,
+            factory,
+            self.address_cache.clone(),
+            self.address_fetcher_config,
+        )
+    }
+
+    /// Checks whether the API can be reached over IPv4 and IPv6 respectively, by establishing a TLS
+    /// connection to the known API addresses of each family.
+    pub fn check_api_reachability(&self) -> impl Future<Output = ApiReachability> {
+        let mut https_connector = self.https_connector.clone();
+        https_connector.set_sni_hostname(Some(API_HOST.to_owned()));
+        let address_cache = self.address_cache.clone();
+
+        async move { reachability::check_api_reachability(https_connector, &address_cache).await }
     }
 
     /// Returns a new request service handle
@@ -367,10 +600,8 @@ impl WireguardKeyProxy {
             if let Some(timeout) = timeout {
                 request.set_timeout(timeout);
             }
-            request.set_auth(Some(account_token))?;
-            let response = service.request(request).await?;
-            rest::deserialize_body(rest::parse_rest_response(response, StatusCode::CREATED).await?)
-                .await
+            request.set_auth(Some(rest::AccountToken::new(account_token)?))?;
+            service.request_json(request, StatusCode::CREATED).await
         }
     }
 
@@ -459,18 +690,13 @@ impl ApiProxy {
     }
 
     pub async fn get_api_addrs(&self) -> Result<Vec<SocketAddr>, rest::Error> {
-        let service = self.handle.service.clone();
-
-        let response = rest::send_request(
-            &self.handle.factory,
-            service,
-            "/v1/api-addrs",
-            Method::GET,
-            None,
-            StatusCode::OK,
-        )
-        .await?;
-
-        rest::deserialize_body(response).await
+        let mut request = self.handle.factory.get("/v1/api-addrs")?;
+        // New addresses are needed most when requests fail, so don't queue behind those
+        request.set_priority(rest::RequestPriority::High);
+
+        self.handle
+            .service
+            .request_json(request, StatusCode::OK)
+            .await
     }
 }
@@ -0,0 +1,87 @@
+//! Diagnosing whether the API can be reached over IPv4 and IPv6 respectively.
+
+use crate::{address_cache::AddressCache, https_client_with_sni::HttpsConnectorWithSni};
+use hyper::{service::Service, Uri};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// Maximum time to wait for a connection to a single address to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reachability of the API over IPv4 and IPv6.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApiReachability {
+    pub ipv4: Reachability,
+    pub ipv6: Reachability,
+}
+
+/// Reachability of the API over a single address family.
+#[derive(Debug, Clone, serde::Serialize)]
+pub enum Reachability {
+    /// A TLS connection to `address` was established in `connect_time`, of which
+    /// `tls_handshake` was spent on the TLS handshake.
+    Reachable {
+        address: SocketAddr,
+        connect_time: Duration,
+        tls_handshake: Duration,
+    },
+    /// No connection could be established. Contains the error for the last address tried.
+    Unreachable { address: SocketAddr, error: String },
+    /// There are no known API addresses in the address family.
+    NoAddress,
+}
+
+/// Tries to establish a connection to the API over IPv4 and IPv6 separately. The addresses of
+/// each family are tried in order of preference, until a connection succeeds.
+pub(crate) async fn check_api_reachability(
+    connector: HttpsConnectorWithSni,
+    address_cache: &AddressCache,
+) -> ApiReachability {
+    let addresses = address_cache.addresses();
+    let (ipv4_addresses, ipv6_addresses): (Vec<_>, Vec<_>) =
+        addresses.into_iter().partition(SocketAddr::is_ipv4);
+
+    let ipv4 = check_family(
+        connector.clone(),
+        Ipv4Addr::UNSPECIFIED.into(),
+        ipv4_addresses,
+    );
+    let ipv6 = check_family(connector, Ipv6Addr::UNSPECIFIED.into(), ipv6_addresses);
+    let (ipv4, ipv6) = futures::join!(ipv4, ipv6);
+
+    ApiReachability { ipv4, ipv6 }
+}
+
+async fn check_family(
+    mut connector: HttpsConnectorWithSni,
+    local_address: IpAddr,
+    addresses: Vec<SocketAddr>,
+) -> Reachability {
+    // Binding to the unspecified address of the family ensures that no other family is used
+    connector.set_local_address(Some(local_address));
+
+    let mut result = Reachability::NoAddress;
+    for address in addresses {
+        let uri = match format!("https://{}/", address).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => continue,
+        };
+        let start = Instant::now();
+        let error = match tokio::time::timeout(CONNECT_TIMEOUT, connector.call(uri)).await {
+            Ok(Ok(connection)) => {
+                return Reachability::Reachable {
+                    address,
+                    connect_time: start.elapsed(),
+                    tls_handshake: connection.timing().tls_handshake,
+                };
+            }
+            Ok(Err(error)) => error.to_string(),
+            Err(_) => "Timed out".to_owned(),
+        };
+        log::debug!("Failed to connect to API address {}: {}", address, error);
+        result = Reachability::Unreachable { address, error };
+    }
+    result
+}
@@ -34,6 +34,7 @@ impl RelayListProxy {
         let future = async move {
             let mut request = request?;
             request.set_timeout(RELAY_LIST_TIMEOUT);
+            request.set_verify_digest(true);
 
             let response = service.request(request).await?;
             if response.status() != StatusCode::OK {
@@ -0,0 +1,510 @@
+use crate::rest::{self, RequestFactory, RequestServiceHandle, Response, RestRequest, StatusCode};
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Method,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Describes where the body returned by `ResponseCache` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOutcome {
+    /// The body was cached and within the freshness window.
+    Hit,
+    /// The body was cached, but older than the freshness window. It is revalidated in the
+    /// background, so that a subsequent call gets a fresh body.
+    Stale,
+    /// Nothing was cached, so the body was fetched and cached.
+    Miss,
+    /// The request isn't cacheable, or the server forbade storing the response, so the body was
+    /// fetched without being cached.
+    Uncacheable,
+}
+
+/// A response body returned by `ResponseCache`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub body: Arc<[u8]>,
+    pub outcome: CacheOutcome,
+}
+
+impl CachedResponse {
+    /// Deserializes the body from JSON.
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> rest::Result<T> {
+        serde_json::from_slice(&self.body).map_err(rest::Error::DeserializeError)
+    }
+}
+
+/// Identifies a cached response. Responses to requests with different auth are cached
+/// separately, so that they are never shared between accounts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: String,
+    auth_hash: Option<u64>,
+}
+
+impl CacheKey {
+    fn new(request: &RestRequest) -> Self {
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/")
+            .to_owned();
+        let auth_hash = request.authorization().map(|auth| {
+            let mut hasher = DefaultHasher::new();
+            auth.as_bytes().hash(&mut hasher);
+            hasher.finish()
+        });
+        Self { path, auth_hash }
+    }
+}
+
+struct CacheEntry {
+    body: Arc<[u8]>,
+    etag: Option<HeaderValue>,
+    fetched: Instant,
+    ttl: Duration,
+    revalidating: bool,
+}
+
+/// A cache of the bodies of successful responses to cacheable GET requests, keyed on the request
+/// path and auth. Cached bodies are returned immediately, even after they have gone stale, in
+/// which case they are revalidated in the background using `If-None-Match`. Requests must be
+/// marked as cacheable with `RestRequest::set_cacheable` to use the cache, and are otherwise
+/// always sent.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<HashMap<CacheKey, CacheEntry>>>,
+    freshness: Duration,
+}
+
+impl ResponseCache {
+    /// Creates an empty cache. Responses fetched by `get` are cached as fresh for `freshness`.
+    pub fn new(freshness: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            freshness,
+        }
+    }
+
+    /// Returns the body of a successful GET response for `path`, from the cache if it contains
+    /// one. The request is cacheable for the freshness given to `new`.
+    pub async fn get(
+        &self,
+        factory: &RequestFactory,
+        service: RequestServiceHandle,
+        path: &str,
+    ) -> rest::Result<CachedResponse> {
+        let mut request = factory.get(path)?;
+        request.set_cacheable(Some(self.freshness));
+        self.request(service, request).await
+    }
+
+    /// Returns the body of a successful response to `request`. If the request is a cacheable GET
+    /// request, the body is returned from the cache if it contains one.
+    pub async fn request(
+        &self,
+        service: RequestServiceHandle,
+        request: RestRequest,
+    ) -> rest::Result<CachedResponse> {
+        let ttl = match request.cache_ttl() {
+            Some(ttl) if request.method() == Method::GET => ttl,
+            _ => {
+                let response = service.request(request).await?;
+                let (_, body) = read_response(response).await?;
+                return Ok(CachedResponse {
+                    body,
+                    outcome: CacheOutcome::Uncacheable,
+                });
+            }
+        };
+        let key = CacheKey::new(&request);
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.get_mut(&key) {
+                if entry.fetched.elapsed() < entry.ttl {
+                    return Ok(CachedResponse {
+                        body: entry.body.clone(),
+                        outcome: CacheOutcome::Hit,
+                    });
+                }
+
+                if !entry.revalidating {
+                    if let Some(mut request) = request.clone_get() {
+                        entry.revalidating = true;
+                        if let Some(etag) = entry.etag.clone() {
+                            request.headers_mut().insert(header::IF_NONE_MATCH, etag);
+                        }
+                        self.spawn_revalidation(service, request, key, ttl);
+                    }
+                }
+                return Ok(CachedResponse {
+                    body: entry.body.clone(),
+                    outcome: CacheOutcome::Stale,
+                });
+            }
+        }
+
+        let response = service.request(request).await?;
+        let (body, stored) = self.store(key, ttl, response).await?;
+        Ok(CachedResponse {
+            body,
+            outcome: if stored {
+                CacheOutcome::Miss
+            } else {
+                CacheOutcome::Uncacheable
+            },
+        })
+    }
+
+    fn spawn_revalidation(
+        &self,
+        service: RequestServiceHandle,
+        request: RestRequest,
+        key: CacheKey,
+        ttl: Duration,
+    ) {
+        let cache = self.clone();
+        let revalidation_service = service.clone();
+        service.spawn(async move {
+            let result = async {
+                let response = revalidation_service.request(request).await?;
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    cache.renew(&key, ttl, &response);
+                    return Ok(());
+                }
+                cache.store(key.clone(), ttl, response).await.map(|_| ())
+            };
+            if let Err(error) = result.await {
+                log::debug!("Failed to revalidate cached response: {}", error);
+            }
+            if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+                entry.revalidating = false;
+            }
+        });
+    }
+
+    /// Renews the cached body after a `304 Not Modified` revalidation `response`. The
+    /// `Cache-Control` header of the response can shorten `ttl` with `max-age`, or evict the body
+    /// with `no-store`. Does nothing if the body has been evicted since the revalidation started,
+    /// since there is nothing left to renew.
+    fn renew(&self, key: &CacheKey, ttl: Duration, response: &Response) {
+        let cache_control = CacheControl::from_headers(response.headers());
+        let mut entries = self.entries.lock().unwrap();
+        if cache_control.no_store {
+            entries.remove(key);
+        } else if let Some(entry) = entries.get_mut(key) {
+            entry.fetched = Instant::now();
+            entry.ttl = cache_control.ttl(ttl);
+        }
+    }
+
+    /// Stores the body of `response`. The `Cache-Control` header of the response can shorten
+    /// `ttl` with `max-age`, or prevent the body from being stored with `no-store`. Returns the
+    /// body and whether it is stored.
+    async fn store(
+        &self,
+        key: CacheKey,
+        ttl: Duration,
+        response: Response,
+    ) -> rest::Result<(Arc<[u8]>, bool)> {
+        let cache_control = CacheControl::from_headers(response.headers());
+        let ttl = cache_control.ttl(ttl);
+        let (etag, body) = read_response(response).await?;
+        if cache_control.no_store {
+            self.entries.lock().unwrap().remove(&key);
+            return Ok((body, false));
+        }
+        self.entries.lock().unwrap().insert(
+            key,
+            CacheEntry {
+                body: body.clone(),
+                etag,
+                fetched: Instant::now(),
+                ttl,
+                revalidating: false,
+            },
+        );
+        Ok((body, true))
+    }
+}
+
+/// The directives of the `Cache-Control` header of a response that the cache respects.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CacheControl {
+    no_store: bool,
+    max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let argument = parts
+                .next()
+                .map(|argument| argument.trim().trim_matches('"'));
+            if name.eq_ignore_ascii_case("no-store") {
+                cache_control.no_store = true;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                if let Some(max_age) = argument.and_then(|argument| argument.parse().ok()) {
+                    let max_age = Duration::from_secs(max_age);
+                    // The most restrictive value wins if the directive is repeated
+                    cache_control.max_age = Some(
+                        cache_control
+                            .max_age
+                            .map_or(max_age, |current| current.min(max_age)),
+                    );
+                }
+            }
+        }
+        cache_control
+    }
+
+    /// Returns the time a response is fresh, which is the TTL requested by the caller, lowered to
+    /// `max-age` if it is shorter.
+    fn ttl(&self, requested: Duration) -> Duration {
+        match self.max_age {
+            Some(max_age) => max_age.min(requested),
+            None => requested,
+        }
+    }
+}
+
+/// Returns the ETag and the body of a successful response. The body is read like by
+/// `rest::get_bytes`, so it's limited to the maximum body size of the request and verified against
+/// the `Digest` header if the request asked for it.
+async fn read_response(response: Response) -> rest::Result<(Option<HeaderValue>, Arc<[u8]>)> {
+    let response = rest::parse_rest_response(response, StatusCode::OK).await?;
+    let etag = response.headers().get(header::ETAG).cloned();
+    let body = rest::get_bytes(response).await?;
+    Ok((etag, body.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rest::test::{new_service, spawn_server_with_handler};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = requests.clone();
+        let address = spawn_server_with_handler(move |request| {
+            let request = String::from_utf8_lossy(request).to_lowercase();
+            let count = server_requests.fetch_add(1, Ordering::SeqCst) + 1;
+            if request.contains("if-none-match: \"1\"") {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_owned()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"1\"\r\nContent-Length: 1\r\nConnection: close\r\n\r\n{}",
+                    count
+                )
+            }
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let cache = ResponseCache::new(Duration::from_millis(100));
+
+        let response = cache
+            .get(&factory, service.clone(), "relays")
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Miss);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 1);
+
+        let response = cache
+            .get(&factory, service.clone(), "relays")
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Hit);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        tokio::time::delay_for(Duration::from_millis(150)).await;
+        let response = cache
+            .get(&factory, service.clone(), "relays")
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Stale);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 1);
+
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let response = cache.get(&factory, service, "relays").await.unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Hit);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_after_eviction() {
+        let address = spawn_server_with_handler(move |request| {
+            let request = String::from_utf8_lossy(request).to_lowercase();
+            if request.contains("if-none-match: \"1\"") {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_owned()
+            } else {
+                "HTTP/1.1 200 OK\r\nETag: \"1\"\r\nContent-Length: 1\r\nConnection: close\r\n\r\n1"
+                    .to_owned()
+            }
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let cache = ResponseCache::new(Duration::from_millis(10));
+
+        cache
+            .get(&factory, service.clone(), "relays")
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        let response = cache
+            .get(&factory, service.clone(), "relays")
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Stale);
+        // Evict the body before the revalidation completes
+        cache.entries.lock().unwrap().clear();
+
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(cache.entries.lock().unwrap().is_empty());
+        let response = cache.get(&factory, service, "relays").await.unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Miss);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_isolated_per_account() {
+        let address = spawn_server_with_handler(|request| {
+            let request = String::from_utf8_lossy(request).to_lowercase();
+            let account = if request.contains("authorization: token 1111") {
+                1
+            } else {
+                2
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\n{}",
+                account
+            )
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let request = |account: &str| {
+            let mut request = factory.get("me").unwrap();
+            request
+                .set_auth(Some(rest::AccountToken::new(account).unwrap()))
+                .unwrap();
+            request.set_cacheable(Some(Duration::from_secs(60)));
+            request
+        };
+
+        let response = cache
+            .request(service.clone(), request("1111"))
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Miss);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 1);
+
+        let response = cache
+            .request(service.clone(), request("2222"))
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Miss);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 2);
+
+        let response = cache
+            .request(service.clone(), request("1111"))
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Hit);
+        assert_eq!(response.deserialize::<u32>().unwrap(), 1);
+
+        let response = cache
+            .request(service, factory.get("me").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Uncacheable);
+    }
+
+    #[test]
+    fn test_parse_cache_control() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=\"60\""),
+        );
+        headers.append(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("MAX-AGE=30"),
+        );
+        let cache_control = CacheControl::from_headers(&headers);
+        assert!(!cache_control.no_store);
+        assert_eq!(
+            cache_control.ttl(Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            cache_control.ttl(Duration::from_secs(90)),
+            Duration::from_secs(30)
+        );
+
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert!(CacheControl::from_headers(&headers).no_store);
+    }
+
+    #[tokio::test]
+    async fn test_cache_control_is_respected() {
+        let address = spawn_server_with_handler(|request| {
+            let cache_control = if request.starts_with(b"GET /private") {
+                "no-store"
+            } else {
+                "max-age=0"
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: {}\r\nContent-Length: 1\r\nConnection: close\r\n\r\n1",
+                cache_control
+            )
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let cache = ResponseCache::new(Duration::from_secs(60));
+
+        for _ in 0..2 {
+            let response = cache
+                .get(&factory, service.clone(), "private")
+                .await
+                .unwrap();
+            assert_eq!(response.outcome, CacheOutcome::Uncacheable);
+        }
+
+        let response = cache.get(&factory, service.clone(), "short").await.unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Miss);
+        let response = cache.get(&factory, service, "short").await.unwrap();
+        assert_eq!(response.outcome, CacheOutcome::Stale);
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size_is_enforced() {
+        let address = spawn_server_with_handler(|_| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n".to_owned()
+                + &"1".repeat(100)
+        })
+        .await;
+        let (mut factory, service) = new_service(address);
+        factory.set_max_body_size(99);
+        let cache = ResponseCache::new(Duration::from_secs(60));
+
+        let result = cache.get(&factory, service, "relays").await;
+        assert!(matches!(result, Err(rest::Error::ResponseTooLarge(99))));
+    }
+}
@@ -1,25 +1,45 @@
-use crate::address_cache::AddressCache;
+use crate::{
+    address_cache::AddressCache,
+    https_client_with_sni::{ConnectionTiming, HostnameVerificationError},
+    tls::PinVerificationError,
+};
+use chrono::{offset::Utc, DateTime};
 use futures::{
     channel::{mpsc, oneshot},
-    future::{abortable, AbortHandle, Aborted},
+    future::{abortable, join_all, select, AbortHandle, Either},
     sink::SinkExt,
     stream::StreamExt,
-    TryFutureExt,
+    FutureExt, TryFutureExt,
 };
 use hyper::{
     client::{connect::Connect, Client},
-    header::{self, HeaderValue},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
     Method, Uri,
 };
+use lazy_static::lazy_static;
+use rand::Rng;
+use regex::Regex;
 use std::{
-    collections::BTreeMap,
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    fmt,
     future::Future,
-    mem,
-    net::{IpAddr, SocketAddr},
+    io, mem,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    pin::Pin,
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
     time::{Duration, Instant},
 };
-use tokio::runtime::Handle;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    runtime::Handle,
+    time::Elapsed,
+};
 
 pub use hyper::StatusCode;
 
@@ -30,16 +50,48 @@ const TIMER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 const API_IP_CHECK_DELAY: Duration = Duration::from_secs(15 * 60);
 const API_IP_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 const API_IP_CHECK_ERROR_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Fraction by which the API address check intervals are randomly lengthened or shortened, so
+/// that clients that started at the same time don't fetch addresses at the same time.
+const API_IP_CHECK_JITTER: f64 = 0.1;
 
 
 pub type Result<T> = std::result::Result<T, Error>;
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of requests that are not given one. Factories can use another default, see
+/// `RequestFactory::with_timeout`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default limit on the number of network round trips a single request may make, counting both
+/// followed redirects and retries.
+const DEFAULT_MAX_TOTAL_ATTEMPTS: u32 = 10;
+/// Connection pooling is disabled, so that every request establishes a new connection.
+const POOL_MAX_IDLE_PER_HOST: usize = 0;
+/// Number of high priority requests that may be in flight in addition to the concurrency limit of
+/// a `RequestService`.
+const MAX_HIGH_PRIORITY_OVERFLOW: usize = 1;
+/// Default limit on the number of headers in a response.
+const DEFAULT_MAX_RESPONSE_HEADERS: usize = 64;
+/// Default limit on the total size of the names and values of the headers in a response.
+const DEFAULT_MAX_RESPONSE_HEADERS_SIZE: usize = 32 * 1024;
+/// Name of the header containing digests of the response body, as defined by RFC 3230.
+const DIGEST_HEADER: &str = "digest";
+/// Maximum number of request outcomes that the success rate is computed from.
+const SUCCESS_WINDOW_MAX_REQUESTS: usize = 100;
+/// Maximum age of the request outcomes that the success rate is computed from.
+const SUCCESS_WINDOW_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+/// Default limit on the size of response bodies that are read into memory.
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+/// Number of body chunks that are buffered while waiting for `deserialize_body_streaming` to
+/// deserialize them.
+const STREAMING_BODY_CHUNK_BUFFER: usize = 4;
+/// Maximum number of bytes of a response body that are included in an error.
+const MAX_BODY_SNIPPET_LENGTH: usize = 512;
+/// Maximum length of an `AccountToken`.
+const MAX_ACCOUNT_TOKEN_LENGTH: usize = 1024;
 
 /// Describes all the ways a REST request can fail
 #[derive(err_derive::Error, Debug)]
 pub enum Error {
-    #[error(display = "Request cancelled")]
-    Aborted(Aborted),
+    #[error(display = "Request cancelled - {}", _0)]
+    Cancelled(AbortReason),
 
     #[error(display = "Hyper error")]
     HyperError(#[error(source)] hyper::Error),
@@ -53,22 +105,302 @@ pub enum Error {
     #[error(display = "Request timed out")]
     TimeoutError(#[error(source)] tokio::time::Elapsed),
 
+    /// A connection to the API could not be established within the connect timeout of the
+    /// request.
+    #[error(display = "Timed out connecting to the API after {:?}", _0)]
+    ConnectTimeout(Duration),
+
     #[error(display = "Failed to deserialize data")]
     DeserializeError(#[error(source)] serde_json::Error),
 
+    /// The response body could not be deserialized. Contains a truncated copy of the body, with
+    /// secrets redacted.
+    #[error(display = "Failed to deserialize response body: {}", _1)]
+    DeserializeBodyError(#[error(source)] serde_json::Error, String),
+
     #[error(display = "Failed to send request to rest client")]
     SendError,
 
     #[error(display = "Failed to receive response from rest client")]
     ReceiveError,
 
-    /// Unexpected response code
-    #[error(display = "Unexpected response status code {} - {}", _0, _1)]
-    ApiError(StatusCode, String),
+    /// Fetching new API addresses failed. The cause is logged by the API address fetcher.
+    #[error(display = "Failed to fetch new API addresses")]
+    ApiAddressFetchFailed,
+
+    /// The API responded with an error status and an `ErrorResponse` body.
+    #[error(display = "API error {} - {}", status, code)]
+    ApiError {
+        status: StatusCode,
+        code: ApiErrorCode,
+        /// A human-readable description of the error, if the API included one.
+        detail: Option<String>,
+    },
+
+    /// The API responded with an error status and a body that is not an `ErrorResponse`, such as
+    /// an HTML page from a proxy. Contains the redacted start of the body, which is empty if the
+    /// response had no body.
+    #[error(display = "Unexpected response status code {}: {:?}", _0, _1)]
+    UnexpectedErrorBody(StatusCode, String),
+
+    /// The request was refused with `451 Unavailable For Legal Reasons`, which likely means that
+    /// the API is censored. Contains the link to the blocking authority, if the response has one.
+    #[error(display = "The API is unavailable for legal reasons")]
+    LegallyBlocked(Option<String>),
+
+    /// The response body exceeds the maximum body size of the request, in bytes.
+    #[error(display = "Response body is larger than {} bytes", _0)]
+    ResponseTooLarge(usize),
+
+    /// An identical request was submitted within the deduplication window of the request, so
+    /// this one was not sent. See `RestRequest::set_dedup_window`.
+    #[error(display = "Suppressed a duplicate of a recent request")]
+    DuplicateSuppressed,
 
     /// The string given was not a valid URI.
     #[error(display = "Not a valid URI")]
     UriError(#[error(source)] http::uri::InvalidUri),
+
+    /// The combined number of redirects and retries exceeded the request's total attempt limit.
+    /// Contains the limit and the outcome of the last round trip, which is a `Redirect` if it
+    /// was redirected. See `RestRequest::set_max_total_attempts`.
+    #[error(display = "Exceeded the limit of {} total attempts - {}", _0, _1)]
+    AttemptLimitExceeded(u32, Box<Error>),
+
+    /// The server responded with more redirects than the request follows. Contains the limit.
+    /// See `RestRequest::set_max_redirects`.
+    #[error(display = "Exceeded the limit of {} redirects", _0)]
+    TooManyRedirects(u32),
+
+    /// The server responded with a redirect that was not followed. Contains the status and the
+    /// `Location` header of the response.
+    #[error(display = "Redirected with status {} to {}", _0, _1)]
+    Redirect(StatusCode, String),
+
+    /// The server did not respond with the requested range of a resumed download.
+    #[error(display = "Server did not resume the download at byte {}", _0)]
+    ResumeUnsupported(u64),
+
+    /// Failed to write a downloaded response body.
+    #[error(display = "Failed to write response body")]
+    WriteError(#[error(source)] io::Error),
+
+    /// The request is malformed.
+    #[error(display = "Invalid request: {}", _0)]
+    InvalidRequest(String),
+
+    /// A request in a batch is malformed, so none of the requests were sent. Contains the index
+    /// of the first invalid request.
+    #[error(display = "Request {} in the batch is invalid - {}", _0, _1)]
+    InvalidBatchRequest(usize, Box<Error>),
+
+    /// The string given is not a valid account token. See `AccountToken::new`.
+    #[error(display = "Invalid account token")]
+    InvalidAccountToken,
+
+    /// The string given was not a valid W3C Trace Context `traceparent` value.
+    #[error(display = "Invalid trace context: {}", _0)]
+    InvalidTraceContext(String),
+
+    /// The response body lacks fields that the caller requires.
+    #[error(display = "Response is missing required fields {:?}", _0)]
+    MissingFields(Vec<String>),
+
+    /// The body of the response doesn't match the digest computed with the given algorithm, in
+    /// the `Digest` header of the response.
+    #[error(display = "The response body doesn't match its {} digest", _0)]
+    IntegrityMismatch(String),
+
+    /// A default header was given that the request factory sets by itself.
+    #[error(display = "The {} header cannot be set as a default header", _0)]
+    ReservedHeader(HeaderName),
+
+    /// The response headers exceed the service's limits. Contains the number of headers and their
+    /// total size in bytes.
+    #[error(
+        display = "Response has too many headers - {} headers totalling {} bytes",
+        _0,
+        _1
+    )]
+    TooManyHeaders(usize, usize),
+
+    /// The certificate of the server is not valid for the hostname that the request was made for.
+    #[error(display = "The server certificate is not valid for {}", _0)]
+    HostnameVerificationFailed(String),
+
+    /// None of the certificates of the server match the pinned public keys. See
+    /// `HttpsConnectorWithSni::set_spki_pins`.
+    #[error(display = "The server certificate doesn't match any of the pinned public keys")]
+    PinVerificationFailed,
+
+    /// A retried request failed. Contains a summary of the attempts and the error returned by the
+    /// last attempt.
+    #[error(display = "Request failed, {} - {}", _0, _1)]
+    RetriesStopped(RetrySummary, Box<Error>),
+}
+
+impl Error {
+    /// Returns whether a request that failed with this error may succeed if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::HyperError(_) | Error::TimeoutError(_) | Error::ConnectTimeout(_) => true,
+            Error::RetriesStopped(_, error) => error.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Replaces a `HyperError` that was caused by a failed hostname verification with
+    /// `HostnameVerificationFailed`, one that was caused by a failed pin verification with
+    /// `PinVerificationFailed`, and one that was caused by a connect timeout with
+    /// `ConnectTimeout`.
+    fn with_connection_cause(self) -> Self {
+        let error = match self {
+            Error::HyperError(error) => error,
+            error => return error,
+        };
+        let mut source = std::error::Error::source(&error);
+        while let Some(cause) = source {
+            // `io::Error` doesn't return the error it wraps as its source
+            let inner = cause
+                .downcast_ref::<io::Error>()
+                .and_then(|cause| cause.get_ref());
+            if let Some(HostnameVerificationError(hostname)) =
+                inner.and_then(|cause| cause.downcast_ref::<HostnameVerificationError>())
+            {
+                return Error::HostnameVerificationFailed(hostname.clone());
+            }
+            if matches!(inner, Some(cause) if cause.is::<PinVerificationError>()) {
+                return Error::PinVerificationFailed;
+            }
+            if let Some(ConnectTimeoutError(timeout)) =
+                inner.and_then(|cause| cause.downcast_ref::<ConnectTimeoutError>())
+            {
+                return Error::ConnectTimeout(*timeout);
+            }
+            source = cause.source();
+        }
+        Error::HyperError(error)
+    }
+
+    /// Returns the parsed error code of an `ApiError`, looking through retries.
+    pub fn api_error_code(&self) -> Option<ApiErrorCode> {
+        match self {
+            Error::ApiError { code, .. } => Some(code.clone()),
+            Error::RetriesStopped(_, error) | Error::AttemptLimitExceeded(_, error) => {
+                error.api_error_code()
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the status of an `ApiError` or an `UnexpectedErrorBody`, looking through retries.
+    pub fn api_status(&self) -> Option<StatusCode> {
+        match self {
+            Error::ApiError { status, .. } | Error::UnexpectedErrorBody(status, _) => Some(*status),
+            Error::RetriesStopped(_, error) | Error::AttemptLimitExceeded(_, error) => {
+                error.api_status()
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the summary of the attempts made, if the error was returned by a retried request.
+    pub fn retry_summary(&self) -> Option<&RetrySummary> {
+        match self {
+            Error::RetriesStopped(summary, _) => Some(summary),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the attempts made for a retried request that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrySummary {
+    /// Number of attempts made, including the first one.
+    pub attempts: u32,
+    /// Why no more attempts were made.
+    pub stop_reason: RetryStopReason,
+}
+
+impl fmt::Display for RetrySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.attempts <= 1 && self.stop_reason == RetryStopReason::NonRetryable {
+            return write!(f, "not retried: {}", self.stop_reason);
+        }
+        write!(
+            f,
+            "gave up after {} attempt{}: {}",
+            self.attempts,
+            if self.attempts == 1 { "" } else { "s" },
+            self.stop_reason
+        )
+    }
+}
+
+/// Describes why a retried request was not attempted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryStopReason {
+    /// The maximum number of attempts was made.
+    AttemptsExhausted,
+    /// The deadline of the retry policy was reached.
+    DeadlineExceeded,
+    /// The last attempt failed with an error that retrying can't fix.
+    NonRetryable,
+}
+
+impl fmt::Display for RetryStopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            RetryStopReason::AttemptsExhausted => "attempts exhausted",
+            RetryStopReason::DeadlineExceeded => "deadline exceeded",
+            RetryStopReason::NonRetryable => "the error is non-retryable",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Describes why an in-flight request was aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    /// The request service was reset.
+    Reset,
+    /// Connectivity was lost.
+    ConnectivityLost,
+    /// The API address the request was sent to is no longer used.
+    HostRotated,
+    /// The caller cancelled the request.
+    CallerCancelled,
+    /// The connections to the API address the request was sent to were closed.
+    ConnectionClosed,
+    /// The request service or the runtime it runs on was shut down.
+    Shutdown,
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            AbortReason::Reset => "the request service was reset",
+            AbortReason::ConnectivityLost => "connectivity was lost",
+            AbortReason::HostRotated => "the API address was rotated",
+            AbortReason::CallerCancelled => "cancelled by the caller",
+            AbortReason::ConnectionClosed => "the connection to the API address was closed",
+            AbortReason::Shutdown => "the request service was shut down",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Aborts an in-flight request and records the reason for the request future to report.
+struct RequestAbortHandle {
+    abort_handle: AbortHandle,
+    reason: Arc<Mutex<Option<AbortReason>>>,
+}
+
+impl RequestAbortHandle {
+    fn abort(&self, reason: AbortReason) {
+        *self.reason.lock().unwrap() = Some(reason);
+        self.abort_handle.abort();
+    }
 }
 
 /// A service that executes HTTP requests, allowing for on-demand termination of all in-flight
@@ -80,14 +412,167 @@ pub(crate) struct RequestService<C> {
     connector: C,
     handle: Handle,
     next_id: u64,
-    in_flight_requests: BTreeMap<u64, AbortHandle>,
+    in_flight_requests: BTreeMap<u64, RequestAbortHandle>,
+    in_flight_metadata: Arc<Mutex<BTreeMap<u64, RequestMetadata>>>,
+    in_flight_count: Arc<AtomicUsize>,
+    idle_waiters: Vec<oneshot::Sender<()>>,
+    event_subscribers: RequestEventSubscribers,
+    outcomes: Arc<Mutex<OutcomeWindow>>,
+    address_cache: AddressCache,
+    name: Option<String>,
+    connection_closer: Option<ConnectionCloser>,
+    header_limits: HeaderLimits,
+    timer: Arc<dyn Timer>,
+    observer: Option<Arc<dyn RequestObserver>>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Option<Duration>,
+    max_concurrent: Option<usize>,
+    queued_requests: VecDeque<QueuedRequest>,
+    /// Deduplicated requests that are in flight or queued, with no expiry, and ones that recently
+    /// succeeded, with the expiry of their windows.
+    recent_requests: BTreeMap<DedupKey, Option<Instant>>,
+    /// The keys and windows of the deduplicated requests that haven't finished yet.
+    unfinished_dedup_requests: BTreeMap<u64, (DedupKey, Duration)>,
+}
+
+/// Identifies identical requests for deduplication.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DedupKey {
+    method: String,
+    host: String,
+    path: String,
+    /// The SHA-256 digest of the `Authorization` header, if any.
+    auth_digest: Option<Vec<u8>>,
+    /// The SHA-256 digest of the body.
+    body_digest: Vec<u8>,
+}
+
+/// A request that waits for other requests to finish before it's started.
+struct QueuedRequest {
+    id: u64,
+    request: RestRequest,
+    completion_tx: oneshot::Sender<Result<Response>>,
+}
+
+/// Builds request services that share a runtime and an address cache, but nothing else. Every
+/// service has its own connector, connection pool and in-flight requests, so resetting one service
+/// doesn't affect the others. The limits and pooling configured on the builder apply to the
+/// services that it subsequently builds.
+#[derive(Clone)]
+pub(crate) struct RequestServiceBuilder {
+    handle: Handle,
     address_cache: AddressCache,
+    name: Option<String>,
+    header_limits: HeaderLimits,
+    timer: Arc<dyn Timer>,
+    observer: Option<Arc<dyn RequestObserver>>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Option<Duration>,
+    max_concurrent: Option<usize>,
+}
+
+impl RequestServiceBuilder {
+    pub fn new(handle: Handle, address_cache: AddressCache) -> Self {
+        Self {
+            handle,
+            address_cache,
+            name: None,
+            header_limits: HeaderLimits::default(),
+            timer: Arc::new(TokioTimer),
+            observer: None,
+            pool_max_idle_per_host: POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: None,
+            max_concurrent: None,
+        }
+    }
+
+    /// Sets the name that identifies the services in logs and diagnostics.
+    pub fn name(&mut self, name: Option<String>) -> &mut Self {
+        self.name = name;
+        self
+    }
+
+    /// Sets the limits on the headers of responses. Responses that exceed them are rejected with
+    /// `Error::TooManyHeaders` before their bodies are read.
+    pub fn header_limits(&mut self, limits: HeaderLimits) -> &mut Self {
+        self.header_limits = limits;
+        self
+    }
+
+    /// Sets the timer that measures the timeouts of requests and the delays between retries.
+    /// Defaults to `TokioTimer`.
+    pub fn timer(&mut self, timer: Arc<dyn Timer>) -> &mut Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Sets the observer that is notified when requests start and end. Defaults to `None`, which
+    /// doesn't observe requests at all.
+    pub fn observer(&mut self, observer: Option<Arc<dyn RequestObserver>>) -> &mut Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Sets the maximum number of idle connections kept alive per API address by each service.
+    /// Reusing connections saves a TLS handshake per request, but it's disabled by default
+    /// (`POOL_MAX_IDLE_PER_HOST`), since it doesn't play well with the address failover: a kept
+    /// connection that has silently broken, e.g. because the tunnel state changed, fails the next
+    /// request to its address, which counts towards rotating away from an address that may work
+    /// fine. Resetting the service discards all kept connections.
+    pub fn pool_max_idle_per_host(&mut self, pool_max_idle_per_host: usize) -> &mut Self {
+        self.pool_max_idle_per_host = pool_max_idle_per_host;
+        self
+    }
+
+    /// Sets how long idle connections are kept alive by each service, or hyper's default if it's
+    /// not set.
+    pub fn pool_idle_timeout(&mut self, pool_idle_timeout: Option<Duration>) -> &mut Self {
+        self.pool_idle_timeout = pool_idle_timeout;
+        self
+    }
+
+    /// Sets the maximum number of requests that each service has in flight at once. If set,
+    /// further requests are queued until earlier requests finish. Requests with
+    /// `RequestPriority::High` are queued ahead of other requests, and one such request at a time
+    /// may be started in excess of the limit, so that e.g. fetching new API addresses isn't
+    /// starved by requests that fail because the current addresses don't work.
+    pub fn max_concurrent(&mut self, max_concurrent: Option<usize>) -> &mut Self {
+        self.max_concurrent = max_concurrent;
+        self
+    }
+
+    /// Builds a request service that connects with `connector`.
+    pub fn build<C: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        connector: C,
+    ) -> RequestService<C> {
+        let mut service =
+            RequestService::new(connector, self.handle.clone(), self.address_cache.clone());
+        service.client = RequestService::new_client(
+            service.connector.clone(),
+            self.pool_max_idle_per_host,
+            self.pool_idle_timeout,
+        );
+        service.name = self.name.clone();
+        service.header_limits = self.header_limits;
+        service.timer = self.timer.clone();
+        service.pool_max_idle_per_host = self.pool_max_idle_per_host;
+        service.pool_idle_timeout = self.pool_idle_timeout;
+        service.max_concurrent = self.max_concurrent;
+        service.observer = self.observer.clone();
+        service
+    }
 }
 
+/// Closes connections to an address that are kept outside of the HTTP client, such as warm
+/// connections, and returns the number of closed connections.
+pub(crate) type ConnectionCloser = Box<dyn Fn(SocketAddr) -> usize + Send>;
+
 impl<C: Connect + Clone + Send + Sync + 'static> RequestService<C> {
-    /// Constructs a new request service.
+    /// Constructs a new request service with the default configuration. Use
+    /// `RequestServiceBuilder` to configure the service.
     pub fn new(connector: C, handle: Handle, address_cache: AddressCache) -> RequestService<C> {
-        let client = Self::new_client(connector.clone());
+        let client = Self::new_client(connector.clone(), POOL_MAX_IDLE_PER_HOST, None);
 
         let (command_tx, command_rx) = mpsc::channel(1);
         Self {
@@ -95,88 +580,508 @@ impl<C: Connect + Clone + Send + Sync + 'static> RequestService<C> {
             command_rx,
             client,
             in_flight_requests: BTreeMap::new(),
+            in_flight_metadata: Arc::new(Mutex::new(BTreeMap::new())),
+            in_flight_count: Arc::new(AtomicUsize::new(0)),
+            idle_waiters: vec![],
+            event_subscribers: RequestEventSubscribers::default(),
+            outcomes: Arc::new(Mutex::new(OutcomeWindow::default())),
             next_id: 0,
             connector,
             handle,
             address_cache,
+            name: None,
+            connection_closer: None,
+            header_limits: HeaderLimits::default(),
+            timer: Arc::new(TokioTimer),
+            observer: None,
+            pool_max_idle_per_host: POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: None,
+            max_concurrent: None,
+            queued_requests: VecDeque::new(),
+            recent_requests: BTreeMap::new(),
+            unfinished_dedup_requests: BTreeMap::new(),
         }
     }
 
+    /// Sets a function that closes the connections to an address that are kept by the connector.
+    pub fn set_connection_closer(&mut self, closer: ConnectionCloser) {
+        self.connection_closer = Some(closer);
+    }
+
     /// Constructs a handle
     pub fn handle(&self) -> RequestServiceHandle {
         RequestServiceHandle {
             tx: self.command_tx.clone(),
             handle: self.handle.clone(),
+            in_flight_metadata: self.in_flight_metadata.clone(),
+            in_flight_count: self.in_flight_count.clone(),
+            event_subscribers: self.event_subscribers.clone(),
+            outcomes: self.outcomes.clone(),
+            timer: self.timer.clone(),
+        }
+    }
+
+    /// Returns a snapshot of the effective configuration of the service. The timeout of requests
+    /// is set by the request factory that builds them and the API address fetch interval by the
+    /// fetcher, neither of which the service knows, so both are left unset.
+    pub fn config(&self) -> ServiceConfig {
+        ServiceConfig {
+            name: self.name.clone(),
+            default_timeout: None,
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            pool_idle_timeout: self.pool_idle_timeout,
+            max_concurrent: self.max_concurrent,
+            api_address_fetch_interval: None,
+            header_limits: self.header_limits,
         }
     }
 
-    fn new_client(connector: C) -> Client<C, hyper::Body> {
-        Client::builder().pool_max_idle_per_host(0).build(connector)
+    fn new_client(
+        connector: C,
+        pool_max_idle_per_host: usize,
+        pool_idle_timeout: Option<Duration>,
+    ) -> Client<C, hyper::Body> {
+        let mut builder = Client::builder();
+        builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        if let Some(pool_idle_timeout) = pool_idle_timeout {
+            builder.pool_idle_timeout(pool_idle_timeout);
+        }
+        builder.build(connector)
     }
 
     fn process_command(&mut self, command: RequestCommand) {
         match command {
-            RequestCommand::NewRequest(request, completion_tx) => {
+            RequestCommand::NewRequest(request, completion_tx, id_tx) => {
+                let dedup = match (request.dedup_window(), request.dedup_key()) {
+                    (Some(window), Some(key)) => Some((window, key)),
+                    _ => None,
+                };
+                if self.is_duplicate(dedup.as_ref()) {
+                    log::debug!(
+                        "{}: Suppressing duplicate {} request to {}",
+                        self.label(),
+                        request.request.method(),
+                        request.request.uri().path()
+                    );
+                    let _ = completion_tx.send(Err(Error::DuplicateSuppressed));
+                    return;
+                }
                 let id = self.id();
-                let mut tx = self.command_tx.clone();
-                let timeout = request.timeout();
-
-                let hyper_request = request.into_request();
-                let host_addr = get_request_socket_addr(&hyper_request);
-
-                let (request_future, abort_handle) =
-                    abortable(self.client.request(hyper_request).map_err(Error::from));
-                let address_cache = self.address_cache.clone();
-
-                let future = async move {
-                    let response =
-                        tokio::time::timeout(timeout, request_future.map_err(Error::Aborted))
-                            .await
-                            .map_err(Error::TimeoutError);
-
-                    let response = flatten_result(flatten_result(response));
-                    if let Some(host_addr) = host_addr {
-                        if let Err(err) = &response {
-                            match err {
-                                Error::HyperError(_) | Error::TimeoutError(_) => {
-                                    address_cache.register_failure(host_addr, err);
-                                }
-                                _ => (),
-                            }
+                if let Some(id_tx) = id_tx {
+                    let _ = id_tx.send(id);
+                }
+                if let Some((window, key)) = dedup {
+                    self.recent_requests.insert(key.clone(), None);
+                    self.unfinished_dedup_requests.insert(id, (key, window));
+                }
+                self.event_subscribers.emit(id, RequestEvent::Queued);
+                if self.can_start(request.priority()) {
+                    self.start_request(id, request, completion_tx);
+                } else {
+                    let queued = QueuedRequest {
+                        id,
+                        request,
+                        completion_tx,
+                    };
+                    match queued.request.priority() {
+                        RequestPriority::Normal => self.queued_requests.push_back(queued),
+                        // Queue behind other high priority requests, but ahead of normal ones
+                        RequestPriority::High => {
+                            let index = self
+                                .queued_requests
+                                .iter()
+                                .position(|queued| {
+                                    queued.request.priority() != RequestPriority::High
+                                })
+                                .unwrap_or(self.queued_requests.len());
+                            self.queued_requests.insert(index, queued);
                         }
                     }
+                }
+            }
 
+            RequestCommand::RequestFinished(id, succeeded) => {
+                self.finish_dedup(id, succeeded);
+                self.in_flight_requests.remove(&id);
+                lock_metadata(&self.in_flight_metadata).remove(&id);
+                self.start_queued_requests();
+            }
 
-                    if completion_tx.send(response).is_err() {
-                        log::trace!(
-                            "Failed to send response to caller, caller channel is shut down"
-                        );
+            RequestCommand::CancelRequest(id) => {
+                if let Some(abort_handle) = self.in_flight_requests.remove(&id) {
+                    log::debug!("{}: Cancelling request {}", self.label(), id);
+                    abort_handle.abort(AbortReason::CallerCancelled);
+                } else if let Some(index) = self
+                    .queued_requests
+                    .iter()
+                    .position(|queued| queued.id == id)
+                {
+                    log::debug!("{}: Cancelling queued request {}", self.label(), id);
+                    if let Some(queued) = self.queued_requests.remove(index) {
+                        self.finish_dedup(id, false);
+                        let _ = queued
+                            .completion_tx
+                            .send(Err(Error::Cancelled(AbortReason::CallerCancelled)));
                     }
-                    let _ = tx.send(RequestCommand::RequestFinished(id)).await;
+                }
+                lock_metadata(&self.in_flight_metadata).remove(&id);
+                self.start_queued_requests();
+            }
+
+            RequestCommand::Reset(reason) => {
+                self.reset(reason);
+            }
+
+            RequestCommand::GetConfig(config_tx) => {
+                let _ = config_tx.send(self.config());
+            }
+
+            RequestCommand::CloseConnections(address, result_tx) => {
+                let _ = result_tx.send(self.close_connections(address));
+                self.start_queued_requests();
+            }
+
+            RequestCommand::WaitUntilIdle(idle_tx) => {
+                self.idle_waiters.push(idle_tx);
+            }
+        }
+        self.update_in_flight_count();
+    }
+
+    /// Publishes the number of in-flight requests, and notifies the callers waiting for the
+    /// service to become idle if there are none.
+    fn update_in_flight_count(&mut self) {
+        self.in_flight_count
+            .store(self.in_flight_requests.len(), Ordering::SeqCst);
+        if self.in_flight_requests.is_empty() {
+            for idle_tx in self.idle_waiters.drain(..) {
+                let _ = idle_tx.send(());
+            }
+        }
+    }
+
+    /// Executes a request and registers it as in flight.
+    fn start_request(
+        &mut self,
+        id: u64,
+        request: RestRequest,
+        mut completion_tx: oneshot::Sender<Result<Response>>,
+    ) {
+        let events = self.event_subscribers.clone();
+        let mut tx = self.command_tx.clone();
+        let timeout = request.timeout();
+        let connect_timeout = request.connect_timeout();
+        let body_idle_timeout = request.body_idle_timeout();
+        let preferred_address = request.preferred_address();
+        let replayable = request.is_replayable();
+        let rotate_on_failure = request.rotate_on_failure();
+        let verify_digest = request.verify_digest();
+        let max_body_size = request.max_body_size();
+        let priority = request.priority();
+
+        let mut hyper_request = request.into_request();
+        let fallback_addresses = match preferred_address {
+            Some((address, mode)) => {
+                if let Some(uri) = uri_with_address(hyper_request.uri(), address) {
+                    *hyper_request.uri_mut() = uri;
+                }
+                let count = match mode {
+                    _ if !replayable => 0,
+                    FallbackMode::Single => 1,
+                    FallbackMode::Rotate => usize::MAX,
                 };
+                self.address_cache
+                    .addresses()
+                    .into_iter()
+                    .filter(|fallback| *fallback != address)
+                    .take(count)
+                    .collect()
+            }
+            None => vec![],
+        };
+        let served_addr = Arc::new(Mutex::new(get_request_socket_addr(&hyper_request)));
+        let metadata = RequestMetadata {
+            method: hyper_request.method().clone(),
+            uri: hyper_request.uri().clone(),
+            started: Utc::now(),
+            start_instant: Instant::now(),
+            priority,
+        };
+
+        #[cfg(feature = "request-tracing")]
+        let span = request_span::new(id, &hyper_request);
+        #[cfg(feature = "request-tracing")]
+        let _entered = span.enter();
+
+        let observer = self.observer.clone();
+        if let Some(observer) = &observer {
+            observer.on_request_start(id, &metadata.method, &metadata.uri);
+        }
+
+        let (request_future, abort_handle) = abortable(CONNECTED_PEER.scope(
+            served_addr.clone(),
+            CONNECT_TIMEOUT.scope(
+                connect_timeout,
+                send_with_fallback(
+                    self.client.clone(),
+                    hyper_request,
+                    fallback_addresses,
+                    self.address_cache.clone(),
+                    // Failures of requests that don't rotate addresses aren't reported
+                    rotate_on_failure,
+                    served_addr.clone(),
+                ),
+            ),
+        ));
+        let abort_reason = Arc::new(Mutex::new(None));
+        let abort_handle = RequestAbortHandle {
+            abort_handle,
+            reason: abort_reason.clone(),
+        };
+        let address_cache = self.address_cache.clone();
+        let label = self.label();
+        let header_limits = self.header_limits;
+        let outcomes = self.outcomes.clone();
+        let timeout = self.timer.timeout(timeout);
+        let start = metadata.start_instant;
+        #[cfg(feature = "request-tracing")]
+        let future_span = span.clone();
 
+        let future = async move {
+            events.emit(id, RequestEvent::Dispatched);
+            let response = Box::pin(with_timeout(
+                request_future.map_err(|_| {
+                    Error::Cancelled(abort_reason.lock().unwrap().unwrap_or(AbortReason::Reset))
+                }),
+                timeout,
+            ));
+            // Dropping the response future closes the connection, so that requests that
+            // the caller is no longer waiting for don't run to completion
+            let response = match select(response, completion_tx.cancellation()).await {
+                Either::Left((response, _)) => response.map_err(Error::TimeoutError),
+                Either::Right(_) => Ok(Ok(Err(Error::Cancelled(AbortReason::CallerCancelled)))),
+            };
 
-                self.handle.spawn(future);
-                self.in_flight_requests.insert(id, abort_handle);
+            let mut response = prepare_response(
+                flatten_result(flatten_result(response)),
+                header_limits,
+                body_idle_timeout,
+                verify_digest,
+                max_body_size,
+            );
+            let host_addr = *served_addr.lock().unwrap();
+            if let (Ok(response), Some(host_addr)) = (&mut response, host_addr) {
+                response.extensions_mut().insert(ServedBy(host_addr));
+            }
+            let status = response.as_ref().map(|response| response.status()).ok();
+            #[cfg(feature = "request-tracing")]
+            request_span::record_result(&future_span, &response);
+            if let Some(observer) = observer {
+                let result = response.as_ref().map(|response| response.status());
+                observer.on_request_end(id, start.elapsed(), result);
+            }
+            match &response {
+                // Aborted requests say nothing about the health of the API
+                Err(Error::Cancelled(_)) => (),
+                Ok(response) => outcomes
+                    .lock()
+                    .unwrap()
+                    .record(!response.status().is_server_error()),
+                Err(_) => outcomes.lock().unwrap().record(false),
+            }
+            match &response {
+                Ok(response) => {
+                    if let Some(timing) = response.extensions().get::<ConnectionTiming>() {
+                        events.emit(id, RequestEvent::ConnectionEstablished(*timing));
+                    }
+                    events.emit(id, RequestEvent::HeadersReceived);
+                }
+                Err(Error::Cancelled(reason)) => {
+                    events.emit(id, RequestEvent::Aborted { reason: *reason })
+                }
+                Err(error) => events.emit(
+                    id,
+                    RequestEvent::Failed {
+                        error_kind: FailureKind::from_error(error),
+                    },
+                ),
+            }
+            if let Some(host_addr) = host_addr {
+                match &response {
+                    Ok(_) => address_cache.register_success(host_addr),
+                    Err(err @ Error::HyperError(_))
+                    | Err(err @ Error::TimeoutError(_))
+                    | Err(err @ Error::ConnectTimeout(_))
+                        if rotate_on_failure =>
+                    {
+                        address_cache.register_failure(host_addr, err);
+                    }
+                    Err(_) => (),
+                }
             }
 
-            RequestCommand::RequestFinished(id) => {
-                self.in_flight_requests.remove(&id);
+
+            if completion_tx.send(response).is_err() {
+                log::trace!(
+                    "{}: Failed to send response to caller, caller channel is shut down",
+                    label
+                );
+            }
+            if let Some(status) = status {
+                events.emit(id, RequestEvent::Completed { status });
             }
+            let succeeded = status.map(|status| status.is_success()).unwrap_or(false);
+            let _ = tx
+                .send(RequestCommand::RequestFinished(id, succeeded))
+                .await;
+        };
+        #[cfg(feature = "request-tracing")]
+        let future = tracing_futures::Instrument::instrument(future, span.clone());
+
+
+        self.handle.spawn(future);
+        self.in_flight_requests.insert(id, abort_handle);
+        lock_metadata(&self.in_flight_metadata).insert(id, metadata);
+    }
+
+    /// Returns whether a request with the deduplication window and key in `dedup` is a duplicate
+    /// of a request that hasn't finished yet, or that succeeded within its window.
+    fn is_duplicate(&mut self, dedup: Option<&(Duration, DedupKey)>) -> bool {
+        let now = Instant::now();
+        self.recent_requests.retain(|_, expiry| match expiry {
+            Some(expiry) => *expiry > now,
+            None => true,
+        });
+
+        match dedup {
+            Some((_, key)) => self.recent_requests.contains_key(key),
+            None => false,
+        }
+    }
+
+    /// Starts the deduplication window of request `id` if it succeeded, and forgets it otherwise,
+    /// so that failed requests can be resubmitted right away.
+    fn finish_dedup(&mut self, id: u64, succeeded: bool) {
+        if let Some((key, window)) = self.unfinished_dedup_requests.remove(&id) {
+            if succeeded {
+                self.recent_requests
+                    .insert(key, Some(Instant::now() + window));
+            } else {
+                self.recent_requests.remove(&key);
+            }
+        }
+    }
+
+    /// Returns whether a request with the given priority can be started without exceeding the
+    /// concurrency limit. High priority requests may exceed the limit, but only by
+    /// `MAX_HIGH_PRIORITY_OVERFLOW` requests, so that concurrency remains bounded even if many
+    /// requests are marked as high priority.
+    fn can_start(&self, priority: RequestPriority) -> bool {
+        let max_concurrent = match self.max_concurrent {
+            Some(max_concurrent) => max_concurrent,
+            None => return true,
+        };
+        if self.in_flight_requests.len() < max_concurrent {
+            return true;
+        }
+        priority == RequestPriority::High
+            && self.in_flight_requests.len() < max_concurrent + MAX_HIGH_PRIORITY_OVERFLOW
+            && self.high_priority_requests_in_flight() < MAX_HIGH_PRIORITY_OVERFLOW
+    }
+
+    fn high_priority_requests_in_flight(&self) -> usize {
+        lock_metadata(&self.in_flight_metadata)
+            .values()
+            .filter(|metadata| metadata.priority == RequestPriority::High)
+            .count()
+    }
+
+    /// Starts queued requests for as long as there is room for more requests in flight.
+    fn start_queued_requests(&mut self) {
+        while let Some(priority) = self
+            .queued_requests
+            .front()
+            .map(|queued| queued.request.priority())
+        {
+            if !self.can_start(priority) {
+                break;
+            }
+            let queued = match self.queued_requests.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            // The caller is no longer waiting for the response
+            if queued.completion_tx.is_canceled() {
+                continue;
+            }
+            self.start_request(queued.id, queued.request, queued.completion_tx);
+        }
+    }
 
-            RequestCommand::Reset => {
-                self.reset();
+    /// Aborts the in-flight requests to `address`, which closes their connections since
+    /// connections are not reused, and closes any connections to it that are kept by the
+    /// connector. Returns the number of closed connections.
+    fn close_connections(&mut self, address: SocketAddr) -> usize {
+        let ids: Vec<u64> = lock_metadata(&self.in_flight_metadata)
+            .iter()
+            .filter(|(_, metadata)| get_uri_socket_addr(&metadata.uri) == Some(address))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            if let Some(abort_handle) = self.in_flight_requests.remove(id) {
+                abort_handle.abort(AbortReason::ConnectionClosed);
             }
+            lock_metadata(&self.in_flight_metadata).remove(id);
         }
+
+        let closed = ids.len()
+            + self
+                .connection_closer
+                .as_ref()
+                .map(|closer| closer(address))
+                .unwrap_or(0);
+        log::debug!(
+            "{}: Closed {} connections to {}",
+            self.label(),
+            closed,
+            address
+        );
+        closed
+    }
+
+    /// Returns the name used to identify the service in logs.
+    fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| "Unnamed request service".to_owned())
     }
 
-    fn reset(&mut self) {
+    fn reset(&mut self, reason: AbortReason) {
+        log::debug!(
+            "{}: Aborting {} in-flight requests: {}",
+            self.label(),
+            self.in_flight_requests.len(),
+            reason
+        );
         let old_requests = mem::replace(&mut self.in_flight_requests, BTreeMap::new());
         for (_, abort_handle) in old_requests.into_iter() {
-            abort_handle.abort();
+            abort_handle.abort(reason);
+        }
+        for queued in mem::take(&mut self.queued_requests) {
+            self.finish_dedup(queued.id, false);
+            let _ = queued.completion_tx.send(Err(Error::Cancelled(reason)));
         }
-        let _ = mem::replace(&mut self.client, Self::new_client(self.connector.clone()));
-        self.next_id = 0;
+        lock_metadata(&self.in_flight_metadata).clear();
+        let _ = mem::replace(
+            &mut self.client,
+            Self::new_client(
+                self.connector.clone(),
+                self.pool_max_idle_per_host,
+                self.pool_idle_timeout,
+            ),
+        );
+        // IDs are not reused after a reset, so that requests that are still finishing and
+        // `RequestHandle`s of aborted requests can't affect new requests
     }
 
     fn id(&mut self) -> u64 {
@@ -192,80 +1097,1087 @@ impl<C: Connect + Clone + Send + Sync + 'static> RequestService<C> {
     }
 }
 
-fn get_request_socket_addr(request: &Request) -> Option<SocketAddr> {
-    let uri = request.uri();
-    let port = uri
-        .port_u16()
-        // Assuming HTTPS always
-        .unwrap_or(443);
+impl<C> Drop for RequestService<C> {
+    /// Aborts the in-flight and queued requests, since nothing will complete them once the
+    /// service is gone.
+    fn drop(&mut self) {
+        for (_, abort_handle) in mem::take(&mut self.in_flight_requests) {
+            abort_handle.abort(AbortReason::Shutdown);
+        }
+        for queued in self.queued_requests.drain(..) {
+            let _ = queued
+                .completion_tx
+                .send(Err(Error::Cancelled(AbortReason::Shutdown)));
+        }
+        lock_metadata(&self.in_flight_metadata).clear();
+        self.in_flight_count.store(0, Ordering::SeqCst);
+    }
+}
 
-    let host_addr = uri.host().and_then(|host| host.parse::<IpAddr>().ok())?;
+/// Executes a single request on a throwaway client, without the need for a `RequestService`. The
+/// timeouts of the request and the default `HeaderLimits` are applied, but the request can't be
+/// reset and its failures are not registered with any address cache.
+pub async fn oneshot_request<C: Connect + Clone + Send + Sync + 'static>(
+    connector: C,
+    request: RestRequest,
+) -> Result<Response> {
+    let timeout = request.timeout();
+    let connect_timeout = request.connect_timeout();
+    let body_idle_timeout = request.body_idle_timeout();
+    let verify_digest = request.verify_digest();
+    let max_body_size = request.max_body_size();
 
-    Some(SocketAddr::new(host_addr, port))
+    let hyper_request = request.into_request();
+
+    let client = RequestService::new_client(connector, 0, None);
+    let response_future = CONNECT_TIMEOUT.scope(connect_timeout, client.request(hyper_request));
+    let response = with_timeout(response_future, TokioTimer.timeout(timeout))
+        .await
+        .map_err(Error::TimeoutError)
+        .and_then(|response| response.map_err(Error::from));
+    prepare_response(
+        response,
+        HeaderLimits::default(),
+        body_idle_timeout,
+        verify_digest,
+        max_body_size,
+    )
+}
+
+/// Checks the headers of a sent request's `response` against `header_limits`, and attaches the
+/// settings of the request that reading the body depends on. Shared by `RequestService` and
+/// `oneshot_request`.
+fn prepare_response(
+    response: Result<Response>,
+    header_limits: HeaderLimits,
+    body_idle_timeout: Option<Duration>,
+    verify_digest: bool,
+    max_body_size: usize,
+) -> Result<Response> {
+    let mut response = response
+        .map_err(Error::with_connection_cause)
+        .and_then(|response| header_limits.check(response))?;
+    if let Some(timeout) = body_idle_timeout {
+        response.extensions_mut().insert(BodyIdleTimeout(timeout));
+    }
+    if verify_digest {
+        response.extensions_mut().insert(VerifyDigest);
+    }
+    response.extensions_mut().insert(MaxBodySize(max_body_size));
+    Ok(response)
 }
 
+tokio::task_local! {
+    /// The connect timeout of the request that is being sent by the current task.
+    static CONNECT_TIMEOUT: Option<Duration>;
 
-#[derive(Clone)]
-/// A handle to interact with a spawned `RequestService`.
-pub struct RequestServiceHandle {
-    tx: mpsc::Sender<RequestCommand>,
-    handle: Handle,
+    /// The address that the request being sent by the current task is served by. Initially the
+    /// address in the URI of the request, if any, and updated by the connector with the address of
+    /// the peer that it connects to.
+    static CONNECTED_PEER: Arc<Mutex<Option<SocketAddr>>>;
 }
 
-impl RequestServiceHandle {
-    /// Resets the corresponding RequestService, dropping all in-flight requests.
-    pub async fn reset(&self) {
-        let mut tx = self.tx.clone();
+/// Returns where to record the address of the peer that the request being sent by the current
+/// task connects to, so that failures of requests to hostnames are registered against the address
+/// that the hostname resolved to. Failures to establish a TCP connection to a hostname can't be
+/// attributed to an address, since the connector tries every address it resolves to.
+pub(crate) fn connected_peer() -> Option<Arc<Mutex<Option<SocketAddr>>>> {
+    CONNECTED_PEER.try_with(Arc::clone).ok()
+}
+
+/// A connection could not be established within the connect timeout of the request.
+#[derive(err_derive::Error, Debug)]
+#[error(display = "Timed out connecting after {:?}", _0)]
+pub(crate) struct ConnectTimeoutError(Duration);
 
-        let _ = tx.send(RequestCommand::Reset).await;
+/// Wraps `connect`, the future of a connector that establishes a connection, so that it fails
+/// with a `ConnectTimeoutError` if the connect timeout of the request that is being sent by the
+/// current task elapses. Must be called from the connector's `call`, since hyper connects from
+/// within the task of the request.
+pub(crate) fn with_connect_timeout<T>(
+    connect: impl Future<Output = io::Result<T>>,
+) -> impl Future<Output = io::Result<T>> {
+    let timeout = CONNECT_TIMEOUT.try_with(|timeout| *timeout).ok().flatten();
+    async move {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, ConnectTimeoutError(timeout))
+            })?,
+            None => connect.await,
+        }
     }
+}
 
-    /// Submits a `RestRequest` for exectuion to the request service.
-    pub async fn request(&self, request: RestRequest) -> Result<Response> {
-        let (completion_tx, completion_rx) = oneshot::channel();
-        let mut tx = self.tx.clone();
-        tx.send(RequestCommand::NewRequest(request, completion_tx))
-            .await
-            .map_err(|_| Error::SendError)?;
+/// Measures the timeouts of the requests sent by a `RequestService`, and the delays between the
+/// attempts of `RequestServiceHandle::request_with_retry`. The default is `TokioTimer`, but tests
+/// can inject a timer that they control.
+pub trait Timer: Send + Sync {
+    /// Returns a future that completes with `Elapsed` once `duration` has passed.
+    fn timeout(&self, duration: Duration) -> Pin<Box<dyn Future<Output = Elapsed> + Send>>;
+}
 
+/// Spans that carry the ID, method, host and URI of a request, and its final status code or
+/// error, so that the log output of a request can be told apart from that of other requests.
+#[cfg(feature = "request-tracing")]
+mod request_span {
+    use super::{Request, Response, Result};
+    use hyper::header;
+    use tracing::field;
 
-        completion_rx.await.map_err(|_| Error::ReceiveError)?
+    /// Returns the span of request `id`.
+    pub(super) fn new(id: u64, request: &Request) -> tracing::Span {
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().host())
+            .unwrap_or_default();
+        tracing::info_span!(
+            "request",
+            id,
+            method = %request.method(),
+            host,
+            uri = %request.uri(),
+            status = field::Empty,
+            error = field::Empty,
+        )
     }
 
-    /// Spawns a future on the RPC runtime.
-    pub fn spawn<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) {
-        let _ = self.handle.spawn(future);
+    /// Records the status code or the error of `response` in `span`.
+    pub(super) fn record_result(span: &tracing::Span, response: &Result<Response>) {
+        match response {
+            Ok(response) => span.record("status", &response.status().as_u16()),
+            Err(error) => span.record("error", &field::display(error)),
+        };
     }
 }
 
-#[derive(Debug)]
-enum RequestCommand {
-    NewRequest(
-        RestRequest,
-        oneshot::Sender<std::result::Result<Response, Error>>,
-    ),
-    RequestFinished(u64),
-    Reset,
+/// Observes the requests sent by a `RequestService`, e.g. to record latency metrics or to trace
+/// requests. Both methods do nothing by default. They are called on the runtime of the service, so
+/// they should return quickly. Requests that never start, because they are suppressed as
+/// duplicates or cancelled while queued, are not observed.
+pub trait RequestObserver: Send + Sync {
+    /// Called when request `id` is dispatched to `uri`, which contains the API address.
+    fn on_request_start(&self, _id: u64, _method: &Method, _uri: &Uri) {}
+
+    /// Called when the response headers of request `id` have been received or the request has
+    /// failed, `duration` after it started.
+    fn on_request_end(
+        &self,
+        _id: u64,
+        _duration: Duration,
+        _result: std::result::Result<StatusCode, &Error>,
+    ) {
+    }
 }
 
+/// A `Timer` that uses the timer of the tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
 
-/// A REST request that is sent to the RequestService to be executed.
-#[derive(Debug)]
-pub struct RestRequest {
-    request: Request,
-    timeout: Duration,
-    auth: Option<HeaderValue>,
+impl Timer for TokioTimer {
+    fn timeout(&self, duration: Duration) -> Pin<Box<dyn Future<Output = Elapsed> + Send>> {
+        Box::pin(
+            tokio::time::timeout(duration, futures::future::pending::<Infallible>()).map(
+                |result| match result {
+                    Ok(never) => match never {},
+                    Err(elapsed) => elapsed,
+                },
+            ),
+        )
+    }
 }
 
-impl RestRequest {
-    /// Constructs a GET request with the given URI. Returns an error if the URI is not valid.
-    pub fn get(uri: &str) -> Result<Self> {
+/// Resolves to the output of `future`, or fails with `Elapsed` if `timeout` completes first.
+async fn with_timeout<F: Future>(
+    future: F,
+    timeout: Pin<Box<dyn Future<Output = Elapsed> + Send>>,
+) -> std::result::Result<F::Output, Elapsed> {
+    match select(Box::pin(future), timeout).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right((elapsed, _)) => Err(elapsed),
+    }
+}
+
+/// Sends `request`, and resends it to each of `fallback_addresses` in turn for as long as a
+/// connection can't be established. Every attempt is registered with `address_cache`, and so are
+/// the failed addresses if `report_failures` is set. `served_addr` is updated with the address
+/// that was tried last.
+async fn send_with_fallback<C: Connect + Clone + Send + Sync + 'static>(
+    client: Client<C, hyper::Body>,
+    request: Request,
+    fallback_addresses: Vec<SocketAddr>,
+    address_cache: AddressCache,
+    report_failures: bool,
+    served_addr: Arc<Mutex<Option<SocketAddr>>>,
+) -> Result<Response> {
+    let register_dispatch = || {
+        if let Some(address) = *served_addr.lock().unwrap() {
+            address_cache.register_dispatch(address);
+        }
+    };
+    register_dispatch();
+    if fallback_addresses.is_empty() {
+        return client.request(request).await.map_err(Error::from);
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let mut uri = parts.uri.clone();
+    let mut fallback_addresses = fallback_addresses
+        .into_iter()
+        .filter_map(|address| uri_with_address(&parts.uri, address).map(|uri| (address, uri)));
+    loop {
+        let mut request = Request::new(hyper::Body::from(body.clone()));
+        *request.method_mut() = parts.method.clone();
+        *request.uri_mut() = uri;
+        *request.version_mut() = parts.version;
+        *request.headers_mut() = parts.headers.clone();
+
+        let error = match client.request(request).await {
+            Err(error) if error.is_connect() => error,
+            result => return result.map_err(Error::from),
+        };
+        let (address, next_uri) = match fallback_addresses.next() {
+            Some(next) => next,
+            None => return Err(Error::from(error)),
+        };
+        let mut last_addr = served_addr.lock().unwrap();
+        if let Some(failed_addr) = *last_addr {
+            log::debug!(
+                "Failed to connect to {}, falling back on {}",
+                failed_addr,
+                address
+            );
+            if report_failures {
+                address_cache.register_failure(failed_addr, &error);
+            }
+        }
+        *last_addr = Some(address);
+        drop(last_addr);
+        register_dispatch();
+        uri = next_uri;
+    }
+}
+
+/// Returns whether `status` redirects the request to the location in the `Location` header.
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// Returns `uri` with its authority replaced by `address`.
+fn uri_with_address(uri: &Uri, address: SocketAddr) -> Option<Uri> {
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(address.to_string().parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Returns the value of the `Host` header for `uri`, which includes the port if it isn't the
+/// default port of the scheme, as required by RFC 7230.
+fn host_header(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let default_port = match uri.scheme_str() {
+        Some("http") => Some(80),
+        Some("https") => Some(443),
+        _ => None,
+    };
+    match uri.port_u16() {
+        Some(port) if Some(port) != default_port => Some(format!("{}:{}", host, port)),
+        _ => Some(host.to_owned()),
+    }
+}
+
+fn get_request_socket_addr(request: &Request) -> Option<SocketAddr> {
+    get_uri_socket_addr(request.uri())
+}
+
+pub(crate) fn get_uri_socket_addr(uri: &Uri) -> Option<SocketAddr> {
+    let port = uri
+        .port_u16()
+        // Assuming HTTPS always
+        .unwrap_or(443);
+
+    // IPv6 hosts are bracketed in URIs
+    let host_addr = uri
+        .host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|host| host.parse::<IpAddr>().ok())?;
+
+    Some(SocketAddr::new(host_addr, port))
+}
+
+struct RequestMetadata {
+    method: Method,
+    uri: Uri,
+    started: DateTime<Utc>,
+    start_instant: Instant,
+    priority: RequestPriority,
+}
+
+/// Locks the in-flight request metadata, ignoring poisoning so that it can still be inspected
+/// after a panic.
+fn lock_metadata(
+    metadata: &Mutex<BTreeMap<u64, RequestMetadata>>,
+) -> MutexGuard<'_, BTreeMap<u64, RequestMetadata>> {
+    metadata
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Metadata describing a request that is being executed by a `RequestService`, intended for
+/// crash diagnostics. Never contains request bodies or auth.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InFlightRequest {
+    pub id: u64,
+    pub method: String,
+    pub uri: String,
+    pub started: DateTime<Utc>,
+    pub elapsed: Duration,
+}
+
+/// A snapshot of the effective configuration of a `RequestService`, intended for diagnostics.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ServiceConfig {
+    /// Name of the service, if any.
+    pub name: Option<String>,
+    /// Timeout applied to requests that don't specify their own, if known. Only known when the
+    /// snapshot is taken through `MullvadRestHandle::config`.
+    pub default_timeout: Option<Duration>,
+    /// Maximum number of idle connections kept alive per host.
+    pub pool_max_idle_per_host: usize,
+    /// Time that idle connections are kept alive, if not hyper's default.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Maximum number of requests in flight at once, if limited.
+    pub max_concurrent: Option<usize>,
+    /// Time between the current and the next API address fetch, if the service is used by an API
+    /// address fetcher.
+    pub api_address_fetch_interval: Option<Duration>,
+    /// Limits on the headers of responses.
+    pub header_limits: HeaderLimits,
+}
+
+/// Limits on the headers of a response, protecting against servers that send enough headers to
+/// exhaust memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct HeaderLimits {
+    /// Maximum number of headers.
+    pub max_count: usize,
+    /// Maximum total size of the names and values of all headers, in bytes.
+    pub max_size: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_count: DEFAULT_MAX_RESPONSE_HEADERS,
+            max_size: DEFAULT_MAX_RESPONSE_HEADERS_SIZE,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Returns the response if its headers are within the limits. Otherwise, the response is
+    /// dropped without reading its body.
+    fn check(&self, response: Response) -> Result<Response> {
+        let headers = response.headers();
+        let count = headers.len();
+        let size: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if count > self.max_count || size > self.max_size {
+            return Err(Error::TooManyHeaders(count, size));
+        }
+        Ok(response)
+    }
+}
+
+/// The success rate of the requests in a window of recently completed requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuccessRate {
+    /// Number of requests in the window that succeeded.
+    pub successes: usize,
+    /// Number of requests in the window.
+    pub total: usize,
+    /// Time at which the oldest request in the window completed, if the window isn't empty.
+    pub window_start: Option<Instant>,
+    /// Time at which the success rate was computed.
+    pub window_end: Instant,
+}
+
+impl SuccessRate {
+    /// Returns the fraction of requests that succeeded, or `None` if the window is empty.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(self.successes as f64 / self.total as f64)
+    }
+}
+
+/// The outcomes of recently completed requests, oldest first.
+#[derive(Default)]
+struct OutcomeWindow {
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+impl OutcomeWindow {
+    fn record(&mut self, success: bool) {
+        self.outcomes.push_back((Instant::now(), success));
+        if self.outcomes.len() > SUCCESS_WINDOW_MAX_REQUESTS {
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn success_rate(&mut self) -> SuccessRate {
+        let now = Instant::now();
+        while let Some((completed, _)) = self.outcomes.front() {
+            if now.saturating_duration_since(*completed) <= SUCCESS_WINDOW_MAX_AGE {
+                break;
+            }
+            self.outcomes.pop_front();
+        }
+        SuccessRate {
+            successes: self.outcomes.iter().filter(|(_, success)| *success).count(),
+            total: self.outcomes.len(),
+            window_start: self.outcomes.front().map(|(completed, _)| *completed),
+            window_end: now,
+        }
+    }
+}
+
+/// A stage in the lifecycle of a request executed by a `RequestService`.
+#[derive(Debug, Clone)]
+pub enum RequestEvent {
+    /// The request was received by the service.
+    Queued,
+    /// The request was handed to the HTTP client, which connects and sends it.
+    Dispatched,
+    /// The request was sent over a newly established connection. Since the HTTP client doesn't
+    /// report when a connection is established, this is emitted together with
+    /// `HeadersReceived`, but carries the time the connection took to establish. Only emitted
+    /// by connectors that measure it.
+    ConnectionEstablished(ConnectionTiming),
+    /// The response headers were received.
+    HeadersReceived,
+    /// The response was passed on to the caller, who reads the body.
+    Completed { status: StatusCode },
+    /// The request failed.
+    Failed { error_kind: FailureKind },
+    /// The request was aborted before a response was received.
+    Aborted { reason: AbortReason },
+}
+
+/// Describes how a request failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// No response was received before the request timed out.
+    Timeout,
+    /// The connection failed, or the HTTP exchange was malformed.
+    Network,
+    /// Any other error.
+    Other,
+}
+
+impl FailureKind {
+    fn from_error(error: &Error) -> Self {
+        match error {
+            Error::TimeoutError(_) | Error::ConnectTimeout(_) => FailureKind::Timeout,
+            Error::HyperError(_) => FailureKind::Network,
+            Error::RetriesStopped(_, error) => FailureKind::from_error(error),
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// A `RequestEvent` of the request with the given ID, and the time it occurred.
+#[derive(Debug, Clone)]
+pub struct RequestEventRecord {
+    pub id: u64,
+    pub time: Instant,
+    pub event: RequestEvent,
+}
+
+/// The subscribers to the request events of a `RequestService`.
+#[derive(Clone, Default)]
+struct RequestEventSubscribers {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<RequestEventRecord>>>>,
+}
+
+impl RequestEventSubscribers {
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<RequestEventRecord> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn emit(&self, id: u64, event: RequestEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let record = RequestEventRecord {
+            id,
+            time: Instant::now(),
+            event,
+        };
+        subscribers.retain(|subscriber| subscriber.unbounded_send(record.clone()).is_ok());
+    }
+}
+
+#[derive(Clone)]
+/// A handle to interact with a spawned `RequestService`.
+pub struct RequestServiceHandle {
+    tx: mpsc::Sender<RequestCommand>,
+    handle: Handle,
+    in_flight_metadata: Arc<Mutex<BTreeMap<u64, RequestMetadata>>>,
+    in_flight_count: Arc<AtomicUsize>,
+    event_subscribers: RequestEventSubscribers,
+    outcomes: Arc<Mutex<OutcomeWindow>>,
+    timer: Arc<dyn Timer>,
+}
+
+impl RequestServiceHandle {
+    /// Resets the corresponding RequestService, dropping all in-flight requests.
+    pub async fn reset(&self) {
+        self.reset_with_reason(AbortReason::Reset).await
+    }
+
+    /// Resets the corresponding RequestService, dropping all in-flight requests. The callers of
+    /// the dropped requests receive an `Error::Cancelled` with the given reason.
+    pub async fn reset_with_reason(&self, reason: AbortReason) {
+        let mut tx = self.tx.clone();
+
+        let _ = tx.send(RequestCommand::Reset(reason)).await;
+    }
+
+    /// Submits a `RestRequest` for exectuion to the request service.
+    pub async fn request(&self, request: RestRequest) -> Result<Response> {
+        self.request_counted(request, &mut 0, None).await
+    }
+
+    /// Like `request`, but counts the round trips that are made in `round_trips`, which may
+    /// already include round trips made for earlier attempts. `previous` is the outcome of the
+    /// last of those round trips, if any. See `submit_counted`.
+    async fn request_counted(
+        &self,
+        request: RestRequest,
+        round_trips: &mut u32,
+        previous: Option<Error>,
+    ) -> Result<Response> {
+        if request.max_redirects() == 0 || !request.is_replayable() {
+            return self.submit_counted(request, round_trips, previous).await;
+        }
+        self.request_with_redirects(request, round_trips, previous)
+            .await
+    }
+
+    /// Submits `request` and deserializes the body of the response. Responses with another status
+    /// than `expected_status` are converted into errors by `handle_error_response`, like with
+    /// `parse_rest_response`.
+    pub async fn request_json<T: serde::de::DeserializeOwned>(
+        &self,
+        request: RestRequest,
+        expected_status: StatusCode,
+    ) -> Result<T> {
+        let response = self.request(request).await?;
+        deserialize_body(parse_rest_response(response, expected_status).await?).await
+    }
+
+    /// Submits `request`, following up to `RestRequest::max_redirects` redirects. Every followed
+    /// redirect is a round trip that counts towards `RestRequest::max_total_attempts`.
+    async fn request_with_redirects(
+        &self,
+        mut request: RestRequest,
+        round_trips: &mut u32,
+        mut previous: Option<Error>,
+    ) -> Result<Response> {
+        let max_redirects = request.max_redirects();
+        let mut body = request.take_body().await?;
+
+        let mut redirects = 0;
+        loop {
+            let response = self
+                .submit_counted(request.copy_with_body(body.clone()), round_trips, previous)
+                .await?;
+            let location = match response.headers().get(header::LOCATION) {
+                Some(location) if is_redirect(response.status()) => location.clone(),
+                _ => return Ok(response),
+            };
+            if redirects >= max_redirects {
+                return Err(Error::TooManyRedirects(max_redirects));
+            }
+            redirects += 1;
+            previous = Some(Error::Redirect(
+                response.status(),
+                String::from_utf8_lossy(location.as_bytes()).into_owned(),
+            ));
+            request.redirect(response.status(), &location)?;
+            if response.status() == StatusCode::SEE_OTHER {
+                body = hyper::body::Bytes::new();
+            }
+        }
+    }
+
+    /// Submits `request` as another round trip, and counts it in `round_trips`. If `round_trips`
+    /// has already reached `RestRequest::max_total_attempts`, the request is not submitted, and
+    /// `previous`, the outcome of the last round trip, is returned in
+    /// `Error::AttemptLimitExceeded`.
+    async fn submit_counted(
+        &self,
+        request: RestRequest,
+        round_trips: &mut u32,
+        previous: Option<Error>,
+    ) -> Result<Response> {
+        let max_total_attempts = request.max_total_attempts();
+        if let Some(previous) = previous {
+            if *round_trips >= max_total_attempts {
+                return Err(Error::AttemptLimitExceeded(
+                    max_total_attempts,
+                    Box::new(previous),
+                ));
+            }
+        }
+        *round_trips += 1;
+        self.submit(request).await
+    }
+
+    async fn submit(&self, request: RestRequest) -> Result<Response> {
+        let (completion_tx, completion_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        tx.send(RequestCommand::NewRequest(request, completion_tx, None))
+            .await
+            .map_err(|_| Error::SendError)?;
+
+
+        // The request is only dropped without a response if the runtime is shut down
+        completion_rx
+            .await
+            .map_err(|_| Error::Cancelled(AbortReason::Shutdown))?
+    }
+
+    /// Submits a `RestRequest` like `request`, but returns a `RequestHandle` that can cancel the
+    /// request without affecting other requests, along with a future that resolves to the
+    /// response.
+    pub async fn request_with_handle(
+        &self,
+        request: RestRequest,
+    ) -> Result<(RequestHandle, impl Future<Output = Result<Response>>)> {
+        let (completion_tx, completion_rx) = oneshot::channel();
+        let (id_tx, id_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        tx.send(RequestCommand::NewRequest(
+            request,
+            completion_tx,
+            Some(id_tx),
+        ))
+        .await
+        .map_err(|_| Error::SendError)?;
+        let id = id_rx.await.map_err(|_| Error::ReceiveError)?;
+
+        let handle = RequestHandle {
+            id,
+            tx: self.tx.clone(),
+        };
+        let response = async move {
+            completion_rx
+                .await
+                .map_err(|_| Error::Cancelled(AbortReason::Shutdown))?
+        };
+        Ok((handle, response))
+    }
+
+    /// Submits a `RestRequest` and resubmits it according to `policy` for as long as it fails with
+    /// an error for which `Error::is_retryable` is true, which is the case for connection errors
+    /// and timeouts. Responses are returned as they are, regardless of their status. Every attempt,
+    /// and every redirect followed by an attempt, counts towards `RestRequest::max_total_attempts`,
+    /// and exceeding it fails with `Error::AttemptLimitExceeded`. Otherwise, the error of the last
+    /// attempt is returned wrapped in `Error::RetriesStopped`.
+    ///
+    /// The body of the request is buffered in memory, so that it can be sent again. Requests with
+    /// a streaming body are submitted once, and their error is returned as it is.
+    pub async fn request_with_retry(
+        &self,
+        mut request: RestRequest,
+        policy: RetryPolicy,
+    ) -> Result<Response> {
+        if !request.is_replayable() {
+            return self.request(request).await;
+        }
+        let max_attempts = policy.max_attempts.max(1);
+        let request_timeout = request.timeout();
+        let body = request.take_body().await?;
+        let start = Instant::now();
+
+        let mut attempts = 0;
+        // Redirects and retries share the limit on round trips
+        let mut round_trips = 0;
+        let mut previous = None;
+        loop {
+            let mut attempt = request.copy_with_body(body.clone());
+            if let Some(timeout) =
+                policy.attempt_timeout(request_timeout, attempts, start.elapsed())
+            {
+                attempt.set_timeout(timeout);
+            }
+            let result = self
+                .request_counted(attempt, &mut round_trips, previous.take())
+                .await;
+            let error = match result {
+                Err(error @ Error::AttemptLimitExceeded(..)) => return Err(error),
+                Err(error) => error,
+                response => return response,
+            };
+            attempts += 1;
+
+            let stop_reason = if !error.is_retryable() {
+                Some(RetryStopReason::NonRetryable)
+            } else if attempts >= max_attempts {
+                Some(RetryStopReason::AttemptsExhausted)
+            } else {
+                None
+            };
+            let delay = policy.jittered_delay(attempts - 1);
+            let stop_reason = stop_reason.or_else(|| {
+                policy
+                    .deadline
+                    .filter(|deadline| start.elapsed() + delay >= *deadline)
+                    .map(|_| RetryStopReason::DeadlineExceeded)
+            });
+            if let Some(stop_reason) = stop_reason {
+                return Err(Error::RetriesStopped(
+                    RetrySummary {
+                        attempts,
+                        stop_reason,
+                    },
+                    Box::new(error),
+                ));
+            }
+            log::debug!(
+                "Attempt {} of {} failed: {}. Retrying in {:?}",
+                attempts,
+                max_attempts,
+                error,
+                delay
+            );
+            self.timer.timeout(delay).await;
+            previous = Some(error);
+        }
+    }
+
+    /// Returns metadata for all requests currently executed by the corresponding RequestService.
+    /// This does not wait for the service, so it can be used from a panic hook.
+    pub fn in_flight_requests(&self) -> Vec<InFlightRequest> {
+        lock_metadata(&self.in_flight_metadata)
+            .iter()
+            .map(|(id, metadata)| InFlightRequest {
+                id: *id,
+                method: metadata.method.to_string(),
+                uri: metadata.uri.to_string(),
+                started: metadata.started,
+                elapsed: metadata.start_instant.elapsed(),
+            })
+            .collect()
+    }
+
+    /// Returns the number of requests currently executed by the corresponding RequestService.
+    /// Queued requests are not counted.
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight_count.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the corresponding RequestService has no requests in flight. This doesn't stop
+    /// the service from accepting new requests, so callers that want to drain the service should
+    /// stop submitting requests first. Resolves immediately if the service is already idle, or if
+    /// the service has stopped.
+    pub async fn wait_until_idle(&self) {
+        let (idle_tx, idle_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        if tx
+            .send(RequestCommand::WaitUntilIdle(idle_tx))
+            .await
+            .is_ok()
+        {
+            let _ = idle_rx.await;
+        }
+    }
+
+    /// Returns the success rate of the requests recently completed by the corresponding
+    /// RequestService, computed from at most the last 100 requests completed in the last 5
+    /// minutes. Requests that failed with a server error or without a response count as failures.
+    /// Aborted requests are not counted at all.
+    pub fn success_rate(&self) -> SuccessRate {
+        self.outcomes.lock().unwrap().success_rate()
+    }
+
+    /// Returns a stream of the lifecycle events of all requests subsequently executed by the
+    /// corresponding RequestService. Events of the same request have the same ID. Events are
+    /// buffered until they're read, so the stream should be read continuously or dropped.
+    pub fn subscribe_events(&self) -> mpsc::UnboundedReceiver<RequestEventRecord> {
+        self.event_subscribers.subscribe()
+    }
+
+    /// Returns a snapshot of the effective configuration of the corresponding RequestService.
+    pub async fn config(&self) -> Result<ServiceConfig> {
+        let (config_tx, config_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        tx.send(RequestCommand::GetConfig(config_tx))
+            .await
+            .map_err(|_| Error::SendError)?;
+
+        config_rx.await.map_err(|_| Error::ReceiveError)
+    }
+
+    /// Closes the connections to `address`, leaving connections to other addresses intact. The
+    /// callers of in-flight requests to `address` receive an `Error::Cancelled`. Returns the number
+    /// of closed connections.
+    pub async fn close_connections(&self, address: SocketAddr) -> Result<usize> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        tx.send(RequestCommand::CloseConnections(address, result_tx))
+            .await
+            .map_err(|_| Error::SendError)?;
+
+        result_rx.await.map_err(|_| Error::ReceiveError)
+    }
+
+    /// Validates all `requests`, and only if they're all valid, submits them concurrently. Returns
+    /// the results in the same order as the requests. Fails with `Error::InvalidBatchRequest` for
+    /// the first invalid request, without submitting any of them.
+    pub async fn request_all_validated(
+        &self,
+        requests: Vec<RestRequest>,
+    ) -> Result<Vec<Result<Response>>> {
+        for (index, request) in requests.iter().enumerate() {
+            request
+                .validate()
+                .map_err(|error| Error::InvalidBatchRequest(index, Box::new(error)))?;
+        }
+        Ok(join_all(requests.into_iter().map(|request| self.request(request))).await)
+    }
+
+    /// Submits all `requests` concurrently, and summarizes their results in a `BatchOutcome`.
+    /// The results are kept in the same order as the requests.
+    pub async fn request_all(&self, requests: Vec<RestRequest>) -> BatchOutcome {
+        let start = Instant::now();
+        let results = join_all(requests.into_iter().map(|request| self.request(request))).await;
+        BatchOutcome::new(results, start.elapsed())
+    }
+
+    /// Spawns a future on the RPC runtime.
+    pub fn spawn<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) {
+        let _ = self.handle.spawn(future);
+    }
+}
+
+/// Results of a batch of requests submitted by `RequestServiceHandle::request_all`.
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// Results of the individual requests, in the order they were submitted.
+    pub results: Vec<Result<Response>>,
+    /// Number of requests that received a response.
+    pub succeeded: usize,
+    /// Number of requests that failed, excluding aborted requests.
+    pub failed: usize,
+    /// Number of requests that were aborted before they completed.
+    pub aborted: usize,
+    /// Time it took for all requests to complete.
+    pub elapsed: Duration,
+}
+
+impl BatchOutcome {
+    fn new(results: Vec<Result<Response>>, elapsed: Duration) -> Self {
+        let mut outcome = BatchOutcome {
+            results: Vec::with_capacity(results.len()),
+            succeeded: 0,
+            failed: 0,
+            aborted: 0,
+            elapsed,
+        };
+        for result in results {
+            match &result {
+                Ok(_) => outcome.succeeded += 1,
+                Err(Error::Cancelled(_)) => outcome.aborted += 1,
+                Err(_) => outcome.failed += 1,
+            }
+            outcome.results.push(result);
+        }
+        outcome
+    }
+
+    /// Returns the total number of requests in the batch.
+    pub fn total(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Returns whether every request in the batch succeeded.
+    pub fn all_succeeded(&self) -> bool {
+        self.succeeded == self.total()
+    }
+
+    /// Returns whether at least `min_succeeded` requests in the batch succeeded.
+    pub fn at_least_succeeded(&self, min_succeeded: usize) -> bool {
+        self.succeeded >= min_succeeded
+    }
+}
+
+#[derive(Debug)]
+enum RequestCommand {
+    /// Submits a request. The ID of the request is sent on the optional channel.
+    NewRequest(
+        RestRequest,
+        oneshot::Sender<std::result::Result<Response, Error>>,
+        Option<oneshot::Sender<u64>>,
+    ),
+    /// A request has finished, successfully if the flag is set.
+    RequestFinished(u64, bool),
+    CancelRequest(u64),
+    Reset(AbortReason),
+    GetConfig(oneshot::Sender<ServiceConfig>),
+    CloseConnections(SocketAddr, oneshot::Sender<usize>),
+    WaitUntilIdle(oneshot::Sender<()>),
+}
+
+
+/// Cancels a single request submitted by `RequestServiceHandle::request_with_handle`.
+#[derive(Debug, Clone)]
+pub struct RequestHandle {
+    id: u64,
+    tx: mpsc::Sender<RequestCommand>,
+}
+
+impl RequestHandle {
+    /// Returns the ID of the request, as used in its `RequestEvent`s.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Aborts the request, if it's still in flight. Its caller receives an `Error::Cancelled` with
+    /// `AbortReason::CallerCancelled`. Other requests are not affected.
+    pub async fn cancel(&self) {
+        let mut tx = self.tx.clone();
+        let _ = tx.send(RequestCommand::CancelRequest(self.id)).await;
+    }
+}
+
+/// A REST request that is sent to the RequestService to be executed.
+#[derive(Debug)]
+pub struct RestRequest {
+    request: Request,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    body_idle_timeout: Option<Duration>,
+    auth: RequestAuth,
+    max_total_attempts: u32,
+    max_redirects: u32,
+    cache_ttl: Option<Duration>,
+    preferred_address: Option<(SocketAddr, FallbackMode)>,
+    replayable: bool,
+    rotate_on_failure: bool,
+    custom_headers: HeaderMap,
+    verify_digest: bool,
+    max_body_size: usize,
+    priority: RequestPriority,
+    dedup_window: Option<Duration>,
+    /// The body, if it's buffered in memory.
+    body: Option<hyper::body::Bytes>,
+}
+
+/// The scheme of the `Authorization` header of a `RestRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// `Token`, used by most API endpoints.
+    Token,
+    /// `Bearer`, as defined by RFC 6750.
+    Bearer,
+}
+
+impl fmt::Display for AuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthScheme::Token => f.write_str("Token"),
+            AuthScheme::Bearer => f.write_str("Bearer"),
+        }
+    }
+}
+
+/// An account token, or another credential that is sent in the `Authorization` header. Its
+/// contents are validated when it's created, so that it can always be sent in a header.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AccountToken(String);
+
+impl AccountToken {
+    /// Validates `token`, which must consist of 1 to 1024 characters in the `token68` charset of
+    /// RFC 7235: ASCII letters and digits, `-`, `.`, `_`, `~`, `+` and `/`, optionally followed
+    /// by `=` padding. In particular, whitespace and control characters are rejected. Fails with
+    /// `Error::InvalidAccountToken` otherwise.
+    pub fn new(token: impl Into<String>) -> Result<Self> {
+        let token = token.into();
+        let value = token.trim_end_matches('=');
+        let is_valid = !value.is_empty()
+            && token.len() <= MAX_ACCOUNT_TOKEN_LENGTH
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c));
+        if is_valid {
+            Ok(Self(token))
+        } else {
+            Err(Error::InvalidAccountToken)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for AccountToken {
+    /// Doesn't reveal the token, since it's a secret.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccountToken(..)")
+    }
+}
+
+/// The auth of a `RestRequest`.
+#[derive(Debug, Clone)]
+enum RequestAuth {
+    /// An `Authorization` header set on the request, or no auth.
+    Fixed(Option<HeaderValue>),
+    /// The default account token of the factory that built the request. It is read when the
+    /// request is dispatched, so that requests use the latest token.
+    Default(Arc<Mutex<Option<AccountToken>>>),
+}
+
+impl RequestAuth {
+    fn header(&self) -> Option<HeaderValue> {
+        match self {
+            RequestAuth::Fixed(header) => header.clone(),
+            // The token was validated when it was created
+            RequestAuth::Default(token) => token
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|token| auth_header(AuthScheme::Token, token).ok()),
+        }
+    }
+}
+
+impl RestRequest {
+    /// Constructs a GET request with the given URI. Returns an error if the URI is not valid.
+    pub fn get(uri: &str) -> Result<Self> {
         let uri = hyper::Uri::from_str(&uri).map_err(Error::UriError)?;
 
         let mut builder = http::request::Builder::new()
             .method(Method::GET)
             .header(header::ACCEPT, HeaderValue::from_static("application/json"));
-        if let Some(host) = uri.host() {
+        if let Some(host) = host_header(&uri) {
             builder = builder.header(header::HOST, HeaderValue::from_str(&host)?);
         };
 
@@ -277,341 +2189,4941 @@ impl RestRequest {
 
         Ok(RestRequest {
             timeout: DEFAULT_TIMEOUT,
-            auth: None,
+            connect_timeout: None,
+            body_idle_timeout: None,
+            auth: RequestAuth::Fixed(None),
+            max_total_attempts: DEFAULT_MAX_TOTAL_ATTEMPTS,
+            max_redirects: 0,
+            cache_ttl: None,
+            preferred_address: None,
+            replayable: true,
+            rotate_on_failure: true,
+            custom_headers: HeaderMap::new(),
+            verify_digest: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            priority: RequestPriority::Normal,
+            dedup_window: None,
+            body: Some(hyper::body::Bytes::new()),
             request,
         })
     }
 
-    /// Set the auth header with the following format: `Token $auth`.
-    pub fn set_auth(&mut self, auth: Option<String>) -> Result<()> {
+    /// Wraps a hyper request like `From<Request>`, but with `timeout` instead of
+    /// `DEFAULT_TIMEOUT`. Intended for one-off requests that aren't built by a `RequestFactory`.
+    pub fn from_request_with_timeout(request: Request, timeout: Duration) -> Self {
+        let mut request = Self::from(request);
+        request.timeout = timeout;
+        request
+    }
+
+    /// Set the auth header with the following format: `Token $auth`. Passing `None` clears any
+    /// auth, including a default set by the `RequestFactory` that built the request.
+    pub fn set_auth(&mut self, auth: Option<AccountToken>) -> Result<()> {
+        self.set_auth_with_scheme(AuthScheme::Token, auth)
+    }
+
+    /// Like `set_auth`, but sets the auth header with the given scheme: `$scheme $auth`.
+    pub fn set_auth_with_scheme(
+        &mut self,
+        scheme: AuthScheme,
+        auth: Option<AccountToken>,
+    ) -> Result<()> {
         let header = match auth {
-            Some(auth) => Some(
-                HeaderValue::from_str(&format!("Token {}", auth))
-                    .map_err(Error::InvalidHeaderError)?,
-            ),
+            Some(auth) => Some(auth_header(scheme, &auth)?),
             None => None,
         };
 
-        self.auth = header;
+        self.auth = RequestAuth::Fixed(header);
+        Ok(())
+    }
+
+    /// Asks the server to respond with an empty body instead of the affected resource, by setting
+    /// the `Prefer: return=minimal` header.
+    pub fn set_prefer_return_minimal(&mut self) {
+        self.request.headers_mut().insert(
+            HeaderName::from_static("prefer"),
+            HeaderValue::from_static("return=minimal"),
+        );
+    }
+
+    /// Checks that the request is well-formed: it must have an absolute `http` or `https` URI and
+    /// a `Host` header, a `Content-Length` header must match the length of the body if that is
+    /// known, and the timeout must not be zero. Fails with `Error::InvalidRequest` otherwise.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::InvalidRequest(reason.to_owned()));
+        let uri = self.request.uri();
+        match uri.scheme_str() {
+            Some("https") | Some("http") => (),
+            _ => return invalid("the URI must have an http or https scheme"),
+        }
+        if uri.host().is_none() {
+            return invalid("the URI has no host");
+        }
+
+        let headers = self.request.headers();
+        if !headers.contains_key(header::HOST) {
+            return invalid("the Host header is missing");
+        }
+        if let Some(content_length) = headers.get(header::CONTENT_LENGTH) {
+            let content_length = content_length
+                .to_str()
+                .ok()
+                .and_then(|length| length.parse::<u64>().ok());
+            let content_length = match content_length {
+                Some(content_length) => content_length,
+                None => return invalid("the Content-Length header is not a number"),
+            };
+            let body_length = hyper::body::HttpBody::size_hint(self.request.body()).exact();
+            if let Some(body_length) = body_length {
+                if body_length != content_length {
+                    return invalid("the Content-Length header doesn't match the body");
+                }
+            }
+        }
+
+        if self.timeout == Duration::from_secs(0) {
+            return invalid("the timeout is zero");
+        }
+        Ok(())
+    }
+
+    /// Sets the `traceparent` header, so that traces of the request on the server can be linked
+    /// to the trace of the caller.
+    pub fn set_trace_context(&mut self, context: TraceContext) {
+        // The formatted context only contains hex digits and dashes
+        let value = HeaderValue::from_str(&context.to_string()).unwrap();
+        self.request
+            .headers_mut()
+            .insert(HeaderName::from_static("traceparent"), value);
+    }
+
+    /// Sets a custom header, replacing any other value of it, such as a default of the factory.
+    /// The `Authorization` header set by the auth of the request takes precedence over a custom
+    /// one.
+    pub fn set_header(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.custom_headers.insert(name, value);
         Ok(())
     }
 
+    /// Like `set_header`, but takes the value as a string. Fails with `Error::InvalidHeaderError`
+    /// if it isn't a valid header value.
+    pub fn header_str(&mut self, name: HeaderName, value: &str) -> Result<()> {
+        let value = HeaderValue::from_str(value).map_err(Error::InvalidHeaderError)?;
+        self.set_header(name, value)
+    }
+
+    pub(crate) fn headers_mut(&mut self) -> &mut HeaderMap {
+        self.request.headers_mut()
+    }
+
     /// Sets timeout for the request.
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
 
-    /// Retrieves timeout
-    pub fn timeout(&self) -> Duration {
-        self.timeout
+    /// Retrieves timeout
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sets the maximum time to wait for a connection to be established, including the TLS
+    /// handshake. Unlike the overall timeout, this fails fast on unreachable API addresses without
+    /// cutting off responses that are merely slow. If it elapses, the request fails with
+    /// `Error::ConnectTimeout`. `None`, the default, only applies the overall timeout. Only
+    /// connectors that support it, such as `HttpsConnectorWithSni`, enforce the connect timeout.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Retrieves the connect timeout
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Sets the maximum time to wait for more of the response body once the headers have been
+    /// received. If no body data arrives within this time, reading the body fails with
+    /// `Error::TimeoutError`. `None`, the default, waits indefinitely.
+    pub fn set_body_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.body_idle_timeout = timeout;
+    }
+
+    /// Retrieves the body idle timeout
+    pub fn body_idle_timeout(&self) -> Option<Duration> {
+        self.body_idle_timeout
+    }
+
+    /// Sets the maximum number of network round trips the request may make in total. Every
+    /// followed redirect and every retry counts as one attempt, regardless of which mechanism
+    /// triggered it. A limit of 0 is treated as 1, since the first attempt is always made.
+    pub fn set_max_total_attempts(&mut self, max_total_attempts: u32) {
+        self.max_total_attempts = max_total_attempts.max(1);
+    }
+
+    /// Retrieves the maximum number of network round trips the request may make in total.
+    pub fn max_total_attempts(&self) -> u32 {
+        self.max_total_attempts
+    }
+
+    /// Sets the maximum number of redirects to follow. A response with a redirect status and a
+    /// `Location` header is followed by sending the request again to that location, and past the
+    /// limit the request fails with `Error::TooManyRedirects`. The `Authorization` header is not
+    /// sent to a host other than the one of the original request. Defaults to 0, which returns
+    /// redirect responses as they are. Requests with a streaming body are never redirected.
+    pub fn set_max_redirects(&mut self, max_redirects: u32) {
+        self.max_redirects = max_redirects;
+    }
+
+    /// Retrieves the maximum number of redirects to follow.
+    pub fn max_redirects(&self) -> u32 {
+        self.max_redirects
+    }
+
+    /// Marks the request as cacheable by a `ResponseCache`, which treats a cached response as fresh
+    /// for `ttl`. Requests are not cacheable by default, and only GET requests are ever cached.
+    /// Use `None` to make the request uncacheable.
+    pub fn set_cacheable(&mut self, ttl: Option<Duration>) {
+        self.cache_ttl = ttl;
+    }
+
+    /// Returns the time a cached response to the request is fresh, if the request is cacheable.
+    pub fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl
+    }
+
+    /// Sets whether a connection-level failure of the request counts towards rotating the API
+    /// address that subsequent requests use. This takes precedence over the default of the
+    /// `RequestFactory` that built the request. Disable it for requests that target a specific
+    /// address by design, such as diagnostic probes.
+    pub fn set_rotate_on_failure(&mut self, rotate_on_failure: bool) {
+        self.rotate_on_failure = rotate_on_failure;
+    }
+
+    /// Returns whether a connection-level failure of the request counts towards rotating the API
+    /// address.
+    pub fn rotate_on_failure(&self) -> bool {
+        self.rotate_on_failure
+    }
+
+    /// Sets whether the body of the response is checked against the `Digest` header of the
+    /// response, if it has one, when it's read by `deserialize_body` or `get_bytes`. A body that
+    /// doesn't match fails with `Error::IntegrityMismatch`. Disabled by default.
+    pub fn set_verify_digest(&mut self, verify_digest: bool) {
+        self.verify_digest = verify_digest;
+    }
+
+    /// Returns whether the body of the response is checked against its `Digest` header.
+    pub fn verify_digest(&self) -> bool {
+        self.verify_digest
+    }
+
+    /// Sets the maximum size of the response body in bytes when it's read by `deserialize_body`
+    /// or `get_bytes`. Larger bodies fail with `Error::ResponseTooLarge`. Defaults to 10 MiB, or
+    /// the maximum body size of the `RequestFactory` that built the request.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Returns the maximum size of the response body in bytes.
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// Enables deduplication of the request. If an identical request submitted to the same
+    /// `RequestService` is still in flight, or succeeded within `window` before this one was
+    /// submitted, this request is not sent and fails with `Error::DuplicateSuppressed`. Requests
+    /// that fail don't suppress anything, so that they can be resubmitted. This guards against
+    /// e.g. double-submitted forms creating duplicate resources on endpoints that don't support
+    /// idempotency keys. Requests are identical if their methods, hosts, paths, queries and
+    /// SHA-256 digests of their `Authorization` headers and bodies match. Requests with streaming
+    /// bodies are never deduplicated, since their bodies can't be hashed before they are sent.
+    /// Disabled by default.
+    pub fn set_dedup_window(&mut self, window: Option<Duration>) {
+        self.dedup_window = window;
+    }
+
+    /// Returns the deduplication window of the request, if it's deduplicated.
+    pub fn dedup_window(&self) -> Option<Duration> {
+        self.dedup_window
+    }
+
+    /// Returns the key that identifies identical requests for deduplication, if the body is
+    /// buffered.
+    fn dedup_key(&self) -> Option<DedupKey> {
+        let body = self.body.as_ref()?;
+        let uri = self.request.uri();
+        let host = match self.request.headers().get(header::HOST) {
+            Some(host) => String::from_utf8_lossy(host.as_bytes()).into_owned(),
+            None => uri
+                .authority()
+                .map(|authority| authority.to_string())
+                .unwrap_or_default(),
+        };
+        let digest = |data: &[u8]| {
+            ring::digest::digest(&ring::digest::SHA256, data)
+                .as_ref()
+                .to_vec()
+        };
+        Some(DedupKey {
+            method: self.request.method().to_string(),
+            host,
+            path: uri
+                .path_and_query()
+                .map(|path| path.as_str().to_owned())
+                .unwrap_or_default(),
+            auth_digest: self.authorization().map(|auth| digest(auth.as_bytes())),
+            body_digest: digest(body),
+        })
+    }
+
+    /// Sets the priority of the request. Defaults to `RequestPriority::Normal`. See
+    /// `RequestServiceBuilder::max_concurrent` for how high priority requests relate to the
+    /// concurrency limit.
+    pub fn set_priority(&mut self, priority: RequestPriority) {
+        self.priority = priority;
+    }
+
+    /// Returns the priority of the request.
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    /// Sets a buffered body, and a matching `Content-Length` header. The body can be resent if the
+    /// request has to be sent again.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.request
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        let body = hyper::body::Bytes::from(body);
+        *self.request.body_mut() = body.clone().into();
+        self.body = Some(body);
+        self.replayable = true;
+    }
+
+    /// Sets a body that is streamed as it is sent, such as a large upload. Since a stream can't be
+    /// rewound, such a request is never sent again, and the first error is returned instead. In
+    /// particular, it is neither sent to the fallback addresses of a preferred address nor retried
+    /// according to a `RetryPolicy`.
+    pub fn set_streaming_body(&mut self, body: hyper::Body) {
+        self.request.headers_mut().remove(header::CONTENT_LENGTH);
+        *self.request.body_mut() = body;
+        self.body = None;
+        self.replayable = false;
+    }
+
+    /// Returns whether the request can be sent again if an attempt fails. This is only false for
+    /// requests with a streaming body.
+    pub fn is_replayable(&self) -> bool {
+        self.replayable
+    }
+
+    /// Sends the request to `address` instead of the address in its URI. If a connection to
+    /// `address` can't be established, the request is sent to the API addresses selected by
+    /// `fallback` instead, unless the request has a streaming body. The body of such a request is
+    /// buffered so that it can be resent.
+    pub fn set_preferred_address(&mut self, address: SocketAddr, fallback: FallbackMode) {
+        self.preferred_address = Some((address, fallback));
+    }
+
+    /// Returns the preferred address of the request and its fallback mode, if any.
+    pub fn preferred_address(&self) -> Option<(SocketAddr, FallbackMode)> {
+        self.preferred_address
+    }
+
+    /// Returns the value of the `Authorization` header that will be sent, if any.
+    pub(crate) fn authorization(&self) -> Option<HeaderValue> {
+        self.auth
+            .header()
+            .or_else(|| self.custom_headers.get(header::AUTHORIZATION).cloned())
+            .or_else(|| self.request.headers().get(header::AUTHORIZATION).cloned())
+    }
+
+    /// Returns a copy of a GET request, or `None` for requests with other methods, whose bodies
+    /// can't be copied.
+    pub(crate) fn clone_get(&self) -> Option<RestRequest> {
+        if self.request.method() != Method::GET {
+            return None;
+        }
+        Some(self.copy_with_body(hyper::body::Bytes::new()))
+    }
+
+    /// Reads the whole body into memory and returns it, leaving the request with an empty body.
+    async fn take_body(&mut self) -> Result<hyper::body::Bytes> {
+        let body = std::mem::replace(self.request.body_mut(), hyper::Body::empty());
+        let body = match self.body.replace(hyper::body::Bytes::new()) {
+            Some(buffered) => buffered,
+            None => hyper::body::to_bytes(body).await?,
+        };
+        Ok(body)
+    }
+
+    /// Returns a copy of the request, or `None` if its body isn't buffered in memory. Bodies set
+    /// by `set_body` and by the `RequestFactory`, as well as empty bodies, are buffered, so that
+    /// every copy sends the same bytes. Streaming bodies and non-empty bodies of requests
+    /// converted from a `hyper::Request` are not, and can only be sent once.
+    pub fn try_clone(&self) -> Option<RestRequest> {
+        let body = self.body.clone()?;
+        Some(self.copy_with_body(body))
+    }
+
+    /// Returns a copy of the request with `body` as its body.
+    fn copy_with_body(&self, body: hyper::body::Bytes) -> RestRequest {
+        let mut request = Request::new(hyper::Body::from(body.clone()));
+        *request.method_mut() = self.request.method().clone();
+        *request.uri_mut() = self.request.uri().clone();
+        *request.version_mut() = self.request.version();
+        *request.headers_mut() = self.request.headers().clone();
+        RestRequest {
+            request,
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            body_idle_timeout: self.body_idle_timeout,
+            auth: self.auth.clone(),
+            max_total_attempts: self.max_total_attempts,
+            max_redirects: self.max_redirects,
+            cache_ttl: self.cache_ttl,
+            preferred_address: self.preferred_address,
+            replayable: true,
+            rotate_on_failure: self.rotate_on_failure,
+            custom_headers: self.custom_headers.clone(),
+            verify_digest: self.verify_digest,
+            max_body_size: self.max_body_size,
+            priority: self.priority,
+            dedup_window: self.dedup_window,
+            body: Some(body),
+        }
+    }
+
+    /// Points the request at the `location` of a redirect response with the given status. A
+    /// location on another host is requested without auth, and a `303 See Other` is followed
+    /// with a GET request. The caller is responsible for dropping the body in that case.
+    fn redirect(&mut self, status: StatusCode, location: &HeaderValue) -> Result<()> {
+        let invalid_location = || Error::InvalidRequest("Invalid redirect location".to_owned());
+        let location = Uri::from_str(location.to_str().map_err(|_| invalid_location())?)
+            .map_err(|_| invalid_location())?;
+        let host = self
+            .request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.to_owned());
+        let is_same_host = match location.authority() {
+            None => true,
+            Some(authority) => host.as_deref() == Some(authority.as_str()),
+        };
+
+        let uri = if is_same_host {
+            let path_and_query = location.path_and_query().ok_or_else(invalid_location)?;
+            if !path_and_query.as_str().starts_with('/') {
+                return Err(invalid_location());
+            }
+            let mut parts = self.request.uri().clone().into_parts();
+            parts.path_and_query = Some(path_and_query.clone());
+            Uri::from_parts(parts).map_err(|_| invalid_location())?
+        } else {
+            if location.scheme().is_none() {
+                return Err(invalid_location());
+            }
+            let host = host_header(&location).ok_or_else(invalid_location)?;
+            let headers = self.request.headers_mut();
+            headers.insert(header::HOST, HeaderValue::from_str(&host)?);
+            headers.remove(header::AUTHORIZATION);
+            self.custom_headers.remove(header::AUTHORIZATION);
+            self.auth = RequestAuth::Fixed(None);
+            // The new host is not an API address
+            self.preferred_address = None;
+            self.rotate_on_failure = false;
+            location
+        };
+        *self.request.uri_mut() = uri;
+
+        if status == StatusCode::SEE_OTHER && self.request.method() != Method::HEAD {
+            *self.request.method_mut() = Method::GET;
+            let headers = self.request.headers_mut();
+            headers.remove(header::CONTENT_TYPE);
+            headers.remove(header::CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+
+    /// Converts into a `hyper::Request<hyper::Body>`
+    fn into_request(self) -> Request {
+        let Self {
+            mut request,
+            auth,
+            custom_headers,
+            ..
+        } = self;
+        let headers = request.headers_mut();
+        for (name, value) in custom_headers {
+            if let Some(name) = name {
+                headers.insert(name, value);
+            }
+        }
+        if let Some(auth) = auth.header() {
+            request.headers_mut().insert(header::AUTHORIZATION, auth);
+        }
+        request
+    }
+
+    /// Returns the URI of the request
+    pub fn uri(&self) -> &Uri {
+        self.request.uri()
+    }
+
+    /// Returns the method of the request
+    pub fn method(&self) -> &Method {
+        self.request.method()
+    }
+}
+
+impl From<Request> for RestRequest {
+    fn from(request: Request) -> Self {
+        // Only an empty body is known to be buffered, since reading the body requires polling it
+        let body = match hyper::body::HttpBody::size_hint(request.body()).exact() {
+            Some(0) => Some(hyper::body::Bytes::new()),
+            _ => None,
+        };
+        Self {
+            request,
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: None,
+            body_idle_timeout: None,
+            auth: RequestAuth::Fixed(None),
+            max_total_attempts: DEFAULT_MAX_TOTAL_ATTEMPTS,
+            max_redirects: 0,
+            cache_ttl: None,
+            preferred_address: None,
+            replayable: true,
+            rotate_on_failure: true,
+            custom_headers: HeaderMap::new(),
+            verify_digest: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            priority: RequestPriority::Normal,
+            dedup_window: None,
+            body,
+        }
+    }
+}
+
+/// Decides how failed requests are retried. Requests with a streaming body, set by
+/// `RestRequest::set_streaming_body`, are never retried regardless of the policy, since the part of
+/// the stream consumed by a failed attempt can't be sent again. Their first error is returned.
+///
+/// Between attempts, the policy waits for an exponentially increasing delay. The delay is
+/// jittered to a random duration between half of it and all of it, so that clients that fail at
+/// the same time don't retry at the same time.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one. A value of 0 is treated as 1.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Upper bound on the delay between two attempts.
+    pub max_delay: Duration,
+    /// Factor by which the delay before each retry is multiplied, compared to the previous retry.
+    pub backoff_multiplier: f64,
+    /// Factor by which the timeout of each attempt is multiplied, compared to the previous
+    /// attempt. With `1.0`, every attempt gets the timeout of the request.
+    pub timeout_multiplier: f64,
+    /// Upper bound on the timeout of a single attempt.
+    pub max_attempt_timeout: Option<Duration>,
+    /// Time after the start of the first attempt after which no more attempts are made. The
+    /// timeout of an attempt is clamped so that it doesn't run past the deadline.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            backoff_multiplier: 2.0,
+            timeout_multiplier: 1.0,
+            max_attempt_timeout: None,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the retry with the zero-based index `retry`, before jitter is
+    /// applied.
+    pub fn backoff_delay(&self, retry: u32) -> Duration {
+        let multiplier = self
+            .backoff_multiplier
+            .max(0.0)
+            .powi(retry.min(i32::MAX as u32) as i32);
+        // Clamped to avoid overflowing the duration when the multiplier is large
+        let delay = Duration::from_secs_f64(
+            (self.base_delay.as_secs_f64() * multiplier).min(u32::MAX as f64),
+        );
+        delay.min(self.max_delay)
+    }
+
+    /// Returns a random delay between half of `backoff_delay(retry)` and all of it.
+    fn jittered_delay(&self, retry: u32) -> Duration {
+        self.backoff_delay(retry)
+            .mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+    }
+
+    /// Returns the timeout of the attempt with the zero-based index `attempt`, for a request with
+    /// the timeout `request_timeout`, when `elapsed` has passed since the first attempt started.
+    /// Returns `None` if the deadline has been reached.
+    pub fn attempt_timeout(
+        &self,
+        request_timeout: Duration,
+        attempt: u32,
+        elapsed: Duration,
+    ) -> Option<Duration> {
+        let multiplier = self
+            .timeout_multiplier
+            .max(0.0)
+            .powi(attempt.min(i32::MAX as u32) as i32);
+        // Clamped to avoid overflowing the duration when the multiplier is large
+        let mut timeout = Duration::from_secs_f64(
+            (request_timeout.as_secs_f64() * multiplier).min(u32::MAX as f64),
+        );
+        if let Some(max_attempt_timeout) = self.max_attempt_timeout {
+            timeout = timeout.min(max_attempt_timeout);
+        }
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.checked_sub(elapsed)?;
+            if remaining == Duration::from_secs(0) {
+                return None;
+            }
+            timeout = timeout.min(remaining);
+        }
+        Some(timeout)
+    }
+}
+
+/// Selects the API addresses that a request with a preferred address is sent to, when a
+/// connection to the preferred address can't be established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackMode {
+    /// Fall back on the next address of the address cache only.
+    Single,
+    /// Fall back on every address of the address cache in turn, until a connection succeeds.
+    Rotate,
+}
+
+/// The priority of a request, relative to other requests of the same `RequestService`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestPriority {
+    /// The request is queued behind other requests when the service is at its concurrency limit.
+    Normal,
+    /// The request is queued ahead of normal requests, and may be started even though the service
+    /// is at its concurrency limit. Reserved for requests that the service needs to recover, such
+    /// as fetching API addresses when all known addresses are failing.
+    High,
+}
+
+/// A response extension containing the address of the server that served the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServedBy(pub SocketAddr);
+
+/// A W3C Trace Context, as sent in the `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    parent_id: u64,
+    flags: u8,
+}
+
+impl TraceContext {
+    /// Creates a trace context for the span `parent_id` in the trace `trace_id`. Fails with
+    /// `Error::InvalidTraceContext` if either ID is zero, since such IDs are invalid.
+    pub fn new(trace_id: u128, parent_id: u64, sampled: bool) -> Result<Self> {
+        if trace_id == 0 {
+            return Err(Error::InvalidTraceContext("trace ID is zero".to_owned()));
+        }
+        if parent_id == 0 {
+            return Err(Error::InvalidTraceContext("parent ID is zero".to_owned()));
+        }
+        Ok(Self {
+            trace_id,
+            parent_id,
+            flags: sampled as u8,
+        })
+    }
+
+    pub fn trace_id(&self) -> u128 {
+        self.trace_id
+    }
+
+    pub fn parent_id(&self) -> u64 {
+        self.parent_id
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    /// Parses a `traceparent` header value of version `00`.
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || Error::InvalidTraceContext(value.to_owned());
+        let parse_hex = |field: &str, length: usize| {
+            let is_lower_hex = field
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+            if field.len() != length || !is_lower_hex {
+                return Err(invalid());
+            }
+            u128::from_str_radix(field, 16).map_err(|_| invalid())
+        };
+
+        let fields: Vec<&str> = value.split('-').collect();
+        if fields.len() != 4 || fields[0] != "00" {
+            return Err(invalid());
+        }
+        let trace_id = parse_hex(fields[1], 32)?;
+        let parent_id = parse_hex(fields[2], 16)? as u64;
+        let flags = parse_hex(fields[3], 2)? as u8;
+
+        let mut context = Self::new(trace_id, parent_id, false)?;
+        context.flags = flags;
+        Ok(context)
+    }
+}
+
+/// Idle timeout for reading the body of a response, stored in the response's extensions.
+#[derive(Clone, Copy)]
+struct BodyIdleTimeout(Duration);
+
+/// Limit on the size of the body of a response when it's read into memory, stored in the
+/// response's extensions.
+#[derive(Clone, Copy)]
+struct MaxBodySize(usize);
+
+/// Marks a response whose body is checked against its `Digest` header, stored in the response's
+/// extensions.
+#[derive(Clone, Copy)]
+struct VerifyDigest;
+
+/// Returns the `Digest` header of `response` if its body should be checked against it.
+fn digest_to_verify(response: &Response) -> Option<HeaderValue> {
+    response.extensions().get::<VerifyDigest>()?;
+    response.headers().get(DIGEST_HEADER).cloned()
+}
+
+/// Checks `body` against the `Digest` header value `digest`, as defined by RFC 3230. Every
+/// supported algorithm in the header must match. Algorithms other than SHA-256 and SHA-512 are
+/// ignored, so a header with only unsupported algorithms is accepted.
+fn verify_digest(digest: &HeaderValue, body: &[u8]) -> Result<()> {
+    let mut verifier = DigestVerifier::new(digest)?;
+    verifier.update(body);
+    verifier.finish()
+}
+
+/// Checks a body that is read in chunks against a `Digest` header.
+struct DigestVerifier {
+    /// The name of the algorithm, the expected digest, and the digest of the body read so far,
+    /// for every supported algorithm in the header.
+    digests: Vec<(String, Option<Vec<u8>>, ring::digest::Context)>,
+}
+
+impl DigestVerifier {
+    fn new(digest: &HeaderValue) -> Result<Self> {
+        let digest = digest
+            .to_str()
+            .map_err(|_| Error::IntegrityMismatch("unknown".to_owned()))?;
+        let mut digests = vec![];
+        for instance in digest.split(',') {
+            let mut parts = instance.trim().splitn(2, '=');
+            let algorithm_name = parts.next().unwrap_or("").trim();
+            let algorithm = match algorithm_name.to_ascii_lowercase().as_str() {
+                "sha-256" => &ring::digest::SHA256,
+                "sha-512" => &ring::digest::SHA512,
+                _ => {
+                    log::debug!("Ignoring unsupported digest algorithm {}", algorithm_name);
+                    continue;
+                }
+            };
+            let expected = base64::decode(parts.next().unwrap_or("").trim()).ok();
+            digests.push((
+                algorithm_name.to_owned(),
+                expected,
+                ring::digest::Context::new(algorithm),
+            ));
+        }
+        Ok(Self { digests })
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        for (_, _, context) in &mut self.digests {
+            context.update(chunk);
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        for (algorithm_name, expected, context) in self.digests {
+            if expected.as_deref() != Some(context.finish().as_ref()) {
+                return Err(Error::IntegrityMismatch(algorithm_name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next chunk of the response body, honoring the body idle timeout of the request.
+async fn next_body_chunk(response: &mut Response) -> Option<Result<hyper::body::Bytes>> {
+    let chunk = match response.extensions().get::<BodyIdleTimeout>() {
+        Some(&BodyIdleTimeout(timeout)) => {
+            match tokio::time::timeout(timeout, response.body_mut().next()).await {
+                Ok(chunk) => chunk,
+                Err(elapsed) => return Some(Err(Error::TimeoutError(elapsed))),
+            }
+        }
+        None => response.body_mut().next().await,
+    };
+    chunk.map(|chunk| chunk.map_err(Error::from))
+}
+
+fn auth_header(scheme: AuthScheme, auth: &AccountToken) -> Result<HeaderValue> {
+    HeaderValue::from_str(&format!("{} {}", scheme, auth.as_str()))
+        .map_err(Error::InvalidHeaderError)
+}
+
+#[derive(serde::Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    /// A human-readable description of the error, if the API included one.
+    #[serde(default, alias = "message")]
+    pub detail: Option<String>,
+}
+
+/// The error codes returned by the API in `ErrorResponse::code` that callers may want to handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiErrorCode {
+    InvalidAccount,
+    TooManyDevices,
+    KeyLimitReached,
+    InvalidVoucher,
+    VoucherUsed,
+    /// A code without a variant of its own.
+    Unknown(String),
+}
+
+impl ApiErrorCode {
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            crate::INVALID_ACCOUNT => ApiErrorCode::InvalidAccount,
+            crate::TOO_MANY_DEVICES => ApiErrorCode::TooManyDevices,
+            crate::KEY_LIMIT_REACHED => ApiErrorCode::KeyLimitReached,
+            crate::INVALID_VOUCHER => ApiErrorCode::InvalidVoucher,
+            crate::VOUCHER_USED => ApiErrorCode::VoucherUsed,
+            code => ApiErrorCode::Unknown(code.to_owned()),
+        }
+    }
+
+    /// Returns the code as returned by the API.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ApiErrorCode::InvalidAccount => crate::INVALID_ACCOUNT,
+            ApiErrorCode::TooManyDevices => crate::TOO_MANY_DEVICES,
+            ApiErrorCode::KeyLimitReached => crate::KEY_LIMIT_REACHED,
+            ApiErrorCode::InvalidVoucher => crate::INVALID_VOUCHER,
+            ApiErrorCode::VoucherUsed => crate::VOUCHER_USED,
+            ApiErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ApiErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestFactory {
+    hostname: String,
+    address_provider: Box<dyn AddressProvider>,
+    path_prefix: Option<String>,
+    pub timeout: Duration,
+    default_auth: Arc<Mutex<Option<AccountToken>>>,
+    default_headers: HeaderMap,
+    rotate_on_failure: bool,
+    max_body_size: usize,
+    max_redirects: u32,
+}
+
+/// Headers that are managed by the request factory and can't be set as default headers.
+const RESERVED_HEADERS: &[HeaderName] = &[header::HOST, header::CONTENT_LENGTH];
+
+impl RequestFactory {
+    pub fn new(
+        hostname: String,
+        address_provider: Box<dyn AddressProvider>,
+        path_prefix: Option<String>,
+    ) -> Self {
+        Self {
+            hostname,
+            address_provider,
+            path_prefix,
+            timeout: DEFAULT_TIMEOUT,
+            default_auth: Arc::new(Mutex::new(None)),
+            default_headers: HeaderMap::new(),
+            rotate_on_failure: true,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_redirects: 0,
+        }
+    }
+
+    /// Like `new`, but the requests built by the factory time out after `timeout` instead of
+    /// `DEFAULT_TIMEOUT`, e.g. for slow links. Individual requests can still override it with
+    /// `RestRequest::set_timeout`.
+    pub fn with_timeout(
+        hostname: String,
+        address_provider: Box<dyn AddressProvider>,
+        path_prefix: Option<String>,
+        timeout: Duration,
+    ) -> Self {
+        let mut factory = Self::new(hostname, address_provider, path_prefix);
+        factory.timeout = timeout;
+        factory
+    }
+
+    /// Like `new`, but adds `headers` to every request built by the factory. Headers that are set
+    /// on an individual request replace the defaults. Fails with `Error::ReservedHeader` if
+    /// `headers` contains a header that the factory sets by itself, such as `Host`.
+    pub fn with_headers(
+        hostname: String,
+        address_provider: Box<dyn AddressProvider>,
+        path_prefix: Option<String>,
+        headers: HeaderMap,
+    ) -> Result<Self> {
+        if let Some(reserved) = RESERVED_HEADERS
+            .iter()
+            .find(|name| headers.contains_key(*name))
+        {
+            return Err(Error::ReservedHeader(reserved.clone()));
+        }
+
+        let mut factory = Self::new(hostname, address_provider, path_prefix);
+        factory.default_headers = headers;
+        Ok(factory)
+    }
+
+    /// Sets the account token that is used as auth for all requests built by this factory and its
+    /// clones that haven't been dispatched yet. Requests that are already in flight keep the token
+    /// they were sent with. Individual requests can still override or clear it with
+    /// `RestRequest::set_auth`, and helpers such as `send_request` only override it when they are
+    /// given an auth token.
+    /// Fails with `Error::InvalidAccountToken` if the token is not valid.
+    pub fn set_default_auth(&self, auth: Option<String>) -> Result<()> {
+        *self.default_auth.lock().unwrap() = auth.map(AccountToken::new).transpose()?;
+        Ok(())
+    }
+
+    /// Sets whether connection-level failures of requests built by this factory count towards
+    /// rotating the API address, which is the default. Individual requests can override this with
+    /// `RestRequest::set_rotate_on_failure`.
+    pub fn set_rotate_on_failure(&mut self, rotate_on_failure: bool) {
+        self.rotate_on_failure = rotate_on_failure;
+    }
+
+    /// Sets the maximum size of the response bodies of requests built by this factory, which
+    /// defaults to 10 MiB. See `RestRequest::set_max_body_size`.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Sets the maximum number of redirects that requests built by this factory follow, which
+    /// defaults to 0. See `RestRequest::set_max_redirects`.
+    pub fn set_max_redirects(&mut self, max_redirects: u32) {
+        self.max_redirects = max_redirects;
+    }
+
+    /// Returns the account token that is used as auth by default, if any.
+    pub fn default_auth(&self) -> Option<String> {
+        self.default_auth
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|auth| auth.as_str().to_owned())
+    }
+
+    pub fn request(&self, path: &str, method: Method) -> Result<RestRequest> {
+        self.hyper_request(path, method)
+            .map(|req| self.rest_request(req))
+    }
+
+    pub fn get(&self, path: &str) -> Result<RestRequest> {
+        self.hyper_request(path, Method::GET)
+            .map(|req| self.rest_request(req))
+    }
+
+    /// Like `get`, but appends `params` to the URI as a percent-encoded query. Repeated keys are
+    /// kept in the given order.
+    pub fn get_with_query(&self, path: &str, params: &[(&str, &str)]) -> Result<RestRequest> {
+        self.request_with_query(path, Method::GET, params)
+    }
+
+    /// Like `request`, but appends `params` to the URI as a percent-encoded query. Repeated keys
+    /// are kept in the given order.
+    pub fn request_with_query(
+        &self,
+        path: &str,
+        method: Method,
+        params: &[(&str, &str)],
+    ) -> Result<RestRequest> {
+        self.request(&path_with_query(path, params), method)
+    }
+
+    pub fn post(&self, path: &str) -> Result<RestRequest> {
+        self.hyper_request(path, Method::POST)
+            .map(|req| self.rest_request(req))
+    }
+
+    pub fn post_json<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<RestRequest> {
+        self.json_request(path, Method::POST, body)
+    }
+
+    pub fn put(&self, path: &str) -> Result<RestRequest> {
+        self.hyper_request(path, Method::PUT)
+            .map(|req| self.rest_request(req))
+    }
+
+    pub fn put_json<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<RestRequest> {
+        self.json_request(path, Method::PUT, body)
+    }
+
+    pub fn patch(&self, path: &str) -> Result<RestRequest> {
+        self.hyper_request(path, Method::PATCH)
+            .map(|req| self.rest_request(req))
+    }
+
+    pub fn patch_json<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<RestRequest> {
+        self.json_request(path, Method::PATCH, body)
+    }
+
+    fn json_request<S: serde::Serialize>(
+        &self,
+        path: &str,
+        method: Method,
+        body: &S,
+    ) -> Result<RestRequest> {
+        let mut request = self.hyper_request(path, method)?;
+        request.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let mut request = self.rest_request(request);
+        request.set_body(serde_json::to_vec(&body)?);
+        Ok(request)
+    }
+
+    pub fn delete(&self, path: &str) -> Result<RestRequest> {
+        self.hyper_request(path, Method::DELETE)
+            .map(|req| self.rest_request(req))
+    }
+
+    fn hyper_request(&self, path: &str, method: Method) -> Result<Request> {
+        let uri = self.get_uri(path)?;
+        let request = http::request::Builder::new()
+            .method(method)
+            .uri(uri)
+            .header(header::ACCEPT, HeaderValue::from_static("application/json"))
+            .header(header::HOST, self.hostname.clone());
+
+        let mut request = request
+            .body(hyper::Body::empty())
+            .map_err(Error::HttpError)?;
+        let headers = request.headers_mut();
+        for name in self.default_headers.keys() {
+            headers.remove(name);
+        }
+        for (name, value) in &self.default_headers {
+            headers.append(name, value.clone());
+        }
+        Ok(request)
+    }
+
+    fn get_uri(&self, path: &str) -> Result<Uri> {
+        let host = self.address_provider.get_address();
+        let prefix = self.path_prefix.as_ref().map(AsRef::as_ref).unwrap_or("");
+        let uri = format!(
+            "https://{}{}",
+            uri_authority(&host),
+            join_path(prefix, path)?
+        );
+        hyper::Uri::from_str(&uri).map_err(Error::UriError)
+    }
+
+    /// Wraps `request` with the defaults of the factory.
+    fn rest_request(&self, request: Request) -> RestRequest {
+        let mut request = RestRequest::from(request);
+        request.auth = RequestAuth::Default(self.default_auth.clone());
+        request.timeout = self.timeout;
+        request.rotate_on_failure = self.rotate_on_failure;
+        request.max_body_size = self.max_body_size;
+        request.max_redirects = self.max_redirects;
+        request
+    }
+}
+
+/// Returns `address`, which is a host or a host with port as returned by an `AddressProvider`, in
+/// the form used as the authority of a URI. Bare IPv6 addresses are bracketed, everything else is
+/// already valid and returned as is.
+fn uri_authority(address: &str) -> String {
+    match address.parse::<Ipv6Addr>() {
+        Ok(address) => format!("[{}]", address),
+        Err(_) => address.to_owned(),
+    }
+}
+
+/// Joins a path prefix and a path into an absolute path, with exactly one slash between the
+/// segments no matter whether the prefix ends or the path starts with one. A trailing slash on
+/// `path` is kept. Fails with `Error::InvalidRequest` if either part contains a scheme or a host,
+/// since the factory always sends requests to the API host.
+fn join_path(prefix: &str, path: &str) -> Result<String> {
+    for part in &[prefix, path] {
+        if part.contains("://") || part.starts_with("//") {
+            return Err(Error::InvalidRequest(format!(
+                "Path must not contain a scheme or host: {}",
+                part
+            )));
+        }
+    }
+
+    let prefix = prefix.trim_matches('/');
+    let path = path.trim_start_matches('/');
+    if prefix.is_empty() {
+        Ok(format!("/{}", path))
+    } else {
+        Ok(format!("/{}/{}", prefix, path))
+    }
+}
+
+/// Appends `params` to `path` as a query component, percent-encoding keys and values.
+fn path_with_query(path: &str, params: &[(&str, &str)]) -> String {
+    if params.is_empty() {
+        return path.to_owned();
+    }
+    let query = params
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                urlencoding::encode(key),
+                urlencoding::encode(value)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if path.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", path, separator, query)
+}
+
+pub trait AddressProvider: Send + Sync {
+    /// Must return a string that represents either a host or a host with port
+    fn get_address(&self) -> String;
+    fn clone_box(&self) -> Box<dyn AddressProvider>;
+
+    /// Returns whether every address of the provider is known to have failed, in which case a
+    /// `ChainedAddressProvider` moves on to the next provider. Providers that don't track failures
+    /// are never exhausted.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+impl Clone for Box<dyn AddressProvider> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl AddressProvider for IpAddr {
+    /// Must return a string that represents either a host or a host with port
+    fn get_address(&self) -> String {
+        self.to_string()
+    }
+
+    fn clone_box(&self) -> Box<dyn AddressProvider> {
+        Box::new(*self)
+    }
+}
+
+/// An address provider that consults a list of providers in order, and uses the first one that
+/// isn't exhausted. If every provider is exhausted, the last one is used anyway.
+#[derive(Clone)]
+pub struct ChainedAddressProvider {
+    providers: Vec<Box<dyn AddressProvider>>,
+}
+
+impl ChainedAddressProvider {
+    /// Creates a provider that prefers the providers in the order given. Fails with
+    /// `Error::InvalidRequest` if `providers` is empty.
+    pub fn new(providers: Vec<Box<dyn AddressProvider>>) -> Result<Self> {
+        if providers.is_empty() {
+            return Err(Error::InvalidRequest(
+                "An address provider chain needs at least one provider".to_owned(),
+            ));
+        }
+        Ok(Self { providers })
+    }
+
+    fn current_provider(&self) -> &dyn AddressProvider {
+        let last = &self.providers[self.providers.len() - 1];
+        self.providers
+            .iter()
+            .find(|provider| !provider.is_exhausted())
+            .unwrap_or(last)
+            .as_ref()
+    }
+}
+
+impl AddressProvider for ChainedAddressProvider {
+    fn get_address(&self) -> String {
+        self.current_provider().get_address()
+    }
+
+    fn clone_box(&self) -> Box<dyn AddressProvider> {
+        Box::new(self.clone())
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.providers
+            .iter()
+            .all(|provider| provider.is_exhausted())
+    }
+}
+
+/// An address provider that rotates through several addresses, returning the next one on every
+/// call to `get_address`, so that repeated attempts of a request are spread over different IPs.
+/// Clones share the rotation.
+#[derive(Clone)]
+pub struct MultiAddressProvider {
+    addresses: Vec<SocketAddr>,
+    next: Arc<AtomicUsize>,
+    failure_feedback: Option<AddressCache>,
+}
+
+impl MultiAddressProvider {
+    /// Creates a provider that rotates through `addresses` in the order given. Fails with
+    /// `Error::InvalidRequest` if `addresses` is empty.
+    pub fn new(addresses: Vec<SocketAddr>) -> Result<Self> {
+        if addresses.is_empty() {
+            return Err(Error::InvalidRequest(
+                "A multi-address provider needs at least one address".to_owned(),
+            ));
+        }
+        Ok(Self {
+            addresses,
+            next: Arc::new(AtomicUsize::new(0)),
+            failure_feedback: None,
+        })
+    }
+
+    /// Skips addresses that `address_cache` has recently seen fail. Failures and successes are
+    /// registered with the address cache of the `RequestService` that sends the requests, so it
+    /// should be the same cache. If every address has failed, they're rotated through anyway.
+    pub fn skip_failed(mut self, address_cache: AddressCache) -> Self {
+        self.failure_feedback = Some(address_cache);
+        self
+    }
+
+    fn next_address(&self) -> SocketAddr {
+        let next = self.next.fetch_add(1, Ordering::SeqCst);
+        self.addresses[next % self.addresses.len()]
+    }
+
+    fn has_failed(&self, address: SocketAddr) -> bool {
+        self.failure_feedback
+            .as_ref()
+            .map(|cache| cache.has_recently_failed(address))
+            .unwrap_or(false)
+    }
+}
+
+impl AddressProvider for MultiAddressProvider {
+    fn get_address(&self) -> String {
+        // Failed addresses are skipped, unless every address has failed
+        let exhausted = self.is_exhausted();
+        let mut address = self.next_address();
+        for _ in 1..self.addresses.len() {
+            if exhausted || !self.has_failed(address) {
+                break;
+            }
+            address = self.next_address();
+        }
+        address.to_string()
+    }
+
+    fn clone_box(&self) -> Box<dyn AddressProvider> {
+        Box::new(self.clone())
+    }
+
+    /// The provider is exhausted if it skips failed addresses and every address has failed.
+    fn is_exhausted(&self) -> bool {
+        self.failure_feedback.is_some()
+            && self
+                .addresses
+                .iter()
+                .all(|address| self.has_failed(*address))
+    }
+}
+
+pub fn get_request<T: serde::de::DeserializeOwned>(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    auth: Option<String>,
+    expected_status: hyper::StatusCode,
+) -> impl Future<Output = Result<Response>> {
+    let request = factory.get(uri);
+    async move {
+        let mut request = request?;
+        if auth.is_some() {
+            request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+        }
+        let response = service.request(request).await?;
+        parse_rest_response(response, expected_status).await
+    }
+}
+
+pub fn send_request(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    method: Method,
+    auth: Option<String>,
+    expected_status: hyper::StatusCode,
+) -> impl Future<Output = Result<Response>> {
+    let request = factory.request(uri, method);
+
+    async move {
+        let mut request = request?;
+        if auth.is_some() {
+            request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+        }
+        let response = service.request(request).await?;
+        parse_rest_response(response, expected_status).await
+    }
+}
+
+pub fn post_request_with_json<B: serde::Serialize>(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    body: &B,
+    auth: Option<String>,
+    expected_status: hyper::StatusCode,
+) -> impl Future<Output = Result<Response>> {
+    let request = factory.post_json(uri, body);
+    async move {
+        let mut request = request?;
+        if auth.is_some() {
+            request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+        }
+        let response = service.request(request).await?;
+        parse_rest_response(response, expected_status).await
+    }
+}
+
+/// Like `post_request_with_json`, but asks the server not to return the created or updated
+/// resource. The response body is discarded.
+pub fn post_request_with_json_minimal<B: serde::Serialize>(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    body: &B,
+    auth: Option<String>,
+    expected_status: hyper::StatusCode,
+) -> impl Future<Output = Result<()>> {
+    let request = factory.post_json(uri, body);
+    async move {
+        let mut request = request?;
+        if auth.is_some() {
+            request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+        }
+        request.set_prefer_return_minimal();
+        let response = service.request(request).await?;
+        drain_body(parse_rest_response(response, expected_status).await?).await?;
+        Ok(())
+    }
+}
+
+/// Sends a DELETE request for each of `paths` concurrently, and returns the result for each path
+/// in the same order. A failed deletion does not prevent the others from being attempted. Any
+/// successful status code is treated as a successful deletion.
+pub fn delete_many(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    paths: &[&str],
+    auth: Option<String>,
+) -> impl Future<Output = Vec<(String, Result<()>)>> {
+    let deletions = paths.iter().map(|path| {
+        let request = factory.delete(path);
+        let service = service.clone();
+        let auth = auth.clone();
+        let path = path.to_string();
+
+        async move {
+            let result = async move {
+                let mut request = request?;
+                if auth.is_some() {
+                    request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+                }
+                let response = service.request(request).await?;
+                if !response.status().is_success() {
+                    return handle_error_response(response).await;
+                }
+                Ok(())
+            };
+            (path, result.await)
+        }
+    });
+
+    join_all(deletions)
+}
+
+/// Downloads the resource at `uri` and streams the response body into `writer`, calling
+/// `progress` with the cumulative number of bytes written after every chunk. Returns the total
+/// number of bytes written.
+///
+/// The request timeout bounds the time until the response headers are received, and is then
+/// used as the body idle timeout. If the download fails, or the returned future is
+/// dropped to cancel it, the bytes written so far are left in `writer` for the caller to keep or
+/// discard.
+pub fn download_to<W, P>(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    auth: Option<String>,
+    writer: W,
+    progress: P,
+) -> impl Future<Output = Result<u64>>
+where
+    W: AsyncWrite + Unpin,
+    P: FnMut(u64),
+{
+    resume_download_to(factory, service, uri, auth, 0, writer, progress)
+}
+
+/// Like `download_to`, but resumes an interrupted download by only requesting the bytes from
+/// `offset` onwards, which are appended to `writer`. The bytes before `offset` are included in
+/// the progress and in the returned total. Fails with `Error::ResumeUnsupported`, before
+/// anything is written, if the server doesn't respond with the requested range.
+pub fn resume_download_to<W, P>(
+    factory: &RequestFactory,
+    service: RequestServiceHandle,
+    uri: &str,
+    auth: Option<String>,
+    offset: u64,
+    mut writer: W,
+    mut progress: P,
+) -> impl Future<Output = Result<u64>>
+where
+    W: AsyncWrite + Unpin,
+    P: FnMut(u64),
+{
+    let request = factory.get(uri);
+    async move {
+        let mut request = request?;
+        if auth.is_some() {
+            request.set_auth(auth.map(AccountToken::new).transpose()?)?;
+        }
+        request.set_body_idle_timeout(Some(request.timeout()));
+        if offset > 0 {
+            request.request.headers_mut().insert(
+                header::RANGE,
+                HeaderValue::from_str(&format!("bytes={}-", offset))?,
+            );
+        }
+        let response = service.request(request).await?;
+        let mut response = if offset > 0 {
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT => {
+                    let range_start = response
+                        .headers()
+                        .get(header::CONTENT_RANGE)
+                        .and_then(content_range_start);
+                    if range_start != Some(offset) {
+                        return Err(Error::ResumeUnsupported(offset));
+                    }
+                    response
+                }
+                StatusCode::OK => return Err(Error::ResumeUnsupported(offset)),
+                _ => return handle_error_response(response).await,
+            }
+        } else {
+            parse_rest_response(response, StatusCode::OK).await?
+        };
+
+        let mut written = offset;
+        while let Some(chunk) = next_body_chunk(&mut response).await {
+            let chunk = chunk?;
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+            progress(written);
+        }
+        writer.flush().await?;
+
+        Ok(written)
+    }
+}
+
+/// Returns the first byte position of a `Content-Range` header value such as
+/// `bytes 100-199/200`.
+fn content_range_start(value: &HeaderValue) -> Option<u64> {
+    let range = value.to_str().ok()?.trim().strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+/// Reads the whole body of `response`. If the request asked for it with
+/// `RestRequest::set_verify_digest`, the body is checked against the `Digest` header of the
+/// response.
+pub async fn get_bytes(mut response: Response) -> Result<Vec<u8>> {
+    let digest = digest_to_verify(&response);
+    let mut body = Vec::new();
+    read_body(&mut response, &mut body).await?;
+    if let Some(digest) = digest {
+        verify_digest(&digest, &body)?;
+    }
+    Ok(body)
+}
+
+pub async fn deserialize_body<T: serde::de::DeserializeOwned>(response: Response) -> Result<T> {
+    deserialize_body_into(response, &mut Vec::new()).await
+}
+
+/// Like `deserialize_body`, but reads the body into `buffer` instead of a newly allocated buffer.
+/// The buffer is cleared and its contents overwritten. Reusing the same buffer for many responses,
+/// such as when polling, avoids allocating for every response once the buffer has grown to fit
+/// them. The caller owns the buffer and decides how long to keep it, and its capacity, around.
+pub async fn deserialize_body_into<T: serde::de::DeserializeOwned>(
+    mut response: Response,
+    buffer: &mut Vec<u8>,
+) -> Result<T> {
+    buffer.clear();
+    if response.status() == StatusCode::NO_CONTENT {
+        return serde_json::from_slice(b"null").map_err(Error::DeserializeError);
+    }
+
+    let digest = digest_to_verify(&response);
+    read_body(&mut response, buffer).await?;
+    if let Some(digest) = digest {
+        verify_digest(&digest, buffer)?;
+    }
+    // An empty body, which is always the case for `204 No Content`, is treated as `null`, so that
+    // it can be deserialized as `()` or `None`
+    if buffer.is_empty() {
+        buffer.extend(b"null");
+    }
+
+    serde_json::from_slice(buffer).map_err(Error::DeserializeError)
+}
+
+/// Like `deserialize_body`, but deserializes the body as it arrives instead of buffering it in
+/// full first, so that large bodies don't have to fit in memory twice. Fails with
+/// `Error::ResponseTooLarge` once the body exceeds the maximum body size of the request, like
+/// `deserialize_body`. Bodies without a `Content-Length` header, such as chunked ones, are
+/// supported. The body is still checked against the `Digest` header of the response if the
+/// request asked for it.
+pub async fn deserialize_body_streaming<T: serde::de::DeserializeOwned + Send + 'static>(
+    mut response: Response,
+) -> Result<T> {
+    if response.status() == StatusCode::NO_CONTENT {
+        return serde_json::from_slice(b"null").map_err(Error::DeserializeError);
+    }
+
+    let max_body_size = max_body_size(&response);
+    let mut verifier = match digest_to_verify(&response) {
+        Some(digest) => Some(DigestVerifier::new(&digest)?),
+        None => None,
+    };
+    let (mut chunk_tx, chunk_rx) = mpsc::channel(STREAMING_BODY_CHUNK_BUFFER);
+    // `serde_json` only reads synchronously, so the body is deserialized on a blocking thread
+    let deserializer = tokio::task::spawn_blocking(move || {
+        serde_json::from_reader::<_, T>(ChunkReader::new(chunk_rx))
+    });
+
+    let mut read = 0;
+    while let Some(chunk) = next_body_chunk(&mut response).await {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > max_body_size {
+            return Err(Error::ResponseTooLarge(max_body_size));
+        }
+        if let Some(verifier) = &mut verifier {
+            verifier.update(&chunk);
+        }
+        // The deserializer stops reading if the body is malformed
+        if chunk_tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(chunk_tx);
+
+    let value = match deserializer.await {
+        Ok(value) => value.map_err(Error::DeserializeError)?,
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    };
+    if let Some(verifier) = verifier {
+        verifier.finish()?;
+    }
+    Ok(value)
+}
+
+/// Reads the chunks of a body that are sent over a channel, blocking until they arrive. An empty
+/// body is read as `null`, like in `deserialize_body`.
+struct ChunkReader {
+    chunk_rx: futures::stream::Fuse<mpsc::Receiver<hyper::body::Bytes>>,
+    chunk: hyper::body::Bytes,
+    empty: bool,
+}
+
+impl ChunkReader {
+    fn new(chunk_rx: mpsc::Receiver<hyper::body::Bytes>) -> Self {
+        Self {
+            chunk_rx: chunk_rx.fuse(),
+            chunk: hyper::body::Bytes::new(),
+            empty: true,
+        }
+    }
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match futures::executor::block_on(self.chunk_rx.next()) {
+                Some(chunk) => self.chunk = chunk,
+                None if self.empty => {
+                    self.empty = false;
+                    self.chunk = hyper::body::Bytes::from_static(b"null");
+                }
+                None => return Ok(0),
+            }
+        }
+        self.empty = false;
+        let length = buf.len().min(self.chunk.len());
+        buf[..length].copy_from_slice(&self.chunk.split_to(length));
+        Ok(length)
+    }
+}
+
+/// Appends the body of `response` to `buffer`. Fails with `Error::ResponseTooLarge` once the body
+/// exceeds the `MaxBodySize` stored in the response's extensions, or `DEFAULT_MAX_BODY_SIZE`.
+///
+/// The body never extends past the `Content-Length` of the response, since hyper ends the body at
+/// the declared length. Any bytes sent after it are not part of the body.
+async fn read_body(response: &mut Response, buffer: &mut Vec<u8>) -> Result<()> {
+    let content_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    let max_body_size = max_body_size(response);
+
+    let start = buffer.len();
+    // The header is only a hint for the capacity, since the body may be shorter than declared
+    buffer.reserve(content_length.unwrap_or(0).min(max_body_size));
+    while let Some(chunk) = next_body_chunk(response).await {
+        buffer.extend(&chunk?);
+        if buffer.len() - start > max_body_size {
+            return Err(Error::ResponseTooLarge(max_body_size));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `MaxBodySize` stored in the extensions of `response`, or `DEFAULT_MAX_BODY_SIZE`.
+fn max_body_size(response: &Response) -> usize {
+    response
+        .extensions()
+        .get::<MaxBodySize>()
+        .map(|&MaxBodySize(max_body_size)| max_body_size)
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+}
+
+/// Like `deserialize_body`, but if the body can't be deserialized, the error contains the start of
+/// the body, so that malformed responses can be logged. Account numbers and the values of fields
+/// that look like they contain secrets, such as tokens and keys, are redacted.
+pub async fn deserialize_body_or_raw<T: serde::de::DeserializeOwned>(
+    response: Response,
+) -> Result<T> {
+    let mut buffer = Vec::new();
+    match deserialize_body_into(response, &mut buffer).await {
+        Err(Error::DeserializeError(error)) => {
+            Err(Error::DeserializeBodyError(error, body_snippet(&buffer)))
+        }
+        result => result,
+    }
+}
+
+/// Returns the redacted start of `body`, at most `MAX_BODY_SNIPPET_LENGTH` bytes long.
+fn body_snippet(body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    // Redacting before truncating ensures that secrets that are cut off are redacted too
+    let mut snippet = redact_secrets(&body).into_owned();
+    if snippet.len() > MAX_BODY_SNIPPET_LENGTH {
+        let mut end = MAX_BODY_SNIPPET_LENGTH;
+        while !snippet.is_char_boundary(end) {
+            end -= 1;
+        }
+        snippet.truncate(end);
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+fn redact_secrets(input: &str) -> Cow<'_, str> {
+    lazy_static! {
+        static ref SECRET_FIELD_RE: Regex = Regex::new(
+            r#"(?i)(?P<field>"[^"]*(?:token|key|secret|password|auth)[^"]*"\s*:\s*)"(?:[^"\\]|\\.)*""#
+        )
+        .unwrap();
+        static ref ACCOUNT_NUMBER_RE: Regex = Regex::new("\\d{16}").unwrap();
+    }
+    let redacted = SECRET_FIELD_RE.replace_all(input, "$field\"[REDACTED]\"");
+    match ACCOUNT_NUMBER_RE.replace_all(&redacted, "[REDACTED ACCOUNT NUMBER]") {
+        Cow::Borrowed(_) => redacted,
+        Cow::Owned(redacted) => Cow::Owned(redacted),
+    }
+}
+
+/// Reads and discards the body of `response`, and returns its length. Reading the body to the end
+/// allows the connection to be reused for other requests. The chunks received from hyper are
+/// dropped as they arrive, so the body is never buffered in full. Like when reading the body,
+/// fails with `Error::ResponseTooLarge` once the body exceeds the maximum body size of the
+/// request, so that an endless body isn't read until the idle timeout.
+pub async fn drain_body(mut response: Response) -> Result<u64> {
+    let max_body_size = max_body_size(&response);
+    let mut length = 0;
+    while let Some(chunk) = next_body_chunk(&mut response).await {
+        length += chunk?.len() as u64;
+        if length > max_body_size as u64 {
+            return Err(Error::ResponseTooLarge(max_body_size));
+        }
+    }
+    Ok(length)
+}
+
+/// Like `deserialize_body`, but first checks that the body is a JSON object containing all of
+/// `required_fields`, and fails with `Error::MissingFields` listing the missing ones otherwise.
+/// Fields of nested objects are given as paths separated by `.`, such as `"wireguard.ipv4"`.
+/// This gives a clearer error than `Error::DeserializeError` when a field is removed from the API.
+pub async fn deserialize_body_with_required_fields<T: serde::de::DeserializeOwned>(
+    response: Response,
+    required_fields: &[&str],
+) -> Result<T> {
+    let value: serde_json::Value = deserialize_body(response).await?;
+    let missing_fields: Vec<String> = required_fields
+        .iter()
+        .filter(|field| {
+            field
+                .split('.')
+                .try_fold(&value, |value, key| value.as_object()?.get(key))
+                .is_none()
+        })
+        .map(|field| field.to_string())
+        .collect();
+    if !missing_fields.is_empty() {
+        return Err(Error::MissingFields(missing_fields));
+    }
+
+    serde_json::from_value(value).map_err(Error::DeserializeError)
+}
+
+/// Returns the response if its status is `expected_status`. `204 No Content` is also accepted
+/// whenever a successful status is expected, since it only means that there is no body.
+pub async fn parse_rest_response(
+    response: Response,
+    expected_status: hyper::StatusCode,
+) -> Result<Response> {
+    let status = response.status();
+    let no_content = status == StatusCode::NO_CONTENT && expected_status.is_success();
+    if status != expected_status && !no_content {
+        return handle_error_response(response).await;
+    }
+
+    Ok(response)
+}
+
+
+/// Converts an error response into an `Error`. An `ErrorResponse` body becomes an `ApiError`, and
+/// any other body, such as an HTML page from a proxy, an `UnexpectedErrorBody`.
+pub async fn handle_error_response<T>(response: Response) -> Result<T> {
+    let status = response.status();
+    if status == StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS {
+        return Err(Error::LegallyBlocked(blocked_by(response.headers())));
+    }
+
+    let body = get_bytes(response).await?;
+    match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(err) => Err(Error::ApiError {
+            status,
+            code: ApiErrorCode::from_code(&err.code),
+            detail: err.detail,
+        }),
+        Err(_) => Err(Error::UnexpectedErrorBody(status, body_snippet(&body))),
+    }
+}
+
+/// Returns the target of the first `Link` header with the `blocked-by` relation, which identifies
+/// the entity that blocks a resource, as defined by RFC 7725.
+fn blocked_by(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|link| {
+            let mut parts = link.split(';');
+            let target = parts.next()?.trim();
+            let is_blocked_by = parts.any(|param| {
+                let mut param = param.splitn(2, '=');
+                param.next().map(str::trim) == Some("rel")
+                    && param
+                        .next()
+                        .map(|rel| rel.trim().trim_matches('"'))
+                        .map(|rel| rel.split_whitespace().any(|rel| rel == "blocked-by"))
+                        .unwrap_or(false)
+            });
+            if is_blocked_by && target.starts_with('<') && target.ends_with('>') {
+                Some(target[1..target.len() - 1].to_owned())
+            } else {
+                None
+            }
+        })
+}
+
+#[derive(Clone)]
+pub struct MullvadRestHandle {
+    pub(crate) service: RequestServiceHandle,
+    pub factory: RequestFactory,
+    api_address_fetch_interval: Arc<Mutex<Duration>>,
+    /// Asks the API address fetcher to fetch new addresses right away. The fetcher replies with
+    /// whether the fetch succeeded.
+    address_refresh_tx: mpsc::UnboundedSender<oneshot::Sender<bool>>,
+    /// Stops the API address fetcher once every handle that shares it has been dropped. The
+    /// fetcher's own handle doesn't share it.
+    fetcher: Option<Arc<FetcherGuard>>,
+}
+
+/// Returns `interval` randomly lengthened or shortened by up to `API_IP_CHECK_JITTER`.
+fn jittered_interval(interval: Duration, rng: &mut impl Rng) -> Duration {
+    interval.mul_f64(rng.gen_range(1.0 - API_IP_CHECK_JITTER, 1.0 + API_IP_CHECK_JITTER))
+}
+
+/// Decides when the API address fetcher of a `MullvadRestHandle` fetches new API addresses. The
+/// intervals are randomly lengthened or shortened by up to 10%.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressFetcherConfig {
+    /// Time to wait before the first fetch.
+    pub first_fetch_delay: Duration,
+    /// Time to wait after a successful fetch.
+    pub interval: Duration,
+    /// Time to wait after a failed fetch.
+    pub error_interval: Duration,
+}
+
+impl Default for AddressFetcherConfig {
+    fn default() -> Self {
+        Self {
+            first_fetch_delay: API_IP_CHECK_DELAY,
+            interval: API_IP_CHECK_INTERVAL,
+            error_interval: API_IP_CHECK_ERROR_INTERVAL,
+        }
+    }
+}
+
+/// Aborts the API address fetcher, including any request it has in flight, when dropped.
+struct FetcherGuard(AbortHandle);
+
+impl Drop for FetcherGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl MullvadRestHandle {
+    pub(crate) fn new(
+        service: RequestServiceHandle,
+        factory: RequestFactory,
+        address_cache: AddressCache,
+        fetcher_config: AddressFetcherConfig,
+    ) -> Self {
+        let (address_refresh_tx, address_refresh_rx) = mpsc::unbounded();
+        let mut handle = Self {
+            service,
+            factory,
+            api_address_fetch_interval: Arc::new(Mutex::new(fetcher_config.first_fetch_delay)),
+            address_refresh_tx,
+            fetcher: None,
+        };
+        let abort_handle =
+            handle.spawn_api_address_fetcher(address_cache, fetcher_config, address_refresh_rx);
+        handle.fetcher = Some(Arc::new(FetcherGuard(abort_handle)));
+
+        handle
+    }
+
+    /// Stops fetching new API addresses, and aborts the fetch that is in progress, if any. The
+    /// fetcher is also stopped when every clone of this handle has been dropped.
+    pub fn stop_api_address_fetcher(&self) {
+        if let Some(fetcher) = &self.fetcher {
+            fetcher.0.abort();
+        }
+    }
+
+    /// Fetches new API addresses right away, instead of waiting for the next scheduled fetch, and
+    /// updates the address cache with them. Intended for when the API has become unreachable. If a
+    /// fetch is already in progress, no new fetch is made, and the result of that one is returned.
+    /// Fails with `Error::ApiAddressFetchFailed` if the addresses could not be fetched.
+    pub async fn refresh_api_addresses(&self) -> Result<()> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.address_refresh_tx
+            .unbounded_send(result_tx)
+            .map_err(|_| Error::SendError)?;
+        match result_rx.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::ApiAddressFetchFailed),
+            Err(_) => Err(Error::ReceiveError),
+        }
+    }
+
+    fn spawn_api_address_fetcher(
+        &self,
+        address_cache: AddressCache,
+        config: AddressFetcherConfig,
+        mut refresh_rx: mpsc::UnboundedReceiver<oneshot::Sender<bool>>,
+    ) -> AbortHandle {
+        let handle = self.clone();
+        let fetch_interval = self.api_address_fetch_interval.clone();
+
+        let (fetcher, abort_handle) = abortable(async move {
+            // start the first fetch after the delay, 15 minutes by default
+            let api_proxy = crate::ApiProxy { handle };
+            // The clock of the runtime is used, so that the schedule can be tested with paused time
+            let mut next_check = tokio::time::Instant::now() + config.first_fetch_delay;
+
+            let next_error_check =
+                || jittered_interval(config.error_interval, &mut rand::thread_rng());
+            let next_regular_check = || jittered_interval(config.interval, &mut rand::thread_rng());
+
+            let mut interval = tokio::time::interval_at(next_check, TIMER_CHECK_INTERVAL);
+
+            loop {
+                let mut refresh_waiters = vec![];
+                match select(Box::pin(interval.tick()), refresh_rx.next()).await {
+                    Either::Left(_) if next_check < tokio::time::Instant::now() => (),
+                    Either::Left(_) => continue,
+                    Either::Right((Some(result_tx), _)) => {
+                        log::debug!("Refreshing API addresses ahead of schedule");
+                        refresh_waiters.push(result_tx);
+                    }
+                    Either::Right((None, _)) => continue,
+                }
+
+                let succeeded = match api_proxy.clone().get_api_addrs().await {
+                    Ok(new_addrs) => {
+                        let delay = next_regular_check();
+                        log::debug!(
+                            "Fetched new API addresses {:?}, will fetch again in {} hours",
+                            new_addrs,
+                            delay.as_secs() / (60 * 60)
+                        );
+                        if let Err(err) = address_cache.set_addresses(new_addrs).await {
+                            log::error!("Failed to save newly updated API addresses: {}", err);
+                        }
+                        next_check = tokio::time::Instant::now() + delay;
+                        *fetch_interval.lock().unwrap() = delay;
+                        true
+                    }
+                    Err(err) => {
+                        let delay = next_error_check();
+                        log::error!(
+                            "Failed to fetch new API addresses: {}, will retry again in {} seconds",
+                            err,
+                            delay.as_secs()
+                        );
+                        next_check = tokio::time::Instant::now() + delay;
+                        *fetch_interval.lock().unwrap() = delay;
+                        false
+                    }
+                };
+
+                // Refreshes that were requested during the fetch are answered by it
+                while let Ok(Some(result_tx)) = refresh_rx.try_next() {
+                    refresh_waiters.push(result_tx);
+                }
+                for result_tx in refresh_waiters {
+                    let _ = result_tx.send(succeeded);
+                }
+            }
+        });
+        self.service.spawn(fetcher);
+        abort_handle
+    }
+
+    pub fn service(&self) -> RequestServiceHandle {
+        self.service.clone()
+    }
+
+    pub fn factory(&self) -> &RequestFactory {
+        &self.factory
+    }
+
+    /// Replaces the account token used by all requests built by this handle and its clones that
+    /// haven't been dispatched yet, without affecting requests in flight. `None` removes the auth.
+    pub fn set_token(&self, token: Option<String>) -> Result<()> {
+        self.factory.set_default_auth(token)
+    }
+
+    /// Returns a handle that can submit requests to the request service, but can't reset the
+    /// service or close its connections. Intended for components that don't own the service.
+    pub fn readonly(&self) -> RestHandle {
+        RestHandle {
+            service: self.service.clone(),
+            factory: self.factory.clone(),
+        }
+    }
+
+    /// Returns a snapshot of the effective configuration of the request service, including the
+    /// timeout of the requests built by the factory and the current API address fetch interval.
+    pub async fn config(&self) -> Result<ServiceConfig> {
+        let mut config = self.service.config().await?;
+        config.default_timeout = Some(self.factory.timeout);
+        config.api_address_fetch_interval = Some(*self.api_address_fetch_interval.lock().unwrap());
+        Ok(config)
+    }
+}
+
+/// A handle to a request service that can submit requests, but not control the lifecycle of the
+/// service. Created by `MullvadRestHandle::readonly`.
+#[derive(Clone)]
+pub struct RestHandle {
+    service: RequestServiceHandle,
+    factory: RequestFactory,
+}
+
+impl RestHandle {
+    /// Submits a `RestRequest` for execution to the request service.
+    pub async fn request(&self, request: RestRequest) -> Result<Response> {
+        self.service.request(request).await
+    }
+
+    /// Returns metadata for all requests currently executed by the request service.
+    pub fn in_flight_requests(&self) -> Vec<InFlightRequest> {
+        self.service.in_flight_requests()
+    }
+
+    /// Spawns a future on the RPC runtime.
+    pub fn spawn<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) {
+        self.service.spawn(future)
+    }
+
+    pub fn factory(&self) -> &RequestFactory {
+        &self.factory
+    }
+}
+
+fn flatten_result<T, E>(
+    result: std::result::Result<std::result::Result<T, E>, E>,
+) -> std::result::Result<T, E> {
+    match result {
+        Ok(value) => value,
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use hyper::client::HttpConnector;
+    use std::{net::Ipv4Addr, sync::atomic::AtomicBool};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    const NO_CONTENT_RESPONSE: &str = "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+
+    /// A connector that refuses the first `failures` connections, and then connects normally.
+    #[derive(Clone)]
+    struct FailingConnector {
+        failures: Arc<Mutex<usize>>,
+        attempts: Arc<Mutex<usize>>,
+        http: HttpConnector,
+    }
+
+    impl FailingConnector {
+        fn new(failures: usize) -> Self {
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            Self {
+                failures: Arc::new(Mutex::new(failures)),
+                attempts: Arc::new(Mutex::new(0)),
+                http,
+            }
+        }
+
+        fn attempts(&self) -> usize {
+            *self.attempts.lock().unwrap()
+        }
+    }
+
+    impl hyper::service::Service<Uri> for FailingConnector {
+        type Response = TcpStream;
+        type Error = Box<dyn std::error::Error + Send + Sync>;
+        type Future = std::pin::Pin<
+            Box<dyn Future<Output = std::result::Result<TcpStream, Self::Error>> + Send + 'static>,
+        >;
+
+        fn poll_ready(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+            self.http.poll_ready(cx).map_err(Into::into)
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            *self.attempts.lock().unwrap() += 1;
+            {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Box::pin(async {
+                        Err(io::Error::from(io::ErrorKind::ConnectionRefused).into())
+                    });
+                }
+            }
+            let connecting = self.http.call(uri);
+            Box::pin(async move { connecting.await.map_err(Into::into) })
+        }
+    }
+
+    fn new_failing_service(
+        address: SocketAddr,
+        failures: usize,
+    ) -> (FailingConnector, RequestFactory, RequestServiceHandle) {
+        let connector = FailingConnector::new(failures);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestService::new(connector.clone(), Handle::current(), address_cache);
+        let handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+        (connector, factory, handle)
+    }
+
+    /// A connector that applies the connect timeout of requests, and either connects normally or
+    /// never completes connecting.
+    #[derive(Clone)]
+    struct TimeoutConnector {
+        stall: bool,
+        http: HttpConnector,
+    }
+
+    impl TimeoutConnector {
+        fn connecting() -> Self {
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            Self { stall: false, http }
+        }
+
+        fn stalling() -> Self {
+            Self {
+                stall: true,
+                ..Self::connecting()
+            }
+        }
+    }
+
+    impl hyper::service::Service<Uri> for TimeoutConnector {
+        type Response = TcpStream;
+        type Error = io::Error;
+        type Future = std::pin::Pin<
+            Box<dyn Future<Output = std::result::Result<TcpStream, Self::Error>> + Send + 'static>,
+        >;
+
+        fn poll_ready(
+            &mut self,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            if self.stall {
+                return Box::pin(with_connect_timeout(futures::future::pending()));
+            }
+            let connecting = self.http.call(uri);
+            Box::pin(with_connect_timeout(async move {
+                connecting
+                    .await
+                    .map_err(|error| io::Error::new(io::ErrorKind::ConnectionRefused, error))
+            }))
+        }
+    }
+
+    /// A timer that records the timeouts that it measures.
+    #[derive(Default)]
+    struct RecordingTimer(Mutex<Vec<Duration>>);
+
+    impl Timer for RecordingTimer {
+        fn timeout(&self, duration: Duration) -> Pin<Box<dyn Future<Output = Elapsed> + Send>> {
+            self.0.lock().unwrap().push(duration);
+            TokioTimer.timeout(duration)
+        }
+    }
+
+    fn fast_retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestAddressProvider(SocketAddr);
+
+    impl AddressProvider for TestAddressProvider {
+        fn get_address(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn clone_box(&self) -> Box<dyn AddressProvider> {
+            Box::new(self.clone())
+        }
+    }
+
+    /// A provider whose exhaustion is controlled by the test.
+    #[derive(Clone)]
+    struct ExhaustibleAddressProvider(SocketAddr, Arc<AtomicBool>);
+
+    impl AddressProvider for ExhaustibleAddressProvider {
+        fn get_address(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn clone_box(&self) -> Box<dyn AddressProvider> {
+            Box::new(self.clone())
+        }
+
+        fn is_exhausted(&self) -> bool {
+            self.1.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Spawns a server that reads one request per connection and answers it with
+    /// `NO_CONTENT_RESPONSE` after `delay`.
+    async fn spawn_slow_server(delay: Duration) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    read_request(&mut stream).await;
+                    tokio::time::delay_for(delay).await;
+                    let _ = stream.write_all(NO_CONTENT_RESPONSE.as_bytes()).await;
+                });
+            }
+        });
+        address
+    }
+
+    /// Spawns a server that reads one request per connection and answers it with `response`.
+    async fn spawn_server(response: &'static str) -> SocketAddr {
+        spawn_server_with_handler(move |_| response.to_owned()).await
+    }
+
+    /// Spawns a server that reads one request per connection and answers it with the response
+    /// returned by `handler` for the raw request.
+    pub(crate) async fn spawn_server_with_handler(
+        handler: impl Fn(&[u8]) -> String + Send + Sync + 'static,
+    ) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let request = read_request(&mut stream).await;
+                    let _ = stream.write_all(handler(&request).as_bytes()).await;
+                });
+            }
+        });
+        address
+    }
+
+    /// Reads the request headers and the body, if it has a `Content-Length`.
+    async fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut request = vec![];
+        let mut buffer = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap_or(0);
+            if read == 0 {
+                return request;
+            }
+            request.extend(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request).to_lowercase();
+            if let Some(headers_end) = text.find("\r\n\r\n") {
+                let content_length = text[..headers_end]
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|length| length.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= headers_end + 4 + content_length {
+                    return request;
+                }
+            }
+        }
+    }
+
+    /// Spawns a request service that uses `connector` and keeps no idle connections, so that every
+    /// request makes a new connection.
+    pub(crate) fn spawn_service<C: Connect + Clone + Send + Sync + 'static>(
+        connector: C,
+        address_cache: AddressCache,
+    ) -> RequestServiceHandle {
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .pool_max_idle_per_host(0)
+            .build(connector);
+        let handle = service.handle();
+        tokio::spawn(service.into_future());
+        handle
+    }
+
+    /// Returns a request factory and a service that send plain HTTP requests to `address`.
+    pub(crate) fn new_service(address: SocketAddr) -> (RequestFactory, RequestServiceHandle) {
+        new_service_with_addresses(address, vec![address])
+    }
+
+    /// Like `new_service`, but with `addresses` in the address cache of the service.
+    fn new_service_with_addresses(
+        address: SocketAddr,
+        addresses: Vec<SocketAddr>,
+    ) -> (RequestFactory, RequestServiceHandle) {
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(addresses, None).unwrap();
+        let service = RequestService::new(connector, Handle::current(), address_cache);
+        let handle = service.handle();
+        tokio::spawn(service.into_future());
+
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+        (factory, handle)
+    }
+
+    #[test]
+    fn test_chained_address_provider() {
+        let addresses: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+            "127.0.0.1:3".parse().unwrap(),
+        ];
+        let exhausted: Vec<_> = addresses
+            .iter()
+            .map(|_| Arc::new(AtomicBool::new(false)))
+            .collect();
+        let providers = addresses
+            .iter()
+            .zip(&exhausted)
+            .map(|(address, exhausted)| {
+                Box::new(ExhaustibleAddressProvider(*address, exhausted.clone()))
+                    as Box<dyn AddressProvider>
+            })
+            .collect();
+        let chain = ChainedAddressProvider::new(providers).unwrap();
+
+        for (index, address) in addresses.iter().enumerate() {
+            assert_eq!(chain.get_address(), address.to_string());
+            assert!(!chain.is_exhausted());
+            exhausted[index].store(true, Ordering::SeqCst);
+        }
+        // The last provider is used even though it's exhausted
+        assert!(chain.is_exhausted());
+        assert_eq!(chain.get_address(), addresses[2].to_string());
+
+        exhausted[0].store(false, Ordering::SeqCst);
+        assert_eq!(chain.get_address(), addresses[0].to_string());
+        assert!(ChainedAddressProvider::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_multi_address_provider_rotates() {
+        let addresses: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+            "127.0.0.1:3".parse().unwrap(),
+        ];
+        let provider = MultiAddressProvider::new(addresses.clone()).unwrap();
+        let clone = provider.clone_box();
+
+        assert_eq!(provider.get_address(), addresses[0].to_string());
+        assert_eq!(clone.get_address(), addresses[1].to_string());
+        assert_eq!(provider.get_address(), addresses[2].to_string());
+        assert_eq!(provider.get_address(), addresses[0].to_string());
+        assert!(!provider.is_exhausted());
+        assert!(MultiAddressProvider::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_multi_address_provider_skips_failed() {
+        let addresses: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        let address_cache = AddressCache::new(addresses.clone(), None).unwrap();
+        let provider = MultiAddressProvider::new(addresses.clone())
+            .unwrap()
+            .skip_failed(address_cache.clone());
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+
+        address_cache.register_failure(addresses[0], &error);
+        assert_eq!(provider.get_address(), addresses[1].to_string());
+        assert_eq!(provider.get_address(), addresses[1].to_string());
+        assert!(!provider.is_exhausted());
+
+        // Every address is used once they have all failed
+        address_cache.register_failure(addresses[1], &error);
+        assert!(provider.is_exhausted());
+        assert_eq!(provider.get_address(), addresses[0].to_string());
+        assert_eq!(provider.get_address(), addresses[1].to_string());
+
+        address_cache.register_success(addresses[0]);
+        assert!(!provider.is_exhausted());
+        assert_eq!(provider.get_address(), addresses[0].to_string());
+        assert_eq!(provider.get_address(), addresses[0].to_string());
+    }
+
+    #[test]
+    fn test_retry_summary() {
+        let error = Error::RetriesStopped(
+            RetrySummary {
+                attempts: 3,
+                stop_reason: RetryStopReason::DeadlineExceeded,
+            },
+            Box::new(Error::SendError),
+        );
+        assert_eq!(
+            error.to_string(),
+            "Request failed, gave up after 3 attempts: deadline exceeded - Failed to send request to rest client"
+        );
+        assert!(!error.is_retryable());
+
+        let summary = RetrySummary {
+            attempts: 1,
+            stop_reason: RetryStopReason::NonRetryable,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "not retried: the error is non-retryable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_auth_swap() {
+        let address = spawn_server_with_handler(|request| {
+            let request = String::from_utf8_lossy(request).to_lowercase();
+            let token = request
+                .lines()
+                .find_map(|line| line.strip_prefix("authorization: token "))
+                .unwrap_or("none")
+                .to_owned();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n\"{}\"",
+                token.len() + 2,
+                token
+            )
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        factory.set_default_auth(Some("1111".to_owned())).unwrap();
+
+        // The token is read when the request is dispatched, not when it is built
+        let request = factory.get("token").unwrap();
+        factory.set_default_auth(Some("2222".to_owned())).unwrap();
+        let response = service.request(request).await.unwrap();
+        assert_eq!(deserialize_body::<String>(response).await.unwrap(), "2222");
+
+        let requests = (0..20).map(|i| {
+            let factory = factory.clone();
+            let service = service.clone();
+            tokio::spawn(async move {
+                if i == 10 {
+                    factory.set_default_auth(Some("3333".to_owned())).unwrap();
+                }
+                let response = service.request(factory.get("token").unwrap()).await?;
+                deserialize_body::<String>(response).await
+            })
+        });
+        for token in futures::future::join_all(requests).await {
+            let token = token.unwrap().unwrap();
+            assert!(
+                token == "2222" || token == "3333",
+                "unexpected token {}",
+                token
+            );
+        }
+
+        let response = service
+            .request(factory.get("token").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(deserialize_body::<String>(response).await.unwrap(), "3333");
+    }
+
+    #[tokio::test]
+    async fn test_preferred_address_fallback() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        // Nothing listens on these privileged ports, so connections to them are refused
+        let first_closed = "127.0.0.1:1".parse().unwrap();
+        let second_closed = "127.0.0.1:2".parse().unwrap();
+        // The address cache shuffles its addresses, so the fallback order is only deterministic
+        // when the preferred address is the only other working address
+        let (factory, service) = new_service_with_addresses(address, vec![first_closed, address]);
+
+        let mut request = factory.get("preferred").unwrap();
+        request.set_preferred_address(address, FallbackMode::Single);
+        let response = service.request(request).await.unwrap();
+        assert_eq!(response.extensions().get(), Some(&ServedBy(address)));
+
+        let mut request = factory.post_json("preferred", &[1, 2, 3]).unwrap();
+        request.set_preferred_address(first_closed, FallbackMode::Rotate);
+        let response = service.request(request).await.unwrap();
+        assert_eq!(response.extensions().get(), Some(&ServedBy(address)));
+
+        let (factory, service) =
+            new_service_with_addresses(address, vec![first_closed, second_closed]);
+        let mut request = factory.get("preferred").unwrap();
+        request.set_preferred_address(first_closed, FallbackMode::Single);
+        let result = service.request(request).await;
+        assert!(matches!(result, Err(Error::HyperError(error)) if error.is_connect()));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_retry() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+
+        let (connector, factory, service) = new_failing_service(address, 2);
+        let request = factory.post_json("retry", &[1, 2, 3]).unwrap();
+        let response = service
+            .request_with_retry(request, fast_retry_policy())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(connector.attempts(), 3);
+
+        let (connector, factory, service) = new_failing_service(address, 5);
+        let request = factory.get("retry").unwrap();
+        let result = service
+            .request_with_retry(request, fast_retry_policy())
+            .await;
+        match result {
+            Err(Error::RetriesStopped(summary, error)) => {
+                assert_eq!(
+                    summary,
+                    RetrySummary {
+                        attempts: 3,
+                        stop_reason: RetryStopReason::AttemptsExhausted,
+                    }
+                );
+                assert!(matches!(*error, Error::HyperError(_)));
+            }
+            _ => panic!("unexpected result"),
+        }
+        assert_eq!(connector.attempts(), 3);
+
+        let (connector, factory, service) = new_failing_service(address, 5);
+        let mut request = factory.get("retry").unwrap();
+        request.set_max_total_attempts(2);
+        let result = service
+            .request_with_retry(request, fast_retry_policy())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::AttemptLimitExceeded(2, error)) if matches!(*error, Error::HyperError(_))
+        ));
+        assert_eq!(connector.attempts(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_with_retry_waits_on_timer() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let connector = FailingConnector::new(2);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let timer = Arc::new(RecordingTimer::default());
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .timer(timer.clone())
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let request = factory.get("retry").unwrap();
+        let timeout = request.timeout();
+        let response = service_handle
+            .request_with_retry(request, fast_retry_policy())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Three attempts, with a jittered backoff delay before each retry
+        let durations = timer.0.lock().unwrap().clone();
+        let delays: Vec<_> = durations
+            .into_iter()
+            .filter(|duration| *duration != timeout)
+            .collect();
+        assert_eq!(delays.len(), 2);
+        assert!(delays[0] <= Duration::from_millis(1));
+        assert!(delays[1] <= Duration::from_millis(2));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_retry_stops_on_non_retryable() {
+        let address = spawn_server(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (connector, factory, service) = new_failing_service(address, 0);
+        let request = factory.get("missing").unwrap();
+        let response = service
+            .request_with_retry(request, fast_retry_policy())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(connector.attempts(), 1);
+
+        let (connector, factory, service) = new_failing_service(address, 1);
+        let (mut body_tx, body) = hyper::Body::channel();
+        body_tx.send_data("streamed".into()).await.unwrap();
+        drop(body_tx);
+        let mut request = factory.post("upload").unwrap();
+        request.set_streaming_body(body);
+        let result = service
+            .request_with_retry(request, fast_retry_policy())
+            .await;
+        assert!(matches!(result, Err(Error::HyperError(error)) if error.is_connect()));
+        assert_eq!(connector.attempts(), 1);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            backoff_multiplier: 2.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff_delay(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff_delay(2), Duration::from_secs(4));
+        assert_eq!(policy.backoff_delay(3), Duration::from_secs(5));
+        assert_eq!(policy.backoff_delay(u32::MAX), Duration::from_secs(5));
+        for retry in 0..4 {
+            let delay = policy.jittered_delay(retry);
+            assert!(delay >= policy.backoff_delay(retry) / 2);
+            assert!(delay <= policy.backoff_delay(retry));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_body_is_not_resent() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let closed = "127.0.0.1:1".parse().unwrap();
+        let (factory, service) = new_service_with_addresses(address, vec![closed, address]);
+
+        let mut request = factory.post("upload").unwrap();
+        request.set_body(b"buffered".to_vec());
+        assert!(request.is_replayable());
+        request.set_preferred_address(closed, FallbackMode::Rotate);
+        let response = service.request(request).await.unwrap();
+        assert_eq!(response.extensions().get(), Some(&ServedBy(address)));
+
+        let (mut body_tx, body) = hyper::Body::channel();
+        body_tx.send_data("streamed".into()).await.unwrap();
+        drop(body_tx);
+        let mut request = factory.post("upload").unwrap();
+        request.set_streaming_body(body);
+        assert!(!request.is_replayable());
+        request.set_preferred_address(closed, FallbackMode::Rotate);
+        let result = service.request(request).await;
+        assert!(matches!(result, Err(Error::HyperError(error)) if error.is_connect()));
+    }
+
+    #[tokio::test]
+    async fn test_success_rate() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let mut hanging_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hanging_address = hanging_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = hanging_listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let (factory, service) = new_service(address);
+        let mut events = service.subscribe_events();
+        assert_eq!(service.success_rate().ratio(), None);
+
+        for _ in 0..2 {
+            service.request(factory.get("ok").unwrap()).await.unwrap();
+        }
+        let refused = RestRequest::get("https://127.0.0.1:1/").unwrap();
+        assert!(service.request(refused).await.is_err());
+        let hanging = RestRequest::get(&format!("https://{}/", hanging_address)).unwrap();
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(50), service.request(hanging)).await;
+        assert!(cancelled.is_err());
+        while let Some(record) = events.next().await {
+            if let RequestEvent::Aborted { .. } = record.event {
+                break;
+            }
+        }
+
+        let success_rate = service.success_rate();
+        assert_eq!((success_rate.successes, success_rate.total), (2, 3));
+        assert!(success_rate.window_start.unwrap() <= success_rate.window_end);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_with_paused_time() {
+        tokio::time::pause();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted_tx, accepted_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            let _ = accepted_tx.send(());
+            // Never respond
+            futures::future::pending::<()>().await;
+        });
+
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let mut address_changes = address_cache.subscribe_address_changes();
+        let timer = Arc::new(RecordingTimer::default());
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache.clone())
+            .timer(timer.clone())
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(address_cache.clone()),
+            None,
+        );
+
+        let timeout = Duration::from_secs(10);
+        let mut request = factory.get("hanging").unwrap();
+        request.set_timeout(timeout);
+        let response = tokio::spawn(async move { service_handle.request(request).await });
+        accepted_rx.await.unwrap();
+        tokio::time::advance(timeout + Duration::from_millis(1)).await;
+
+        let result = response.await.unwrap();
+        assert!(matches!(result, Err(Error::TimeoutError(_))));
+        assert_eq!(*timer.0.lock().unwrap(), vec![timeout]);
+        assert_eq!(
+            address_changes.next().await,
+            Some(crate::address_cache::AddressChanged {
+                old: address,
+                new: crate::API_ADDRESS.into(),
+                reason: crate::address_cache::AddressChangeReason::Fallback,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_on_stalled_connect() {
+        let address: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let mut address_changes = address_cache.subscribe_address_changes();
+        let service = RequestService::new(
+            TimeoutConnector::stalling(),
+            Handle::current(),
+            address_cache.clone(),
+        );
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(address_cache.clone()),
+            None,
+        );
+
+        let connect_timeout = Duration::from_millis(100);
+        let mut request = factory.get("stalled").unwrap();
+        request.set_connect_timeout(Some(connect_timeout));
+        let result = service_handle.request(request).await;
+
+        assert!(
+            matches!(result, Err(Error::ConnectTimeout(timeout)) if timeout == connect_timeout)
+        );
+        assert_eq!(
+            address_changes.next().await,
+            Some(crate::address_cache::AddressChanged {
+                old: address,
+                new: crate::API_ADDRESS.into(),
+                reason: crate::address_cache::AddressChangeReason::Fallback,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_allows_slow_body() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_request(&mut stream).await;
+            let headers = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\n";
+            stream.write_all(headers.as_bytes()).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(300)).await;
+            stream.write_all(b"hello").await.unwrap();
+        });
+        let service = RequestService::new(
+            TimeoutConnector::connecting(),
+            Handle::current(),
+            AddressCache::new(vec![address], None).unwrap(),
+        );
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let mut request = factory.get("slow").unwrap();
+        request.set_connect_timeout(Some(Duration::from_millis(100)));
+        let response = service_handle.request(request).await.unwrap();
+        assert_eq!(get_bytes(response).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_connection_pooling() {
+        // Answers every request on a connection, and counts the connections
+        let connections = Arc::new(AtomicUsize::new(0));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_connections = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                server_connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    while !read_request(&mut stream).await.is_empty() {
+                        let response = "HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .pool_max_idle_per_host(1)
+            .pool_idle_timeout(Some(Duration::from_secs(30)))
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let config = service_handle.config().await.unwrap();
+        assert_eq!(config.pool_max_idle_per_host, 1);
+        assert_eq!(config.pool_idle_timeout, Some(Duration::from_secs(30)));
+
+        for _ in 0..3 {
+            let response = service_handle
+                .request(factory.get("resource").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_services_from_builder_are_independent() {
+        let address = spawn_slow_server(Duration::from_millis(100)).await;
+
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let limits = HeaderLimits {
+            max_count: 10,
+            max_size: 1024,
+        };
+        let mut builder = RequestServiceBuilder::new(Handle::current(), address_cache);
+        builder.header_limits(limits).pool_max_idle_per_host(2);
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let services: Vec<_> = ["first", "second"]
+            .iter()
+            .map(|name| {
+                let service = builder
+                    .clone()
+                    .name(Some(name.to_string()))
+                    .build(connector.clone());
+                let handle = service.handle();
+                tokio::spawn(service.into_future());
+                handle
+            })
+            .collect();
+
+        let config = services[0].config().await.unwrap();
+        assert_eq!(config.name.as_deref(), Some("first"));
+        assert_eq!(config.header_limits, limits);
+        assert_eq!(config.pool_max_idle_per_host, 2);
+
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+        let reset_request = services[0].request(factory.get("slow").unwrap());
+        let other_request = services[1].request(factory.get("slow").unwrap());
+        let reset = async {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            services[0].reset().await;
+        };
+        let (reset_result, other_result, _) = futures::join!(reset_request, other_request, reset);
+        assert!(matches!(
+            reset_result,
+            Err(Error::Cancelled(AbortReason::Reset))
+        ));
+        assert_eq!(other_result.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_single_request() {
+        let address = spawn_slow_server(Duration::from_millis(100)).await;
+        let (factory, service) = new_service_with_addresses(address, vec![address]);
+
+        let (cancelled_handle, cancelled) = service
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        let (other_handle, other) = service
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        assert_ne!(cancelled_handle.id(), other_handle.id());
+        cancelled_handle.cancel().await;
+
+        assert!(matches!(
+            cancelled.await,
+            Err(Error::Cancelled(AbortReason::CallerCancelled))
+        ));
+        assert!(service
+            .in_flight_requests()
+            .iter()
+            .all(|request| request.id != cancelled_handle.id()));
+        assert_eq!(other.await.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_requests() {
+        const MAX_CONCURRENT: usize = 2;
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (server_active, server_max_active) = (active.clone(), max_active.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let active = server_active.clone();
+                let max_active = server_max_active.clone();
+                tokio::spawn(async move {
+                    read_request(&mut stream).await;
+                    let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now_active, Ordering::SeqCst);
+                    tokio::time::delay_for(Duration::from_millis(50)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(NO_CONTENT_RESPONSE.as_bytes()).await;
+                });
+            }
+        });
+
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .max_concurrent(Some(MAX_CONCURRENT))
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let requests = (0..3 * MAX_CONCURRENT)
+            .map(|_| service_handle.request(factory.get("slow").unwrap()))
+            .collect::<Vec<_>>();
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        }
+        assert!(max_active.load(Ordering::SeqCst) <= MAX_CONCURRENT);
+        assert!(max_active.load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_requests() {
+        let address = spawn_slow_server(Duration::from_millis(100)).await;
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .max_concurrent(Some(1))
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+        let high_priority_request = || {
+            let mut request = factory.get("slow").unwrap();
+            request.set_priority(RequestPriority::High);
+            request
+        };
+
+        let (normal_handle, normal) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        let (queued_handle, queued) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        let (high_handle, high) = service_handle
+            .request_with_handle(high_priority_request())
+            .await
+            .unwrap();
+        let (second_high_handle, second_high) = service_handle
+            .request_with_handle(high_priority_request())
+            .await
+            .unwrap();
+
+        // Only a single high priority request may exceed the limit
+        let in_flight: Vec<u64> = service_handle
+            .in_flight_requests()
+            .iter()
+            .map(|request| request.id)
+            .collect();
+        assert_eq!(in_flight, vec![normal_handle.id(), high_handle.id()]);
+        assert!(!in_flight.contains(&queued_handle.id()));
+        assert!(!in_flight.contains(&second_high_handle.id()));
+
+        // The second high priority request is started ahead of the queued normal request
+        let mut queued = Box::pin(queued);
+        let (normal, high, second_high) = futures::join!(normal, high, second_high);
+        assert!(normal.is_ok() && high.is_ok() && second_high.is_ok());
+        assert!(futures::poll!(&mut queued).is_pending());
+        assert!(queued.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_count() {
+        let address = spawn_slow_server(Duration::from_millis(100)).await;
+        let (factory, service) = new_service(address);
+        assert_eq!(service.in_flight_count(), 0);
+
+        let (_first_handle, first) = service
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        let (_second_handle, second) = service
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(service.in_flight_count(), 2);
+
+        let (first, second, ()) = futures::join!(first, second, service.wait_until_idle());
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(service.in_flight_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_idle_when_idle() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (_factory, service) = new_service(address);
+
+        tokio::time::timeout(Duration::from_secs(1), service.wait_until_idle())
+            .await
+            .expect("Idle service should resolve immediately");
+    }
+
+    #[tokio::test]
+    async fn test_dedup_window() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+        let window = Duration::from_millis(200);
+        let request = |body: &[&str]| {
+            let mut request = factory.post_json("resource", &body).unwrap();
+            request.set_dedup_window(Some(window));
+            request
+        };
+
+        assert!(service.request(request(&["first"])).await.is_ok());
+        assert!(matches!(
+            service.request(request(&["first"])).await,
+            Err(Error::DuplicateSuppressed)
+        ));
+        // Requests with other bodies, or that are not deduplicated, are unaffected
+        assert!(service.request(request(&["second"])).await.is_ok());
+        assert!(service
+            .request(factory.post_json("resource", &["first"]).unwrap())
+            .await
+            .is_ok());
+
+        tokio::time::delay_for(window).await;
+        assert!(service.request(request(&["first"])).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_only_after_success() {
+        // The first request fails with a server error
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = requests.clone();
+        let address = spawn_server_with_handler(move |_| {
+            if server_requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+            } else {
+                NO_CONTENT_RESPONSE.to_owned()
+            }
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let request = |account: &str| {
+            let mut request = factory.post_json("resource", &["first"]).unwrap();
+            request
+                .set_auth(Some(AccountToken::new(account).unwrap()))
+                .unwrap();
+            request.set_dedup_window(Some(Duration::from_secs(60)));
+            request
+        };
+
+        let response = service.request(request("1111")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(service.request(request("1111")).await.is_ok());
+        assert!(matches!(
+            service.request(request("1111")).await,
+            Err(Error::DuplicateSuppressed)
+        ));
+        // Requests with other auth are not identical
+        assert!(service.request(request("2222")).await.is_ok());
+
+        // Requests are suppressed while an identical request is in flight
+        let address = spawn_slow_server(Duration::from_millis(100)).await;
+        let (factory, service) = new_service(address);
+        let request = || {
+            let mut request = factory.post_json("resource", &["first"]).unwrap();
+            request.set_dedup_window(Some(Duration::from_secs(60)));
+            request
+        };
+        let (first, second) =
+            futures::future::join(service.request(request()), service.request(request())).await;
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(Error::DuplicateSuppressed)));
+    }
+
+    #[tokio::test]
+    async fn test_abort_reasons() {
+        let address = spawn_slow_server(Duration::from_millis(500)).await;
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .max_concurrent(Some(1))
+            .build(connector);
+        let service_handle = service.handle();
+        let (service_future, service_abort_handle) = abortable(service.into_future());
+        tokio::spawn(service_future);
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let (_, reset) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        service_handle.reset().await;
+        assert!(matches!(
+            reset.await,
+            Err(Error::Cancelled(AbortReason::Reset))
+        ));
+
+        let (cancelled_handle, cancelled) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        cancelled_handle.cancel().await;
+        assert!(matches!(
+            cancelled.await,
+            Err(Error::Cancelled(AbortReason::CallerCancelled))
+        ));
+
+        // Both the in-flight and the queued request are aborted when the service stops
+        let (_, in_flight) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        let (_, queued) = service_handle
+            .request_with_handle(factory.get("slow").unwrap())
+            .await
+            .unwrap();
+        service_abort_handle.abort();
+        let (in_flight, queued) = futures::join!(in_flight, queued);
+        assert!(matches!(
+            in_flight,
+            Err(Error::Cancelled(AbortReason::Shutdown))
+        ));
+        assert!(matches!(
+            queued,
+            Err(Error::Cancelled(AbortReason::Shutdown))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_on_failure() {
+        let closed: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![closed], None).unwrap();
+        let service = RequestService::new(connector, Handle::current(), address_cache.clone());
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let mut factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(address_cache.clone()),
+            None,
+        );
+
+        factory.set_rotate_on_failure(false);
+        let request = factory.get("probe").unwrap();
+        assert!(!request.rotate_on_failure());
+        assert!(service_handle.request(request).await.is_err());
+        assert_eq!(address_cache.preferred_addresses(1), vec![closed]);
+        assert_eq!(address_cache.dispatch_stats(), vec![(closed.ip(), 1)]);
+
+        // The request overrides the factory default
+        let mut request = factory.get("probe").unwrap();
+        request.set_rotate_on_failure(true);
+        assert!(service_handle.request(request).await.is_err());
+        assert_ne!(address_cache.preferred_addresses(1), vec![closed]);
+    }
+
+    #[tokio::test]
+    async fn test_stopping_fetcher_aborts_fetch() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = oneshot::channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_request(&mut stream).await;
+            let _ = request_tx.send(());
+            // Never respond, and wait for the client to close the connection
+            let mut buffer = [0u8; 1];
+            while stream.read(&mut buffer).await.unwrap_or(0) > 0 {}
+            let _ = closed_tx.send(());
+        });
+
+        let (factory, service) = new_service(address);
+        let mut events = service.subscribe_events();
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let mut address_changes = address_cache.subscribe_address_changes();
+        let handle = MullvadRestHandle::new(
+            service,
+            factory,
+            address_cache.clone(),
+            AddressFetcherConfig {
+                first_fetch_delay: Duration::from_secs(0),
+                ..AddressFetcherConfig::default()
+            },
+        );
+
+        request_rx.await.unwrap();
+        handle.stop_api_address_fetcher();
+        closed_rx.await.unwrap();
+
+        while let Some(record) = events.next().await {
+            if let RequestEvent::Aborted { reason } = record.event {
+                assert_eq!(reason, AbortReason::CallerCancelled);
+                break;
+            }
+        }
+        assert!(address_changes.try_next().is_err());
+    }
+
+    /// Spawns an API server that answers every request with `response`, and a handle whose
+    /// address fetcher fetches from it. Returns the handle, the address cache updated by the
+    /// fetcher and a stream that is notified of every request.
+    async fn spawn_fetcher(
+        response: &'static str,
+        config: AddressFetcherConfig,
+    ) -> (MullvadRestHandle, AddressCache, mpsc::UnboundedReceiver<()>) {
+        let (request_tx, request_rx) = mpsc::unbounded();
+        let address = spawn_server_with_handler(move |_| {
+            let _ = request_tx.unbounded_send(());
+            response.to_owned()
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let handle = MullvadRestHandle::new(service, factory, address_cache.clone(), config);
+        (handle, address_cache, request_rx)
+    }
+
+    /// Waits until the fetcher has scheduled the next fetch, and returns its delay.
+    async fn next_fetch_delay(handle: &MullvadRestHandle) -> Duration {
+        loop {
+            let delay = handle.config().await.unwrap().api_address_fetch_interval;
+            match delay {
+                Some(delay) if delay > Duration::from_secs(0) => return delay,
+                _ => tokio::task::yield_now().await,
+            }
+        }
+    }
+
+    fn fetcher_config() -> AddressFetcherConfig {
+        AddressFetcherConfig {
+            first_fetch_delay: Duration::from_secs(0),
+            interval: Duration::from_secs(24 * 60 * 60),
+            error_interval: Duration::from_secs(15 * 60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetcher_retries_after_error() {
+        tokio::time::pause();
+        let config = fetcher_config();
+        let (handle, _, mut requests) = spawn_fetcher(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            config,
+        )
+        .await;
+
+        requests.next().await.unwrap();
+        let delay = next_fetch_delay(&handle).await;
+        assert!(delay >= config.error_interval.mul_f64(1.0 - API_IP_CHECK_JITTER));
+        assert!(delay <= config.error_interval.mul_f64(1.0 + API_IP_CHECK_JITTER));
+
+        tokio::time::advance(delay + TIMER_CHECK_INTERVAL).await;
+        requests.next().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetcher_waits_for_interval_after_success() {
+        tokio::time::pause();
+        let config = fetcher_config();
+        let (handle, _, mut requests) = spawn_fetcher(
+            "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\n[\"127.0.0.1:1\"]",
+            config,
+        )
+        .await;
+
+        requests.next().await.unwrap();
+        let delay = next_fetch_delay(&handle).await;
+        assert!(delay >= config.interval.mul_f64(1.0 - API_IP_CHECK_JITTER));
+        assert!(delay <= config.interval.mul_f64(1.0 + API_IP_CHECK_JITTER));
+
+        tokio::time::advance(delay + TIMER_CHECK_INTERVAL).await;
+        requests.next().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_api_addresses() {
+        let (handle, address_cache, mut requests) = spawn_fetcher(
+            "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\n[\"127.0.0.1:1\"]",
+            AddressFetcherConfig::default(),
+        )
+        .await;
+        let fetched: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(!address_cache.addresses().contains(&fetched));
+
+        // Concurrent refreshes are served by a single fetch
+        let (first, second) = futures::future::join(
+            handle.refresh_api_addresses(),
+            handle.refresh_api_addresses(),
+        )
+        .await;
+        first.unwrap();
+        second.unwrap();
+        assert!(address_cache.addresses().contains(&fetched));
+        requests.next().await.unwrap();
+        assert!(requests.try_next().is_err());
+
+        handle.stop_api_address_fetcher();
+        assert!(handle.refresh_api_addresses().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_body_or_raw() {
+        let body = format!(
+            r#"{{"access_token": "secret\"value", "account": "1234567890123456", "padding": "{}""#,
+            "a".repeat(MAX_BODY_SNIPPET_LENGTH)
+        );
+        let response = Response::new(hyper::Body::from(body));
+
+        let error = deserialize_body_or_raw::<serde_json::Value>(response)
+            .await
+            .unwrap_err();
+        let snippet = match error {
+            Error::DeserializeBodyError(_, snippet) => snippet,
+            error => panic!("unexpected error: {}", error),
+        };
+        assert!(snippet.starts_with(
+            r#"{"access_token": "[REDACTED]", "account": "[REDACTED ACCOUNT NUMBER]", "padding""#
+        ));
+        assert_eq!(snippet.len(), MAX_BODY_SNIPPET_LENGTH + "...".len());
+    }
+
+    #[test]
+    fn test_factory_timeout_applies_to_every_method() {
+        let mut factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap())),
+            None,
+        );
+        let timeout = Duration::from_secs(42);
+        factory.timeout = timeout;
+
+        let requests = vec![
+            factory.request("path", Method::PUT).unwrap(),
+            factory.get("path").unwrap(),
+            factory.post("path").unwrap(),
+            factory.post_json("path", &[1, 2, 3]).unwrap(),
+            factory.delete("path").unwrap(),
+        ];
+        for request in requests {
+            assert_eq!(request.timeout(), timeout, "{}", request.method());
+        }
+    }
+
+    #[test]
+    fn test_factory_with_timeout() {
+        let address_provider = || Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap()));
+        let factory = RequestFactory::new("localhost".to_string(), address_provider(), None);
+        assert_eq!(factory.get("path").unwrap().timeout(), DEFAULT_TIMEOUT);
+
+        let timeout = Duration::from_secs(60);
+        let factory = RequestFactory::with_timeout(
+            "localhost".to_string(),
+            address_provider(),
+            None,
+            timeout,
+        );
+        assert_eq!(factory.get("path").unwrap().timeout(), timeout);
+        assert_eq!(factory.post("path").unwrap().timeout(), timeout);
+
+        let mut request = factory.get("path").unwrap();
+        request.set_timeout(Duration::from_secs(1));
+        assert_eq!(request.timeout(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_put_and_patch() {
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap())),
+            None,
+        );
+        let body_length = |request: &RestRequest| {
+            hyper::body::HttpBody::size_hint(request.request.body()).exact()
+        };
+
+        for (request, method) in [
+            (factory.put("device").unwrap(), Method::PUT),
+            (factory.patch("device").unwrap(), Method::PATCH),
+        ] {
+            assert_eq!(request.method(), method);
+            assert!(!request.request.headers().contains_key(header::CONTENT_TYPE));
+            assert_eq!(body_length(&request), Some(0));
+        }
+
+        let body = serde_json::json!({ "name": "device" });
+        let expected_length = serde_json::to_string(&body).unwrap().len() as u64;
+        for (request, method) in [
+            (factory.put_json("device", &body).unwrap(), Method::PUT),
+            (factory.patch_json("device", &body).unwrap(), Method::PATCH),
+        ] {
+            assert_eq!(request.method(), method);
+            let headers = request.request.headers();
+            assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+            assert_eq!(
+                headers[header::CONTENT_LENGTH],
+                expected_length.to_string().as_str()
+            );
+            assert_eq!(body_length(&request), Some(expected_length));
+            assert_eq!(request.timeout(), factory.timeout);
+        }
+    }
+
+    #[test]
+    fn test_host_header_omits_default_port() {
+        for uri in &[
+            "https://api.mullvad.net/v1/",
+            "https://api.mullvad.net:443/v1/",
+            "http://api.mullvad.net:80/v1/",
+        ] {
+            let request = RestRequest::get(uri).unwrap();
+            assert_eq!(request.request.headers()[header::HOST], "api.mullvad.net");
+        }
+    }
+
+    #[test]
+    fn test_host_header_includes_non_default_port() {
+        let request = RestRequest::get("https://api.mullvad.net:8443/v1/").unwrap();
+        assert_eq!(
+            request.request.headers()[header::HOST],
+            "api.mullvad.net:8443"
+        );
+
+        let request = RestRequest::get("http://api.mullvad.net:443/v1/").unwrap();
+        assert_eq!(
+            request.request.headers()[header::HOST],
+            "api.mullvad.net:443"
+        );
+
+        let request = RestRequest::get("https://[::1]:8443/v1/").unwrap();
+        assert_eq!(request.request.headers()[header::HOST], "[::1]:8443");
+    }
+
+    #[tokio::test]
+    async fn test_too_many_headers() {
+        let mut response = "HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\n".to_owned();
+        for i in 0..10 {
+            response.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+        }
+        response.push_str("\r\n{");
+        let address = spawn_server_with_handler(move |_| response.clone()).await;
+
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .header_limits(HeaderLimits {
+                max_count: 5,
+                ..HeaderLimits::default()
+            })
+            .build(connector);
+        let handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        // The body is truncated, so reading it would fail with a different error
+        let result = handle.request(factory.get("headers").unwrap()).await;
+        assert!(matches!(result, Err(Error::TooManyHeaders(11, _))));
+    }
+
+    #[tokio::test]
+    async fn test_header_size_limit() {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nX-Large: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "a".repeat(DEFAULT_MAX_RESPONSE_HEADERS_SIZE)
+        );
+        let address = spawn_server_with_handler(move |_| response.clone()).await;
+        let (factory, service) = new_service(address);
+
+        let result = service.request(factory.get("headers").unwrap()).await;
+        assert!(matches!(result, Err(Error::TooManyHeaders(..))));
+
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let result = oneshot_request(connector, factory.get("headers").unwrap()).await;
+        assert!(matches!(result, Err(Error::TooManyHeaders(..))));
+    }
+
+    #[tokio::test]
+    async fn test_no_content_is_success_for_every_verb() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+
+        for (method, expected_status) in [
+            (Method::GET, StatusCode::OK),
+            (Method::POST, StatusCode::CREATED),
+            (Method::PUT, StatusCode::OK),
+            (Method::PATCH, StatusCode::OK),
+            (Method::DELETE, StatusCode::NO_CONTENT),
+        ] {
+            let response = send_request(
+                &factory,
+                service.clone(),
+                "resource",
+                method.clone(),
+                None,
+                expected_status,
+            )
+            .await
+            .unwrap_or_else(|error| panic!("{} failed: {}", method, error));
+            let body: Option<Vec<String>> = deserialize_body(response).await.unwrap();
+            assert_eq!(body, None, "{} returned a body", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_content_helpers() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+
+        let response =
+            get_request::<()>(&factory, service.clone(), "resource", None, StatusCode::OK)
+                .await
+                .unwrap();
+        let _: () = deserialize_body(response).await.unwrap();
+
+        let response = post_request_with_json(
+            &factory,
+            service.clone(),
+            "resource",
+            &["value"],
+            None,
+            StatusCode::CREATED,
+        )
+        .await
+        .unwrap();
+        let body: Option<String> = deserialize_body(response).await.unwrap();
+        assert_eq!(body, None);
+
+        post_request_with_json_minimal(
+            &factory,
+            service.clone(),
+            "resource",
+            &["value"],
+            None,
+            StatusCode::OK,
+        )
+        .await
+        .unwrap();
+
+        let results = delete_many(&factory, service, &["first", "second"], None).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_no_content_is_not_accepted_for_error_statuses() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+
+        let result = send_request(
+            &factory,
+            service,
+            "resource",
+            Method::GET,
+            None,
+            StatusCode::NOT_MODIFIED,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_legally_blocked() {
+        let address = spawn_server(
+            "HTTP/1.1 451 Unavailable For Legal Reasons\r\nLink: <https://example.com/about>; rel=\"help\", <https://authority.example.com>; rel=\"blocked-by\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (factory, service) = new_service(address);
+
+        let result = send_request(
+            &factory,
+            service,
+            "resource",
+            Method::GET,
+            None,
+            StatusCode::OK,
+        )
+        .await;
+        match result {
+            Err(Error::LegallyBlocked(Some(authority))) => {
+                assert_eq!(authority, "https://authority.example.com")
+            }
+            _ => panic!("Expected the request to be legally blocked"),
+        }
+    }
+
+    #[test]
+    fn test_blocked_by_without_link() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(blocked_by(&headers), None);
+        headers.insert(
+            header::LINK,
+            HeaderValue::from_static("<https://example.com>; rel=\"help\""),
+        );
+        assert_eq!(blocked_by(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn test_api_error_codes() {
+        let error_response = |body: &'static str| {
+            let mut response = Response::new(hyper::Body::from(body));
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            response
+        };
+
+        let error = handle_error_response::<()>(error_response(
+            r#"{"code": "INVALID_ACCOUNT", "detail": "This is not a valid Mullvad account"}"#,
+        ))
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            &error,
+            Error::ApiError {
+                status: StatusCode::BAD_REQUEST,
+                code: ApiErrorCode::InvalidAccount,
+                detail: Some(detail),
+            } if detail == "This is not a valid Mullvad account"
+        ));
+        assert_eq!(error.api_error_code(), Some(ApiErrorCode::InvalidAccount));
+        assert_eq!(error.api_status(), Some(StatusCode::BAD_REQUEST));
+
+        let error = handle_error_response::<()>(error_response(
+            r#"{"code": "TOO_MANY_DEVICES", "message": "Too many devices"}"#,
+        ))
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            &error,
+            Error::ApiError { detail: Some(detail), .. } if detail == "Too many devices"
+        ));
+        assert_eq!(error.api_error_code(), Some(ApiErrorCode::TooManyDevices));
+
+        let error = handle_error_response::<()>(error_response(r#"{"code": "SOMETHING_NEW"}"#))
+            .await
+            .unwrap_err();
+        let code = error.api_error_code().unwrap();
+        assert_eq!(code, ApiErrorCode::Unknown("SOMETHING_NEW".to_owned()));
+        assert_eq!(code.as_str(), "SOMETHING_NEW");
+
+        let retried = Error::RetriesStopped(
+            RetrySummary {
+                attempts: 1,
+                stop_reason: RetryStopReason::NonRetryable,
+            },
+            Box::new(Error::ApiError {
+                status: StatusCode::BAD_REQUEST,
+                code: ApiErrorCode::KeyLimitReached,
+                detail: None,
+            }),
+        );
+        assert_eq!(
+            retried.api_error_code(),
+            Some(ApiErrorCode::KeyLimitReached)
+        );
+        assert_eq!(Error::SendError.api_error_code(), None);
+    }
+
+    #[tokio::test]
+    async fn test_error_response_bodies() {
+        let error_response = |status: StatusCode, body: &'static str| {
+            let mut response = Response::new(hyper::Body::from(body));
+            *response.status_mut() = status;
+            response
+        };
+
+        let error = handle_error_response::<()>(error_response(
+            StatusCode::BAD_REQUEST,
+            r#"{"code": "INVALID_VOUCHER"}"#,
+        ))
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::ApiError {
+                status: StatusCode::BAD_REQUEST,
+                code: ApiErrorCode::InvalidVoucher,
+                detail: None,
+            }
+        ));
+
+        let error = handle_error_response::<()>(error_response(
+            StatusCode::BAD_GATEWAY,
+            "<html><body><h1>502 Bad Gateway</h1></body></html>",
+        ))
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            &error,
+            Error::UnexpectedErrorBody(StatusCode::BAD_GATEWAY, body)
+                if body == "<html><body><h1>502 Bad Gateway</h1></body></html>"
+        ));
+        assert_eq!(error.api_error_code(), None);
+
+        let error = handle_error_response::<()>(error_response(StatusCode::NOT_FOUND, ""))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::UnexpectedErrorBody(StatusCode::NOT_FOUND, body) if body.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_close_connections_to_address() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Accept connections, but never respond
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let (factory, service) = new_service(address);
+
+        let request = service.request(factory.get("resource").unwrap());
+        let close = async {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            let other_address = "127.0.0.1:1".parse().unwrap();
+            assert_eq!(service.close_connections(other_address).await.unwrap(), 0);
+            assert_eq!(service.close_connections(address).await.unwrap(), 1);
+        };
+        let (result, _) = futures::join!(request, close);
+        assert!(matches!(
+            result,
+            Err(Error::Cancelled(AbortReason::ConnectionClosed))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_events() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+        let mut events = service.subscribe_events();
+
+        service
+            .request(factory.get("resource").unwrap())
+            .await
+            .unwrap();
+        let mut received = vec![];
+        while let Some(record) = events.next().await {
+            assert_eq!(record.id, 0);
+            let completed = matches!(record.event, RequestEvent::Completed { .. });
+            received.push(record.event);
+            if completed {
+                break;
+            }
+        }
+        assert!(matches!(
+            received.as_slice(),
+            [
+                RequestEvent::Queued,
+                RequestEvent::Dispatched,
+                RequestEvent::HeadersReceived,
+                RequestEvent::Completed {
+                    status: StatusCode::NO_CONTENT
+                },
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_body_into_reuses_buffer() {
+        let mut buffer = Vec::with_capacity(64);
+        let capacity = buffer.capacity();
+        for body in &["[1,2,3]", "[4]"] {
+            let response = Response::new(hyper::Body::from(*body));
+            let values: Vec<u32> = deserialize_body_into(response, &mut buffer).await.unwrap();
+            assert_eq!(values, serde_json::from_str::<Vec<u32>>(body).unwrap());
+            assert_eq!(buffer, body.as_bytes());
+        }
+        assert_eq!(buffer.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn test_body_is_read_to_content_length() {
+        let address = spawn_server(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\n[1,2][3]",
+        )
+        .await;
+        let (factory, service) = new_service(address);
+
+        let response = service.request(factory.get("").unwrap()).await.unwrap();
+        let value: Vec<u32> = deserialize_body(response).await.unwrap();
+        assert_eq!(value, vec![1, 2]);
+        let response = service.request(factory.get("").unwrap()).await.unwrap();
+        assert_eq!(get_bytes(response).await.unwrap(), b"[1,2]");
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_body_streaming() {
+        // A chunked body, without a Content-Length header
+        let chunked_response = |max_body_size| {
+            let chunks: Vec<std::result::Result<_, io::Error>> =
+                vec![Ok("[1,"), Ok("2,"), Ok("3]")];
+            let mut response =
+                Response::new(hyper::Body::wrap_stream(futures::stream::iter(chunks)));
+            response.extensions_mut().insert(MaxBodySize(max_body_size));
+            response
+        };
+        assert!(chunked_response(7)
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .is_none());
+        let values: Vec<u32> = deserialize_body_streaming(chunked_response(7))
+            .await
+            .unwrap();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        let result: Result<Vec<u32>> = deserialize_body_streaming(chunked_response(6)).await;
+        assert!(matches!(result, Err(Error::ResponseTooLarge(6))));
+
+        let empty: Option<u32> = deserialize_body_streaming(Response::new(hyper::Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(empty, None);
+
+        let malformed = Response::new(hyper::Body::from("[1,"));
+        let result: Result<Vec<u32>> = deserialize_body_streaming(malformed).await;
+        assert!(matches!(result, Err(Error::DeserializeError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size() {
+        let body = "a".repeat(100);
+        let address = spawn_server_with_handler(move |_| {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .await;
+        let (mut factory, service) = new_service(address);
+
+        factory.set_max_body_size(100);
+        let response = service.request(factory.get("body").unwrap()).await.unwrap();
+        assert_eq!(get_bytes(response).await.unwrap().len(), 100);
+
+        factory.set_max_body_size(99);
+        let response = service.request(factory.get("body").unwrap()).await.unwrap();
+        assert!(matches!(
+            get_bytes(response).await,
+            Err(Error::ResponseTooLarge(99))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_digest_verification() {
+        let body = r#"{"countries": []}"#;
+        let sha256 = base64::encode(ring::digest::digest(&ring::digest::SHA256, body.as_bytes()));
+        let response = |digest: Option<String>, verify: bool| {
+            let mut response = Response::new(hyper::Body::from(body));
+            if let Some(digest) = digest {
+                response
+                    .headers_mut()
+                    .insert(DIGEST_HEADER, HeaderValue::from_str(&digest).unwrap());
+            }
+            if verify {
+                response.extensions_mut().insert(VerifyDigest);
+            }
+            response
+        };
+
+        let value: serde_json::Value =
+            deserialize_body(response(Some(format!("SHA-256={}", sha256)), true))
+                .await
+                .unwrap();
+        assert_eq!(value["countries"], serde_json::json!([]));
+        assert_eq!(
+            get_bytes(response(Some(format!("md5=abc, sha-256={}", sha256)), true))
+                .await
+                .unwrap(),
+            body.as_bytes()
+        );
+        // Nothing to verify against
+        assert!(get_bytes(response(None, true)).await.is_ok());
+
+        let tampered = base64::encode(ring::digest::digest(&ring::digest::SHA256, b"tampered"));
+        let result: Result<serde_json::Value> =
+            deserialize_body(response(Some(format!("SHA-256={}", tampered)), true)).await;
+        assert!(
+            matches!(result, Err(Error::IntegrityMismatch(ref algorithm)) if algorithm == "SHA-256")
+        );
+        let result = get_bytes(response(Some("SHA-512=invalid".to_owned()), true)).await;
+        assert!(matches!(result, Err(Error::IntegrityMismatch(_))));
+        // Only verified when the request asks for it
+        assert!(
+            get_bytes(response(Some(format!("SHA-256={}", tampered)), false))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_body() {
+        let (mut body_tx, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                body_tx.send_data(vec![0u8; 1000].into()).await.unwrap();
+            }
+        });
+        assert_eq!(drain_body(Response::new(body)).await.unwrap(), 4000);
+
+        // An endless body is only drained up to the maximum body size
+        let (mut body_tx, body) = hyper::Body::channel();
+        tokio::spawn(
+            async move { while body_tx.send_data(vec![0u8; 1000].into()).await.is_ok() {} },
+        );
+        let mut response = Response::new(body);
+        response.extensions_mut().insert(MaxBodySize(2500));
+        assert!(matches!(
+            drain_body(response).await,
+            Err(Error::ResponseTooLarge(2500))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_body_with_required_fields() {
+        let body = r#"{"id": 1, "wireguard": {"ipv4": "10.0.0.1"}}"#;
+        let response = Response::new(hyper::Body::from(body));
+        let value: serde_json::Value =
+            deserialize_body_with_required_fields(response, &["id", "wireguard.ipv4"])
+                .await
+                .unwrap();
+        assert_eq!(value["id"], 1);
+
+        let response = Response::new(hyper::Body::from(body));
+        let result: Result<serde_json::Value> = deserialize_body_with_required_fields(
+            response,
+            &["id", "name", "wireguard.ipv6", "id.value"],
+        )
+        .await;
+        match result {
+            Err(Error::MissingFields(fields)) => {
+                assert_eq!(fields, vec!["name", "wireguard.ipv6", "id.value"])
+            }
+            _ => panic!("Expected missing fields"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_all_validated() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+
+        let mut invalid = factory.post_json("resource", &["value"]).unwrap();
+        invalid
+            .request
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from_static("1"));
+        let requests = vec![
+            factory.get("first").unwrap(),
+            invalid,
+            factory.get("second").unwrap(),
+        ];
+        match service.request_all_validated(requests).await {
+            Err(Error::InvalidBatchRequest(1, _)) => (),
+            _ => panic!("Expected the second request to be invalid"),
+        }
+        assert!(service.in_flight_requests().is_empty());
+
+        let requests = vec![
+            factory.get("first").unwrap(),
+            factory.delete("second").unwrap(),
+        ];
+        let results = service.request_all_validated(requests).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn test_request_all() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
+        let closed: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (unreachable_factory, _) = new_service(closed);
+
+        let requests = vec![
+            factory.get("first").unwrap(),
+            unreachable_factory.get("second").unwrap(),
+            factory.get("third").unwrap(),
+        ];
+        let outcome = service.request_all(requests).await;
+        assert_eq!(outcome.total(), 3);
+        assert_eq!(outcome.succeeded, 2);
+        assert_eq!(outcome.failed, 1);
+        assert_eq!(outcome.aborted, 0);
+        assert!(!outcome.all_succeeded());
+        assert!(outcome.at_least_succeeded(2));
+        assert!(outcome.results[1].is_err());
+    }
+
+    #[test]
+    fn test_api_address_check_jitter() {
+        use rand::{rngs::mock::StepRng, SeedableRng};
+
+        for interval in &[API_IP_CHECK_INTERVAL, API_IP_CHECK_ERROR_INTERVAL] {
+            let min = interval.mul_f64(1.0 - API_IP_CHECK_JITTER);
+            let max = interval.mul_f64(1.0 + API_IP_CHECK_JITTER);
+
+            assert_eq!(jittered_interval(*interval, &mut StepRng::new(0, 0)), min);
+            let longer = jittered_interval(*interval, &mut StepRng::new(u64::MAX / 4 * 3, 0));
+            assert!(longer > *interval && longer < max);
+
+            let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+            let delays: Vec<_> = (0..100)
+                .map(|_| jittered_interval(*interval, &mut rng))
+                .collect();
+            assert!(delays.iter().all(|delay| *delay >= min && *delay <= max));
+            assert!(delays.iter().any(|delay| delay != &delays[0]));
+        }
+    }
+
+    #[test]
+    fn test_retry_timeout_scaling() {
+        let policy = RetryPolicy {
+            timeout_multiplier: 1.5,
+            max_attempt_timeout: Some(Duration::from_secs(20)),
+            deadline: None,
+            ..RetryPolicy::default()
+        };
+        let timeout = Duration::from_secs(10);
+        let elapsed = Duration::from_secs(0);
+        assert_eq!(
+            policy.attempt_timeout(timeout, 0, elapsed),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            policy.attempt_timeout(timeout, 1, elapsed),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            policy.attempt_timeout(timeout, 2, elapsed),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            RetryPolicy::default().attempt_timeout(timeout, 5, elapsed),
+            Some(timeout)
+        );
+    }
+
+    #[test]
+    fn test_retry_timeout_is_clamped_to_deadline() {
+        let policy = RetryPolicy {
+            timeout_multiplier: 2.0,
+            max_attempt_timeout: None,
+            deadline: Some(Duration::from_secs(30)),
+            ..RetryPolicy::default()
+        };
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            policy.attempt_timeout(timeout, 1, Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            policy.attempt_timeout(timeout, 2, Duration::from_secs(25)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            policy.attempt_timeout(timeout, 3, Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_trace_context() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context: TraceContext = value.parse().unwrap();
+        assert_eq!(context.trace_id(), 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.parent_id(), 0x00f067aa0ba902b7);
+        assert!(context.sampled());
+
+        let mut request = RestRequest::get("https://127.0.0.1/").unwrap();
+        request.set_trace_context(context);
+        assert_eq!(request.request.headers()["traceparent"], value);
+
+        for invalid in &[
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_query_parameters() {
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap())),
+            None,
+        );
+
+        assert_eq!(
+            factory.get_with_query("relays", &[]).unwrap().uri(),
+            factory.get("relays").unwrap().uri()
+        );
+        assert_eq!(
+            factory
+                .get_with_query("relays", &[("country", "se"), ("owned", "true")])
+                .unwrap()
+                .uri(),
+            "https://127.0.0.1:1/relays?country=se&owned=true"
+        );
+        assert_eq!(
+            factory
+                .get_with_query("search", &[("a b", "c&d=e")])
+                .unwrap()
+                .uri(),
+            "https://127.0.0.1:1/search?a%20b=c%26d%3De"
+        );
+        let request = factory
+            .request_with_query(
+                "devices",
+                Method::DELETE,
+                &[("id", "1"), ("id", "2"), ("id", "3")],
+            )
+            .unwrap();
+        assert_eq!(request.method(), Method::DELETE);
+        assert_eq!(request.uri(), "https://127.0.0.1:1/devices?id=1&id=2&id=3");
+        assert_eq!(
+            factory
+                .get_with_query("relays?v=2", &[("country", "se")])
+                .unwrap()
+                .uri(),
+            "https://127.0.0.1:1/relays?v=2&country=se"
+        );
+    }
+
+    #[test]
+    fn test_factory_default_headers() {
+        let address: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/plain"));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert("x-custom", HeaderValue::from_static("value"));
+        let factory = RequestFactory::with_headers(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+            headers,
+        )
+        .unwrap();
+
+        let request = factory.get("resource").unwrap();
+        let headers = request.request.headers();
+        assert_eq!(headers[header::ACCEPT], "text/plain");
+        assert_eq!(headers[header::HOST], "localhost");
+        assert_eq!(headers["x-custom"], "value");
+
+        let request = factory.post_json("resource", &["value"]).unwrap();
+        assert_eq!(
+            request.request.headers()[header::CONTENT_TYPE],
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_factory_rejects_reserved_headers() {
+        let address: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        for name in RESERVED_HEADERS {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static("value"));
+            let result = RequestFactory::with_headers(
+                "localhost".to_string(),
+                Box::new(TestAddressProvider(address)),
+                None,
+                headers,
+            );
+            assert!(matches!(result, Err(Error::ReservedHeader(ref reserved)) if reserved == name));
+        }
     }
 
-    /// Converts into a `hyper::Request<hyper::Body>`
-    fn into_request(self) -> Request {
-        let Self {
-            mut request, auth, ..
-        } = self;
-        if let Some(auth) = auth {
-            request.headers_mut().insert(header::AUTHORIZATION, auth);
+    /// Serializes `request` roughly as it's sent, with its auth applied.
+    async fn request_bytes(request: RestRequest) -> Vec<u8> {
+        let request = request.into_request();
+        let mut bytes = format!(
+            "{} {} {:?}\r\n",
+            request.method(),
+            request.uri(),
+            request.version()
+        )
+        .into_bytes();
+        for (name, value) in request.headers() {
+            bytes.extend(name.as_str().as_bytes());
+            bytes.extend(b": ");
+            bytes.extend(value.as_bytes());
+            bytes.extend(b"\r\n");
         }
-        request
+        bytes.extend(b"\r\n");
+        bytes.extend(&hyper::body::to_bytes(request.into_body()).await.unwrap());
+        bytes
     }
 
-    /// Returns the URI of the request
-    pub fn uri(&self) -> &Uri {
-        self.request.uri()
+    #[tokio::test]
+    async fn test_try_clone() {
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap())),
+            None,
+        );
+        factory.set_default_auth(Some("token".to_owned())).unwrap();
+        let mut request = factory
+            .post_json("devices", &serde_json::json!({ "name": "device" }))
+            .unwrap();
+        request.header_str(header::USER_AGENT, "test").unwrap();
+        request.set_timeout(Duration::from_secs(3));
+
+        let copy = request.try_clone().unwrap();
+        assert_eq!(copy.timeout(), Duration::from_secs(3));
+        let original_bytes = request_bytes(request).await;
+        assert!(original_bytes.ends_with(br#"{"name":"device"}"#));
+        assert_eq!(request_bytes(copy).await, original_bytes);
+
+        let mut request = factory.post("upload").unwrap();
+        request.set_streaming_body(hyper::Body::from("streamed"));
+        assert!(request.try_clone().is_none());
     }
-}
 
-impl From<Request> for RestRequest {
-    fn from(request: Request) -> Self {
-        Self {
-            request,
-            timeout: DEFAULT_TIMEOUT,
-            auth: None,
+    #[test]
+    fn test_account_token_validation() {
+        for token in &[
+            "1234567890123456",
+            "token",
+            "eyJhbGciOi.J9-_~+/",
+            "dG9rZW4=",
+        ] {
+            assert_eq!(AccountToken::new(*token).unwrap().as_str(), *token);
+        }
+        let too_long = "a".repeat(MAX_ACCOUNT_TOKEN_LENGTH + 1);
+        for token in &[
+            "",
+            "==",
+            "1234\r\nX-Injected: 1",
+            "1234\n",
+            "12 34",
+            "12\t34",
+            "tökén",
+            "=abc",
+            &too_long,
+        ] {
+            assert!(
+                matches!(AccountToken::new(*token), Err(Error::InvalidAccountToken)),
+                "{:?} was accepted",
+                token
+            );
         }
+
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider("127.0.0.1:443".parse().unwrap())),
+            None,
+        );
+        assert!(matches!(
+            factory.set_default_auth(Some("1234\n".to_owned())),
+            Err(Error::InvalidAccountToken)
+        ));
+        assert_eq!(
+            format!("{:?}", AccountToken::new("secret").unwrap()),
+            "AccountToken(..)"
+        );
     }
-}
 
-#[derive(serde::Deserialize)]
-pub struct ErrorResponse {
-    pub code: String,
-}
+    #[test]
+    fn test_auth_schemes() {
+        let address: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+        let auth_header = |request: RestRequest| {
+            request
+                .into_request()
+                .headers()
+                .get(header::AUTHORIZATION)
+                .cloned()
+        };
 
-#[derive(Clone)]
-pub struct RequestFactory {
-    hostname: String,
-    address_provider: Box<dyn AddressProvider>,
-    path_prefix: Option<String>,
-    pub timeout: Duration,
-}
+        let mut request = factory.get("resource").unwrap();
+        request
+            .set_auth(Some(AccountToken::new("secret").unwrap()))
+            .unwrap();
+        assert_eq!(auth_header(request).unwrap(), "Token secret");
 
+        let mut request = factory.get("resource").unwrap();
+        request
+            .set_auth_with_scheme(
+                AuthScheme::Token,
+                Some(AccountToken::new("secret").unwrap()),
+            )
+            .unwrap();
+        assert_eq!(auth_header(request).unwrap(), "Token secret");
 
-impl RequestFactory {
-    pub fn new(
-        hostname: String,
-        address_provider: Box<dyn AddressProvider>,
-        path_prefix: Option<String>,
-    ) -> Self {
-        Self {
-            hostname,
-            address_provider,
-            path_prefix,
-            timeout: DEFAULT_TIMEOUT,
-        }
-    }
+        let mut request = factory.get("resource").unwrap();
+        request
+            .set_auth_with_scheme(
+                AuthScheme::Bearer,
+                Some(AccountToken::new("secret").unwrap()),
+            )
+            .unwrap();
+        assert_eq!(auth_header(request).unwrap(), "Bearer secret");
 
-    pub fn request(&self, path: &str, method: Method) -> Result<RestRequest> {
-        self.hyper_request(path, method)
-            .map(RestRequest::from)
-            .map(|req| self.set_request_timeout(req))
+        let mut request = factory.get("resource").unwrap();
+        request
+            .set_auth_with_scheme(
+                AuthScheme::Bearer,
+                Some(AccountToken::new("secret").unwrap()),
+            )
+            .unwrap();
+        request
+            .set_auth_with_scheme(AuthScheme::Bearer, None)
+            .unwrap();
+        assert!(auth_header(request).is_none());
+
+        assert!(matches!(
+            AccountToken::new("invalid\n"),
+            Err(Error::InvalidAccountToken)
+        ));
     }
 
-    pub fn get(&self, path: &str) -> Result<RestRequest> {
-        self.hyper_request(path, Method::GET)
-            .map(RestRequest::from)
-            .map(|req| self.set_request_timeout(req))
+    #[test]
+    fn test_custom_headers() {
+        let address: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("default"));
+        let factory = RequestFactory::with_headers(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+            headers,
+        )
+        .unwrap();
+
+        let mut request = factory.get("resource").unwrap();
+        request
+            .set_header(header::IF_NONE_MATCH, HeaderValue::from_static("\"etag\""))
+            .unwrap();
+        request
+            .header_str(HeaderName::from_static("x-correlation-id"), "1234")
+            .unwrap();
+        request.header_str(header::USER_AGENT, "custom").unwrap();
+        request.header_str(header::AUTHORIZATION, "custom").unwrap();
+        request
+            .set_auth(Some(AccountToken::new("token").unwrap()))
+            .unwrap();
+
+        let request = request.into_request();
+        let headers = request.headers();
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"etag\"");
+        assert_eq!(headers["x-correlation-id"], "1234");
+        assert_eq!(headers.get_all(header::USER_AGENT).iter().count(), 1);
+        assert_eq!(headers[header::USER_AGENT], "custom");
+        assert_eq!(headers[header::AUTHORIZATION], "Token token");
+        assert_eq!(headers[header::HOST], "localhost");
+
+        let mut request = factory.get("resource").unwrap();
+        let result = request.header_str(header::USER_AGENT, "invalid\n");
+        assert!(matches!(result, Err(Error::InvalidHeaderError(_))));
     }
 
-    pub fn post(&self, path: &str) -> Result<RestRequest> {
-        self.hyper_request(path, Method::POST)
-            .map(RestRequest::from)
-            .map(|req| self.set_request_timeout(req))
+    /// Returns the request line and the `Authorization` header of a raw request.
+    fn path_and_auth(request: &[u8]) -> (String, String) {
+        let request = String::from_utf8_lossy(request).to_lowercase();
+        let path = request
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_owned();
+        let auth = request
+            .lines()
+            .find_map(|line| line.strip_prefix("authorization: token "))
+            .unwrap_or("none")
+            .to_owned();
+        (path, auth)
     }
 
-    pub fn post_json<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<RestRequest> {
-        let mut request = self.hyper_request(path, Method::POST)?;
+    #[tokio::test]
+    async fn test_follow_redirect() {
+        let address = spawn_server_with_handler(|request| match path_and_auth(request) {
+            (path, _) if path == "/old" => {
+                "HTTP/1.1 302 Found\r\nLocation: /new?x=1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+            }
+            (path, auth) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n\"{} {}\"",
+                path.len() + auth.len() + 3,
+                path,
+                auth
+            ),
+        })
+        .await;
+        let (mut factory, service) = new_service(address);
+        factory.set_default_auth(Some("1111".to_owned())).unwrap();
 
-        let json_body = serde_json::to_string(&body)?;
-        let body_length = json_body.as_bytes().len() as u64;
-        *request.body_mut() = json_body.into_bytes().into();
+        // Redirects are not followed by default
+        let response = service.request(factory.get("old").unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
 
-        let headers = request.headers_mut();
-        headers.insert(
-            header::CONTENT_LENGTH,
-            HeaderValue::from_str(&body_length.to_string()).map_err(Error::InvalidHeaderError)?,
-        );
-        headers.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_static("application/json"),
+        factory.set_max_redirects(1);
+        let response = service.request(factory.get("old").unwrap()).await.unwrap();
+        assert_eq!(
+            deserialize_body::<String>(response).await.unwrap(),
+            "/new?x=1 1111"
         );
-
-        Ok(RestRequest::from(request))
     }
 
-    pub fn delete(&self, path: &str) -> Result<RestRequest> {
-        self.hyper_request(path, Method::DELETE)
-            .map(RestRequest::from)
+    #[tokio::test]
+    async fn test_redirect_to_other_host_drops_auth() {
+        let (auth_tx, mut auth_rx) = mpsc::unbounded();
+        let other_address = spawn_server_with_handler(move |request| {
+            let _ = auth_tx.unbounded_send(path_and_auth(request));
+            NO_CONTENT_RESPONSE.to_owned()
+        })
+        .await;
+        let address = spawn_server_with_handler(move |_| {
+            format!(
+                "HTTP/1.1 307 Temporary Redirect\r\nLocation: https://{}/target\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                other_address
+            )
+        })
+        .await;
+        let (mut factory, service) = new_service(address);
+        factory.set_max_redirects(1);
+
+        let mut request = factory.post("resource").unwrap();
+        request
+            .set_auth(Some(AccountToken::new("secret").unwrap()))
+            .unwrap();
+        let response = service.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            auth_rx.next().await.unwrap(),
+            ("/target".to_owned(), "none".to_owned())
+        );
     }
 
-    fn hyper_request(&self, path: &str, method: Method) -> Result<Request> {
-        let uri = self.get_uri(path)?;
-        let request = http::request::Builder::new()
-            .method(method)
-            .uri(uri)
-            .header(header::ACCEPT, HeaderValue::from_static("application/json"))
-            .header(header::HOST, self.hostname.clone());
+    #[tokio::test]
+    async fn test_too_many_redirects() {
+        let address = spawn_server(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /loop\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (mut factory, service) = new_service(address);
+        factory.set_max_redirects(2);
 
-        request.body(hyper::Body::empty()).map_err(Error::HttpError)
-    }
+        let result = service.request(factory.get("loop").unwrap()).await;
+        assert!(matches!(result, Err(Error::TooManyRedirects(2))));
 
-    fn get_uri(&self, path: &str) -> Result<Uri> {
-        let host = self.address_provider.get_address();
-        let prefix = self.path_prefix.as_ref().map(AsRef::as_ref).unwrap_or("");
-        let uri = format!("https://{}/{}{}", host, prefix, path);
-        hyper::Uri::from_str(&uri).map_err(Error::UriError)
+        // Every followed redirect counts as an attempt
+        let mut request = factory.get("loop").unwrap();
+        request.set_max_redirects(5);
+        request.set_max_total_attempts(3);
+        let result = service.request(request).await;
+        assert!(matches!(
+            result,
+            Err(Error::AttemptLimitExceeded(3, error))
+                if matches!(&*error, Error::Redirect(StatusCode::MOVED_PERMANENTLY, location) if location == "/loop")
+        ));
     }
 
-    fn set_request_timeout(&self, mut request: RestRequest) -> RestRequest {
-        request.timeout = self.timeout;
-        request
+    #[tokio::test]
+    async fn test_attempt_limit_spans_redirects_and_retries() {
+        // Every request to `/start` is redirected to `/next`, which resets the connection
+        let round_trips = Arc::new(AtomicUsize::new(0));
+        let server_round_trips = round_trips.clone();
+        let address = spawn_server_with_handler(move |request| {
+            server_round_trips.fetch_add(1, Ordering::SeqCst);
+            if request.starts_with(b"GET /start ") {
+                "HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+            } else {
+                String::new()
+            }
+        })
+        .await;
+        let (mut factory, service) = new_service(address);
+        factory.set_max_redirects(10);
+
+        let mut request = factory.get("start").unwrap();
+        request.set_max_total_attempts(5);
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            ..fast_retry_policy()
+        };
+        let result = service.request_with_retry(request, policy).await;
+        // The fifth round trip is redirected, and the redirect is not followed
+        assert!(
+            matches!(
+                &result,
+                Err(Error::AttemptLimitExceeded(5, error))
+                    if matches!(&**error, Error::Redirect(StatusCode::FOUND, location) if location == "/next")
+            ),
+            "{:?}",
+            result.map(|response| response.status())
+        );
+        assert_eq!(round_trips.load(Ordering::SeqCst), 5);
     }
-}
 
-pub trait AddressProvider: Send + Sync {
-    /// Must return a string that represents either a host or a host with port
-    fn get_address(&self) -> String;
-    fn clone_box(&self) -> Box<dyn AddressProvider>;
-}
+    /// A request ID, and the status or the error of the request once it has ended.
+    type ObservedEvent = (u64, Option<std::result::Result<StatusCode, String>>);
 
-impl Clone for Box<dyn AddressProvider> {
-    fn clone(&self) -> Self {
-        self.clone_box()
+    #[derive(Default)]
+    struct RecordingObserver(Mutex<Vec<ObservedEvent>>);
+
+    impl RequestObserver for RecordingObserver {
+        fn on_request_start(&self, id: u64, _method: &Method, _uri: &Uri) {
+            self.0.lock().unwrap().push((id, None));
+        }
+
+        fn on_request_end(
+            &self,
+            id: u64,
+            _duration: Duration,
+            result: std::result::Result<StatusCode, &Error>,
+        ) {
+            let result = result.map_err(|error| error.to_string());
+            self.0.lock().unwrap().push((id, Some(result)));
+        }
     }
-}
 
-impl AddressProvider for IpAddr {
-    /// Must return a string that represents either a host or a host with port
-    fn get_address(&self) -> String {
-        self.to_string()
+    #[tokio::test]
+    async fn test_request_observer() {
+        let address = spawn_server_with_handler(|request| {
+            if request.starts_with(b"GET /missing") {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            } else {
+                NO_CONTENT_RESPONSE.to_owned()
+            }
+        })
+        .await;
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let observer = Arc::new(RecordingObserver::default());
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .observer(Some(observer.clone()))
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
+        let factory = RequestFactory::new(
+            "localhost".to_string(),
+            Box::new(TestAddressProvider(address)),
+            None,
+        );
+
+        let response = service_handle.request(factory.get("found").unwrap()).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = service_handle
+            .request(factory.get("missing").unwrap())
+            .await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+        let request = RestRequest::get("http://127.0.0.1:1/").unwrap();
+        assert!(service_handle.request(request).await.is_err());
+        service_handle.wait_until_idle().await;
+
+        let events = observer.0.lock().unwrap();
+        assert_eq!(
+            events[..4],
+            [
+                (0, None),
+                (0, Some(Ok(StatusCode::NO_CONTENT))),
+                (1, None),
+                (1, Some(Ok(StatusCode::NOT_FOUND))),
+            ]
+        );
+        assert_eq!(events[4], (2, None));
+        assert!(matches!(events[5], (2, Some(Err(_)))));
+        assert_eq!(events.len(), 6);
     }
 
-    fn clone_box(&self) -> Box<dyn AddressProvider> {
-        Box::new(*self)
+    /// Records the fields of every span, by span ID.
+    #[cfg(feature = "request-tracing")]
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<BTreeMap<String, String>>>>,
     }
-}
 
+    #[cfg(feature = "request-tracing")]
+    struct FieldVisitor<'a>(&'a mut BTreeMap<String, String>);
 
-pub fn get_request<T: serde::de::DeserializeOwned>(
-    factory: &RequestFactory,
-    service: RequestServiceHandle,
-    uri: &str,
-    auth: Option<String>,
-    expected_status: hyper::StatusCode,
-) -> impl Future<Output = Result<Response>> {
-    let request = factory.get(uri);
-    async move {
-        let mut request = request?;
-        request.set_auth(auth)?;
-        let response = service.request(request).await?;
-        parse_rest_response(response, expected_status).await
+    #[cfg(feature = "request-tracing")]
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_owned(), format!("{:?}", value));
+        }
     }
-}
 
-pub fn send_request(
-    factory: &RequestFactory,
-    service: RequestServiceHandle,
-    uri: &str,
-    method: Method,
-    auth: Option<String>,
-    expected_status: hyper::StatusCode,
-) -> impl Future<Output = Result<Response>> {
-    let request = factory.request(uri, method);
+    #[cfg(feature = "request-tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
 
-    async move {
-        let mut request = request?;
-        request.set_auth(auth)?;
-        let response = service.request(request).await?;
-        parse_rest_response(response, expected_status).await
-    }
-}
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = BTreeMap::new();
+            fields.insert("name".to_owned(), span.metadata().name().to_owned());
+            span.record(&mut FieldVisitor(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
 
-pub fn post_request_with_json<B: serde::Serialize>(
-    factory: &RequestFactory,
-    service: RequestServiceHandle,
-    uri: &str,
-    body: &B,
-    auth: Option<String>,
-    expected_status: hyper::StatusCode,
-) -> impl Future<Output = Result<Response>> {
-    let request = factory.post_json(uri, body);
-    async move {
-        let mut request = request?;
-        request.set_auth(auth)?;
-        let response = service.request(request).await?;
-        parse_rest_response(response, expected_status).await
-    }
-}
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut FieldVisitor(&mut spans[span.into_u64() as usize - 1]));
+        }
 
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
 
-pub async fn deserialize_body<T: serde::de::DeserializeOwned>(mut response: Response) -> Result<T> {
-    let body_length: usize = response
-        .headers()
-        .get(header::CONTENT_LENGTH)
-        .and_then(|header_value| header_value.to_str().ok())
-        .and_then(|length| length.parse::<usize>().ok())
-        .unwrap_or(0);
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, _span: &tracing::span::Id) {}
 
-    let mut body: Vec<u8> = Vec::with_capacity(body_length);
-    while let Some(chunk) = response.body_mut().next().await {
-        body.extend(&chunk?);
+        fn exit(&self, _span: &tracing::span::Id) {}
     }
 
-    serde_json::from_slice(&body).map_err(Error::DeserializeError)
-}
+    #[cfg(feature = "request-tracing")]
+    #[tokio::test]
+    async fn test_request_span() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (factory, service) = new_service(address);
 
-pub async fn parse_rest_response(
-    response: Response,
-    expected_status: hyper::StatusCode,
-) -> Result<Response> {
-    let status = response.status();
-    if status != expected_status {
-        return handle_error_response(response).await;
-    }
+        let response = service.request(factory.get("resource").unwrap()).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let request = RestRequest::get("http://127.0.0.1:1/unreachable").unwrap();
+        assert!(service.request(request).await.is_err());
+        service.wait_until_idle().await;
 
-    Ok(response)
-}
+        let spans = recorder.spans.lock().unwrap();
+        let request_spans: Vec<_> = spans
+            .iter()
+            .filter(|span| span["name"] == "request")
+            .collect();
+        assert_eq!(request_spans.len(), 2);
+        assert_eq!(request_spans[0]["id"], "0");
+        assert_eq!(request_spans[0]["method"], "GET");
+        assert_eq!(request_spans[0]["host"], "\"localhost\"");
+        assert_eq!(
+            request_spans[0]["uri"],
+            format!("https://{}/resource", address)
+        );
+        assert_eq!(request_spans[0]["status"], "204");
+        assert!(!request_spans[0].contains_key("error"));
+        assert_eq!(request_spans[1]["id"], "1");
+        assert_eq!(request_spans[1]["host"], "\"127.0.0.1:1\"");
+        assert!(request_spans[1].contains_key("error"));
+        assert!(!request_spans[1].contains_key("status"));
+    }
 
+    #[tokio::test]
+    async fn test_handle_config_reports_factory_timeout() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let (mut factory, service) = new_service(address);
+        factory.timeout = Duration::from_secs(30);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let handle = MullvadRestHandle::new(service, factory, address_cache, fetcher_config());
 
-pub async fn handle_error_response<T>(response: Response) -> Result<T> {
-    let error_message = match response.status() {
-        hyper::StatusCode::NOT_FOUND => "Not found",
-        hyper::StatusCode::METHOD_NOT_ALLOWED => "Method not allowed",
-        status => {
-            let err: ErrorResponse = deserialize_body(response).await?;
+        let config = handle.config().await.unwrap();
+        assert_eq!(config.default_timeout, Some(Duration::from_secs(30)));
+        assert!(config.api_address_fetch_interval.is_some());
 
-            return Err(Error::ApiError(status, err.code));
-        }
-    };
-    Err(Error::ApiError(response.status(), error_message.to_owned()))
-}
+        let config = handle.service().config().await.unwrap();
+        assert_eq!(config.default_timeout, None);
+        assert_eq!(config.api_address_fetch_interval, None);
+        handle.stop_api_address_fetcher();
+    }
 
-#[derive(Clone)]
-pub struct MullvadRestHandle {
-    pub(crate) service: RequestServiceHandle,
-    pub factory: RequestFactory,
-}
+    #[tokio::test]
+    async fn test_from_request_with_timeout() {
+        let address = spawn_server(NO_CONTENT_RESPONSE).await;
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        let timer = Arc::new(RecordingTimer::default());
+        let service = RequestServiceBuilder::new(Handle::current(), address_cache)
+            .timer(timer.clone())
+            .build(connector);
+        let service_handle = service.handle();
+        tokio::spawn(service.into_future());
 
-impl MullvadRestHandle {
-    pub(crate) fn new(
-        service: RequestServiceHandle,
-        factory: RequestFactory,
-        address_cache: AddressCache,
-    ) -> Self {
-        let handle = Self { service, factory };
-        handle.spawn_api_address_fetcher(address_cache);
+        let timeout = Duration::from_secs(42);
+        let hyper_request = http::request::Builder::new()
+            .method(Method::GET)
+            .uri(format!("http://{}/one-off", address))
+            .body(hyper::Body::empty())
+            .unwrap();
+        let request = RestRequest::from_request_with_timeout(hyper_request, timeout);
+        assert_eq!(request.timeout(), timeout);
 
-        handle
+        let response = service_handle.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(*timer.0.lock().unwrap(), vec![timeout]);
     }
 
-    fn spawn_api_address_fetcher(&self, address_cache: AddressCache) {
-        let handle = self.clone();
-
-        self.service.spawn(async move {
-            // always start the fetch after 15 minutes
-            let api_proxy = crate::ApiProxy { handle };
-            let mut next_check = Instant::now() + API_IP_CHECK_DELAY;
+    #[test]
+    fn test_join_path() {
+        for (prefix, path) in &[
+            ("app", "/v1/accounts"),
+            ("app/", "/v1/accounts"),
+            ("app", "v1/accounts"),
+            ("app/", "v1/accounts"),
+            ("/app/", "/v1/accounts"),
+        ] {
+            assert_eq!(join_path(prefix, path).unwrap(), "/app/v1/accounts");
+        }
+        assert_eq!(join_path("", "/v1/accounts").unwrap(), "/v1/accounts");
+        assert_eq!(join_path("", "v1/accounts").unwrap(), "/v1/accounts");
+        assert_eq!(join_path("/", "v1/").unwrap(), "/v1/");
+        assert_eq!(join_path("app", "").unwrap(), "/app/");
 
-            let next_error_check = || Instant::now() + API_IP_CHECK_ERROR_INTERVAL;
-            let next_regular_check = || Instant::now() + API_IP_CHECK_INTERVAL;
+        assert!(join_path("https://example.com/app", "v1").is_err());
+        assert!(join_path("app", "https://example.com/v1").is_err());
+        assert!(join_path("", "//example.com/v1").is_err());
+    }
 
-            let mut interval = tokio::time::interval_at(next_check.into(), TIMER_CHECK_INTERVAL);
+    #[test]
+    fn test_factory_joins_prefix_and_path() {
+        let factory = RequestFactory::new(
+            "api.mullvad.net".to_owned(),
+            Box::new(TestAddressProvider("127.0.0.1:1".parse().unwrap())),
+            Some("app/".to_owned()),
+        );
+        let request = factory.get("/v1/accounts").unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            "https://127.0.0.1:1/app/v1/accounts"
+        );
+        assert!(factory.get("http://example.com/v1/accounts").is_err());
+    }
 
-            loop {
-                interval.tick().await;
-                if next_check < Instant::now() {
-                    match api_proxy.clone().get_api_addrs().await {
-                        Ok(new_addrs) => {
-                            log::debug!("Fetched new API addresses {:?}, will fetch again in {} hours", new_addrs, API_IP_CHECK_INTERVAL.as_secs() / ( 60 * 60 ));
-                            if let Err(err) = address_cache.set_addresses(new_addrs).await {
-                                log::error!("Failed to save newly updated API addresses: {}", err);
-                            }
-                            next_check = next_regular_check();
-                        }
-                        Err(err) => {
-                            log::error!("Failed to fetch new API addresses: {}, will retry again in {} seconds", err, API_IP_CHECK_ERROR_INTERVAL.as_secs());
-                            next_check = next_error_check();
-                        }
-                    }
-                }
-            }
-        });
+    #[test]
+    fn test_factory_uri_from_address() {
+        let addresses: &[(Box<dyn AddressProvider>, &str)] = &[
+            (
+                Box::new(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))),
+                "https://192.0.2.1/app/v1/relays",
+            ),
+            (
+                Box::new("2001:db8::1".parse::<IpAddr>().unwrap()),
+                "https://[2001:db8::1]/app/v1/relays",
+            ),
+            (
+                Box::new(TestAddressProvider("192.0.2.1:8443".parse().unwrap())),
+                "https://192.0.2.1:8443/app/v1/relays",
+            ),
+            (
+                Box::new(TestAddressProvider("[2001:db8::1]:8443".parse().unwrap())),
+                "https://[2001:db8::1]:8443/app/v1/relays",
+            ),
+        ];
+        for (provider, expected) in addresses {
+            let factory = RequestFactory::new(
+                "api.mullvad.net".to_owned(),
+                provider.clone(),
+                Some("app".to_owned()),
+            );
+            let request = factory.get("v1/relays").unwrap();
+            assert_eq!(request.uri().to_string(), *expected);
+        }
     }
 
-    pub fn service(&self) -> RequestServiceHandle {
-        self.service.clone()
+    #[test]
+    fn test_ipv6_request_socket_addr() {
+        let address: IpAddr = "2001:db8::1".parse().unwrap();
+        let factory = RequestFactory::new("api.mullvad.net".to_owned(), Box::new(address), None);
+        let request = factory.get("v1/relays").unwrap();
+        assert_eq!(
+            get_request_socket_addr(&request.request),
+            Some(SocketAddr::new(address, 443))
+        );
+
+        let address: SocketAddr = "[2001:db8::1]:8443".parse().unwrap();
+        let uri: Uri = format!("https://{}/", address).parse().unwrap();
+        assert_eq!(get_uri_socket_addr(&uri), Some(address));
     }
 
-    pub fn factory(&self) -> &RequestFactory {
-        &self.factory
+    #[tokio::test]
+    async fn test_request_json() {
+        let address = spawn_server(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"id\": \"abc\"}",
+        )
+        .await;
+        let (factory, service) = new_service(address);
+        let value: serde_json::Value = service
+            .request_json(factory.get("resource").unwrap(), StatusCode::OK)
+            .await
+            .unwrap();
+        assert_eq!(value, serde_json::json!({ "id": "abc" }));
     }
-}
 
-fn flatten_result<T, E>(
-    result: std::result::Result<std::result::Result<T, E>, E>,
-) -> std::result::Result<T, E> {
-    match result {
-        Ok(value) => value,
-        Err(err) => Err(err),
+    #[tokio::test]
+    async fn test_request_json_unexpected_status() {
+        let body = r#"{"code": "INVALID_ACCOUNT"}"#;
+        let address = spawn_server_with_handler(move |_| {
+            format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .await;
+        let (factory, service) = new_service(address);
+        let result = service
+            .request_json::<serde_json::Value>(factory.get("resource").unwrap(), StatusCode::OK)
+            .await;
+        assert!(
+            matches!(
+                &result,
+                Err(Error::ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    code: ApiErrorCode::InvalidAccount,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
     }
 }
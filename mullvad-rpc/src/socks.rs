@@ -0,0 +1,358 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USERNAME_PASSWORD: u8 = 2;
+
+const COMMAND_CONNECT: u8 = 1;
+
+const ADDRESS_TYPE_IPV4: u8 = 1;
+const ADDRESS_TYPE_DOMAIN: u8 = 3;
+const ADDRESS_TYPE_IPV6: u8 = 4;
+
+/// Errors from connecting through a SOCKS5 proxy.
+#[derive(err_derive::Error, Debug)]
+pub enum Socks5Error {
+    /// The username or the password is longer than 255 bytes.
+    #[error(display = "SOCKS5 credentials can't be longer than 255 bytes")]
+    CredentialsTooLong,
+
+    /// The host to connect to is longer than 255 bytes.
+    #[error(display = "The host name is too long to be sent to a SOCKS5 proxy")]
+    HostTooLong,
+
+    /// The proxy responded with something other than a SOCKS5 response.
+    #[error(display = "The proxy is not a SOCKS5 proxy")]
+    InvalidResponse,
+
+    /// The proxy doesn't accept the authentication method.
+    #[error(display = "The SOCKS5 proxy doesn't accept the authentication method")]
+    NoAcceptableMethod,
+
+    /// The proxy rejected the credentials.
+    #[error(display = "The SOCKS5 proxy rejected the credentials")]
+    AuthenticationFailed,
+
+    /// The proxy failed to connect to the target. Contains the reply code of the proxy.
+    #[error(display = "The SOCKS5 proxy failed to connect, reply code {}", _0)]
+    ConnectFailed(u8),
+}
+
+/// A SOCKS5 proxy that connections are tunneled through, as described by RFC 1928.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    address: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    /// A proxy at `address` that doesn't require authentication.
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            credentials: None,
+        }
+    }
+
+    /// A proxy at `address` that requires username and password authentication, as described by
+    /// RFC 1929. Fails with `Socks5Error::CredentialsTooLong` if the username or the password is
+    /// longer than 255 bytes.
+    pub fn with_credentials(
+        address: SocketAddr,
+        username: String,
+        password: String,
+    ) -> Result<Self, Socks5Error> {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(Socks5Error::CredentialsTooLong);
+        }
+        Ok(Self {
+            address,
+            credentials: Some((username, password)),
+        })
+    }
+
+    /// Returns the address of the proxy.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Asks the proxy to connect to `host` and `port`, over `stream`, which must be connected to
+    /// the proxy. Once this returns, `stream` is tunneled to the target.
+    pub(crate) async fn handshake<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = match self.credentials {
+            Some(_) => METHOD_USERNAME_PASSWORD,
+            None => METHOD_NO_AUTH,
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        let mut response = [0u8; 2];
+        stream.read_exact(&mut response).await?;
+        if response[0] != SOCKS_VERSION {
+            return Err(socks_error(Socks5Error::InvalidResponse));
+        }
+        if response[1] != method {
+            return Err(socks_error(Socks5Error::NoAcceptableMethod));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let mut request = vec![AUTH_VERSION, username.len() as u8];
+            request.extend(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut response).await?;
+            if response[0] != AUTH_VERSION {
+                return Err(socks_error(Socks5Error::InvalidResponse));
+            }
+            if response[1] != 0 {
+                return Err(socks_error(Socks5Error::AuthenticationFailed));
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(IpAddr::V4(address)) => {
+                request.push(ADDRESS_TYPE_IPV4);
+                request.extend(&address.octets());
+            }
+            Ok(IpAddr::V6(address)) => {
+                request.push(ADDRESS_TYPE_IPV6);
+                request.extend(&address.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(socks_error(Socks5Error::HostTooLong));
+                }
+                request.push(ADDRESS_TYPE_DOMAIN);
+                request.push(host.len() as u8);
+                request.extend(host.as_bytes());
+            }
+        }
+        request.extend(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(socks_error(Socks5Error::InvalidResponse));
+        }
+        if reply[1] != 0 {
+            return Err(socks_error(Socks5Error::ConnectFailed(reply[1])));
+        }
+        // Skip the address that the proxy bound to, followed by the port
+        let bound_address_length = match reply[3] {
+            ADDRESS_TYPE_IPV4 => 4,
+            ADDRESS_TYPE_IPV6 => 16,
+            ADDRESS_TYPE_DOMAIN => usize::from(stream.read_u8().await?),
+            _ => return Err(socks_error(Socks5Error::InvalidResponse)),
+        };
+        let mut bound_address = vec![0u8; bound_address_length + 2];
+        stream.read_exact(&mut bound_address).await?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Socks5Proxy {
+    /// Doesn't reveal the credentials.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Socks5Proxy")
+            .field("address", &self.address)
+            .field("authenticated", &self.credentials.is_some())
+            .finish()
+    }
+}
+
+fn socks_error(error: Socks5Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        address_cache::AddressCache,
+        https_client_with_sni::{
+            test::{server_config, spawn_tls_server, trusting_connector},
+            HttpsConnectorWithSni,
+        },
+        rest::{test::spawn_service, Error, RequestServiceHandle, RestRequest},
+    };
+    use futures::{channel::mpsc, StreamExt};
+    use hyper::StatusCode;
+    use std::net::Ipv4Addr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Spawns a TLS server for `localhost` that answers every request with `204 No Content`, and
+    /// returns its address and a connector that trusts it.
+    async fn spawn_https_server() -> (SocketAddr, HttpsConnectorWithSni) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let address = spawn_tls_server(server_config(&certificate), |mut stream| async move {
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await;
+            let _ = stream.shutdown().await;
+        })
+        .await;
+        (address, trusting_connector(&certificate))
+    }
+
+    /// Spawns a SOCKS5 server that only connects to IPv4 addresses, and returns its address and
+    /// a stream of the addresses that it connected to. If `credentials` are given, the server
+    /// requires them.
+    async fn spawn_socks_server(
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<SocketAddr>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (target_tx, target_rx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let target_tx = target_tx.clone();
+                tokio::spawn(async move {
+                    let _ = serve_socks_client(stream, credentials, target_tx).await;
+                });
+            }
+        });
+        (address, target_rx)
+    }
+
+    async fn serve_socks_client(
+        mut client: TcpStream,
+        credentials: Option<(&'static str, &'static str)>,
+        target_tx: mpsc::UnboundedSender<SocketAddr>,
+    ) -> io::Result<()> {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; usize::from(header[1])];
+        client.read_exact(&mut methods).await?;
+        let method = match credentials {
+            Some(_) => METHOD_USERNAME_PASSWORD,
+            None => METHOD_NO_AUTH,
+        };
+        if !methods.contains(&method) {
+            return client.write_all(&[SOCKS_VERSION, 0xff]).await;
+        }
+        client.write_all(&[SOCKS_VERSION, method]).await?;
+
+        if let Some((username, password)) = credentials {
+            let mut read_field = Vec::new();
+            client.read_u8().await?;
+            read_field.resize(usize::from(client.read_u8().await?), 0);
+            client.read_exact(&mut read_field).await?;
+            let given_username = read_field.clone();
+            read_field.resize(usize::from(client.read_u8().await?), 0);
+            client.read_exact(&mut read_field).await?;
+            let is_valid =
+                given_username == username.as_bytes() && read_field == password.as_bytes();
+            client
+                .write_all(&[AUTH_VERSION, if is_valid { 0 } else { 1 }])
+                .await?;
+            if !is_valid {
+                return Ok(());
+            }
+        }
+
+        let mut request = [0u8; 10];
+        client.read_exact(&mut request).await?;
+        assert_eq!(
+            request[..4],
+            [SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_IPV4]
+        );
+        let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+        let target_address =
+            SocketAddr::new(ip.into(), u16::from_be_bytes([request[8], request[9]]));
+        let target = TcpStream::connect(target_address).await?;
+        let _ = target_tx.unbounded_send(target_address);
+        client
+            .write_all(&[SOCKS_VERSION, 0, 0, ADDRESS_TYPE_IPV4, 0, 0, 0, 0, 0, 0])
+            .await?;
+
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut target_read, mut target_write) = tokio::io::split(target);
+        futures::future::select(
+            Box::pin(tokio::io::copy(&mut client_read, &mut target_write)),
+            Box::pin(tokio::io::copy(&mut target_read, &mut client_write)),
+        )
+        .await;
+        Ok(())
+    }
+
+    fn new_service(
+        connector: HttpsConnectorWithSni,
+        address: SocketAddr,
+    ) -> (RequestServiceHandle, AddressCache) {
+        let address_cache = AddressCache::new(vec![address], None).unwrap();
+        (
+            spawn_service(connector, address_cache.clone()),
+            address_cache,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_request_through_proxy() {
+        let (address, connector) = spawn_https_server().await;
+        let (proxy_address, mut targets) = spawn_socks_server(None).await;
+        connector.set_socks5_proxy(Some(Socks5Proxy::new(proxy_address)));
+        let (service, _) = new_service(connector.clone(), address);
+
+        let uri = format!("https://{}/", address);
+        let response = service
+            .request(RestRequest::get(&uri).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(targets.next().await, Some(address));
+
+        // Switching the proxy affects the service that was already created
+        connector.set_socks5_proxy(None);
+        let response = service
+            .request(RestRequest::get(&uri).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(targets.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_through_authenticated_proxy() {
+        let (address, connector) = spawn_https_server().await;
+        let (proxy_address, mut targets) = spawn_socks_server(Some(("user", "secret"))).await;
+        let (service, address_cache) = new_service(connector.clone(), address);
+        let uri = format!("https://{}/", address);
+
+        connector.set_socks5_proxy(Some(
+            Socks5Proxy::with_credentials(proxy_address, "user".to_owned(), "secret".to_owned())
+                .unwrap(),
+        ));
+        let response = service
+            .request(RestRequest::get(&uri).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(targets.next().await, Some(address));
+
+        // A failure to connect through the proxy is registered for the API address
+        connector.set_socks5_proxy(Some(
+            Socks5Proxy::with_credentials(proxy_address, "user".to_owned(), "wrong".to_owned())
+                .unwrap(),
+        ));
+        let result = service.request(RestRequest::get(&uri).unwrap()).await;
+        assert!(matches!(result, Err(Error::HyperError(_))));
+        assert!(address_cache.has_recently_failed(address));
+
+        assert!(matches!(
+            Socks5Proxy::with_credentials(proxy_address, "a".repeat(256), String::new()),
+            Err(Socks5Error::CredentialsTooLong)
+        ));
+    }
+}
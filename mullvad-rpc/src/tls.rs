@@ -0,0 +1,199 @@
+//! Pinning of the public keys of API certificates.
+
+use crate::https_client_with_sni::HttpsConnectorWithSni;
+use std::fmt;
+
+/// A pin is not a base64 encoded SHA-256 digest.
+#[derive(err_derive::Error, Debug)]
+#[error(display = "Invalid SPKI pin: {}", _0)]
+pub struct InvalidPinError(pub String);
+
+/// None of the certificates presented by the server match the pinned public keys.
+#[derive(err_derive::Error, Debug)]
+#[error(display = "The server certificate doesn't match any of the pinned public keys")]
+pub struct PinVerificationError;
+
+/// The SHA-256 digest of the DER encoded `SubjectPublicKeyInfo` of a certificate, as used by
+/// HTTP Public Key Pinning (RFC 7469). Pinning the public key rather than the whole certificate
+/// lets the certificate be renewed with the same key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpkiPin([u8; 32]);
+
+impl SpkiPin {
+    /// Parses a base64 encoded digest, which is the format of the `pin-sha256` directive.
+    pub fn from_base64(pin: &str) -> Result<Self, InvalidPinError> {
+        let digest = base64::decode(pin).map_err(|_| InvalidPinError(pin.to_owned()))?;
+        if digest.len() != 32 {
+            return Err(InvalidPinError(pin.to_owned()));
+        }
+        let mut pin = [0u8; 32];
+        pin.copy_from_slice(&digest);
+        Ok(Self(pin))
+    }
+
+    /// Returns the pin of a DER encoded X.509 certificate, or `None` if the certificate can't be
+    /// parsed.
+    pub fn from_certificate(certificate: &[u8]) -> Option<Self> {
+        let spki = subject_public_key_info(certificate)?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+        let mut pin = [0u8; 32];
+        pin.copy_from_slice(digest.as_ref());
+        Some(Self(pin))
+    }
+
+    /// Returns the pin encoded as base64.
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+}
+
+impl fmt::Debug for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SpkiPin({})", self.to_base64())
+    }
+}
+
+/// Returns a connector that only completes connections to servers whose certificate chain
+/// contains a certificate with one of the public keys in `pins`, in addition to the regular
+/// certificate and hostname verification. Connections to other servers fail with a
+/// `PinVerificationError` before any request is sent, so that a compromised certificate authority
+/// can't intercept API requests.
+pub fn pinned_https_connector(pins: Vec<SpkiPin>) -> HttpsConnectorWithSni {
+    let mut connector = HttpsConnectorWithSni::new();
+    connector.set_spki_pins(Some(pins));
+    connector
+}
+
+/// Returns whether any of the DER encoded `certificates` matches one of `pins`.
+pub(crate) fn matches_pins<'a>(
+    pins: &[SpkiPin],
+    mut certificates: impl Iterator<Item = &'a [u8]>,
+) -> bool {
+    certificates.any(|certificate| {
+        SpkiPin::from_certificate(certificate)
+            .map(|pin| pins.contains(&pin))
+            .unwrap_or(false)
+    })
+}
+
+/// Returns the DER encoded `SubjectPublicKeyInfo` of an X.509 certificate.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8 = 0x02;
+    const VERSION: u8 = 0xa0;
+
+    let (certificate, _) = read_der(certificate, SEQUENCE)?;
+    let (mut tbs_certificate, _) = read_der(certificate.contents, SEQUENCE)?;
+    if tbs_certificate.contents.first() == Some(&VERSION) {
+        tbs_certificate.contents = read_der(tbs_certificate.contents, VERSION)?.1;
+    }
+    let (_serial_number, rest) = read_der(tbs_certificate.contents, INTEGER)?;
+    // The signature algorithm, issuer, validity and subject precede the public key
+    let mut rest = rest;
+    for _ in 0..4 {
+        rest = read_der(rest, SEQUENCE)?.1;
+    }
+    let (spki, _) = read_der(rest, SEQUENCE)?;
+    Some(spki.encoded)
+}
+
+/// A DER encoded value.
+struct DerValue<'a> {
+    /// The whole value, including the tag and the length.
+    encoded: &'a [u8],
+    contents: &'a [u8],
+}
+
+/// Reads a DER value with the given tag from the start of `input`, and returns it together with
+/// the remaining input.
+fn read_der(input: &[u8], tag: u8) -> Option<(DerValue<'_>, &[u8])> {
+    if *input.first()? != tag {
+        return None;
+    }
+    let first_length_byte = *input.get(1)?;
+    let (length, header_length) = if first_length_byte < 0x80 {
+        (usize::from(first_length_byte), 2)
+    } else {
+        let length_bytes = usize::from(first_length_byte & 0x7f);
+        if length_bytes == 0 || length_bytes > 4 {
+            return None;
+        }
+        let length = input
+            .get(2..2 + length_bytes)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | usize::from(*byte));
+        (length, 2 + length_bytes)
+    };
+    let end = header_length.checked_add(length)?;
+    let value = DerValue {
+        encoded: input.get(..end)?,
+        contents: input.get(header_length..end)?,
+    };
+    Some((value, &input[end..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::https_client_with_sni::test::{server_config, spawn_tls_server, trusting_connector};
+    use hyper::{service::Service, Uri};
+
+    /// Spawns a TLS server for `localhost` and returns its URI and a connector that trusts it.
+    async fn spawn_server(certificate: &rcgen::Certificate) -> (Uri, HttpsConnectorWithSni) {
+        let address = spawn_tls_server(server_config(certificate), |_stream| async {}).await;
+        let uri = format!("https://{}/", address).parse().unwrap();
+        (uri, trusting_connector(certificate))
+    }
+
+    #[test]
+    fn test_pin_from_certificate() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            &certificate.get_key_pair().public_key_der(),
+        );
+        let pin = SpkiPin::from_base64(&base64::encode(digest.as_ref())).unwrap();
+
+        let der = certificate.serialize_der().unwrap();
+        assert_eq!(SpkiPin::from_certificate(&der), Some(pin));
+        assert_eq!(SpkiPin::from_certificate(&der[..der.len() - 1]), None);
+
+        assert!(SpkiPin::from_base64("not base64").is_err());
+        assert!(SpkiPin::from_base64(&base64::encode([0u8; 16])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_matching_pin_is_accepted() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let (uri, mut connector) = spawn_server(&certificate).await;
+        let pin = SpkiPin::from_certificate(&certificate.serialize_der().unwrap()).unwrap();
+        let other_pin = SpkiPin::from_base64(&base64::encode([0u8; 32])).unwrap();
+
+        connector.set_spki_pins(Some(vec![other_pin, pin]));
+        assert!(connector.call(uri).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_pin_is_rejected() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let (uri, mut connector) = spawn_server(&certificate).await;
+        let other_certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let other_pin =
+            SpkiPin::from_certificate(&other_certificate.serialize_der().unwrap()).unwrap();
+
+        connector.set_spki_pins(Some(vec![other_pin]));
+        let error = match connector.call(uri).await {
+            Ok(_) => panic!("Connected to a server with an unpinned key"),
+            Err(error) => error,
+        };
+        assert!(
+            error
+                .get_ref()
+                .map(|error| error.is::<PinVerificationError>())
+                .unwrap_or(false),
+            "{}",
+            error
+        );
+    }
+}
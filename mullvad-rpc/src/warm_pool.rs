@@ -0,0 +1,335 @@
+use crate::address_cache::AddressCache;
+use futures::future;
+use hyper::{service::Service, Uri};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Warm connections older than this are closed instead of being handed out, since the server is
+/// likely to have closed them already.
+const MAX_WARM_CONNECTION_AGE: Duration = Duration::from_secs(20);
+
+/// After failing to establish a warm connection to an address, no new attempt is made for this
+/// long.
+const WARM_CONNECTION_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A connector that keeps connections to the most preferred API addresses established ahead of
+/// time, so that failing over to another address doesn't have to wait for a new handshake.
+///
+/// Every time a connection is requested, the pool is refilled with connections to the `size`
+/// addresses the address cache currently prefers, and connections to addresses that are no longer
+/// among them are closed. Addresses that are already being connected to, either by the pool or by
+/// the request itself, or that recently failed, are not connected to again.
+pub struct WarmPoolConnector<C: Service<Uri>> {
+    connector: C,
+    address_cache: AddressCache,
+    size: usize,
+    connections: Arc<Mutex<HashMap<SocketAddr, WarmConnection<C::Response>>>>,
+    pending: Arc<Mutex<HashSet<SocketAddr>>>,
+    failures: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+impl<C: Service<Uri> + Clone> Clone for WarmPoolConnector<C> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            address_cache: self.address_cache.clone(),
+            size: self.size,
+            connections: self.connections.clone(),
+            pending: self.pending.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+}
+
+struct WarmConnection<T> {
+    stream: T,
+    established: Instant,
+}
+
+impl<C> WarmPoolConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Future: Send + 'static,
+    C::Response: Send + 'static,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    /// Wraps `connector`, keeping up to `size` warm connections. The wrapped connector must always
+    /// be ready to accept connections.
+    pub fn new(connector: C, address_cache: AddressCache, size: usize) -> Self {
+        Self {
+            connector,
+            address_cache,
+            size,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Closes the warm connection to `addr`, if any. Returns the number of closed connections.
+    pub fn close_connections(&self, addr: SocketAddr) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&addr)
+            .map_or(0, |_| 1)
+    }
+
+    fn take(&self, addr: SocketAddr) -> Option<C::Response> {
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&addr)
+            .filter(|connection| connection.established.elapsed() < MAX_WARM_CONNECTION_AGE)
+            .map(|connection| connection.stream)
+    }
+
+    /// Starts connecting to every preferred address that has no warm connection yet, except for
+    /// `requested`, which the caller is already connecting to.
+    fn refill(&self, requested: Option<SocketAddr>) {
+        let wanted = self.address_cache.preferred_addresses(self.size);
+
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|addr, connection| {
+            wanted.contains(addr) && connection.established.elapsed() < MAX_WARM_CONNECTION_AGE
+        });
+
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, failed| failed.elapsed() < WARM_CONNECTION_RETRY_DELAY);
+
+        let mut pending = self.pending.lock().unwrap();
+        for addr in wanted {
+            if Some(addr) == requested
+                || connections.contains_key(&addr)
+                || failures.contains_key(&addr)
+                || !pending.insert(addr)
+            {
+                continue;
+            }
+            let uri = match format!("https://{}/", addr).parse::<Uri>() {
+                Ok(uri) => uri,
+                Err(_) => {
+                    pending.remove(&addr);
+                    continue;
+                }
+            };
+
+            let mut connector = self.connector.clone();
+            let connections = self.connections.clone();
+            let pending = self.pending.clone();
+            let failures = self.failures.clone();
+            tokio::spawn(async move {
+                let result = connector.call(uri).await;
+                pending.lock().unwrap().remove(&addr);
+                match result {
+                    Ok(stream) => {
+                        connections.lock().unwrap().insert(
+                            addr,
+                            WarmConnection {
+                                stream,
+                                established: Instant::now(),
+                            },
+                        );
+                    }
+                    Err(error) => {
+                        failures.lock().unwrap().insert(addr, Instant::now());
+                        log::debug!(
+                            "Failed to establish warm connection to {}: {}",
+                            addr,
+                            error.into()
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl<C> Service<Uri> for WarmPoolConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Future: Send + 'static,
+    C::Response: Send + 'static,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let addr = crate::rest::get_uri_socket_addr(&uri);
+        let warm_connection = addr.and_then(|addr| self.take(addr));
+        self.refill(addr);
+
+        match warm_connection {
+            Some(stream) => Box::pin(future::ready(Ok(stream))),
+            None => Box::pin(self.connector.call(uri)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    /// A connector that doesn't connect anywhere, but hands out numbered fake connections and
+    /// records the addresses it was asked to connect to.
+    #[derive(Clone)]
+    struct CountingConnector {
+        attempts: Arc<Mutex<Vec<SocketAddr>>>,
+        unreachable: Option<SocketAddr>,
+    }
+
+    impl CountingConnector {
+        fn new() -> Self {
+            Self {
+                attempts: Arc::new(Mutex::new(vec![])),
+                unreachable: None,
+            }
+        }
+
+        fn attempts(&self) -> Vec<SocketAddr> {
+            self.attempts.lock().unwrap().clone()
+        }
+    }
+
+    impl Service<Uri> for CountingConnector {
+        type Response = (SocketAddr, usize);
+        type Error = io::Error;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            let addr = crate::rest::get_uri_socket_addr(&uri).unwrap();
+            let mut attempts = self.attempts.lock().unwrap();
+            attempts.push(addr);
+            if Some(addr) == self.unreachable {
+                return future::ready(Err(io::Error::from(io::ErrorKind::ConnectionRefused)));
+            }
+            future::ready(Ok((addr, attempts.len())))
+        }
+    }
+
+    fn uri(addr: SocketAddr) -> Uri {
+        format!("https://{}/", addr).parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn warm_addresses(pool: &WarmPoolConnector<CountingConnector>) -> HashSet<SocketAddr> {
+        pool.connections.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Lets the spawned connect tasks finish.
+    async fn settle() {
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn test_warm_connection_is_handed_out() {
+        let connector = CountingConnector::new();
+        let cache =
+            AddressCache::new(vec![addr("10.0.0.1:443"), addr("10.0.0.2:443")], None).unwrap();
+        let preferred = cache.preferred_addresses(2);
+        let mut pool = WarmPoolConnector::new(connector.clone(), cache, 2);
+
+        assert_eq!(
+            pool.call(uri(preferred[0])).await.unwrap(),
+            (preferred[0], 1)
+        );
+        settle().await;
+        assert_eq!(connector.attempts(), vec![preferred[0], preferred[1]]);
+
+        assert_eq!(
+            pool.call(uri(preferred[1])).await.unwrap(),
+            (preferred[1], 2)
+        );
+        settle().await;
+        assert_eq!(
+            connector.attempts(),
+            vec![preferred[0], preferred[1], preferred[0]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_addresses_leaving_preferred_set_are_evicted() {
+        let connector = CountingConnector::new();
+        let cache = AddressCache::new(vec![addr("10.0.0.1:443")], None).unwrap();
+        let mut pool = WarmPoolConnector::new(connector, cache.clone(), 2);
+        let other = addr("10.0.0.9:443");
+
+        pool.call(uri(other)).await.unwrap();
+        settle().await;
+        assert_eq!(
+            warm_addresses(&pool),
+            cache.preferred_addresses(2).into_iter().collect()
+        );
+
+        cache
+            .set_addresses(vec![addr("10.0.0.2:443")])
+            .await
+            .unwrap();
+        pool.call(uri(other)).await.unwrap();
+        settle().await;
+        let warm = warm_addresses(&pool);
+        assert!(!warm.contains(&addr("10.0.0.1:443")));
+        assert_eq!(warm, cache.preferred_addresses(2).into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn test_pool_size_is_bounded() {
+        let connector = CountingConnector::new();
+        let cache = AddressCache::new(
+            vec![
+                addr("10.0.0.1:443"),
+                addr("10.0.0.2:443"),
+                addr("10.0.0.3:443"),
+            ],
+            None,
+        )
+        .unwrap();
+        let mut pool = WarmPoolConnector::new(connector.clone(), cache, 2);
+
+        for _ in 0..3 {
+            pool.call(uri(addr("10.0.0.9:443"))).await.unwrap();
+            settle().await;
+        }
+        assert_eq!(warm_addresses(&pool).len(), 2);
+        assert_eq!(connector.attempts().len(), 3 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_addresses_are_not_retried_on_every_call() {
+        let mut connector = CountingConnector::new();
+        let cache = AddressCache::new(vec![addr("10.0.0.1:443")], None).unwrap();
+        let unreachable = cache.preferred_addresses(1)[0];
+        connector.unreachable = Some(unreachable);
+        let mut pool = WarmPoolConnector::new(connector.clone(), cache, 1);
+
+        for _ in 0..3 {
+            pool.call(uri(addr("10.0.0.9:443"))).await.unwrap();
+            settle().await;
+        }
+        let attempts = connector.attempts();
+        assert_eq!(attempts.iter().filter(|a| **a == unreachable).count(), 1);
+        assert!(warm_addresses(&pool).is_empty());
+    }
+}
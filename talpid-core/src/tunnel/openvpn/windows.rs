@@ -1,6 +1,7 @@
 use std::{
-    ffi::CStr,
+    ffi::{CStr, CString},
     fmt, io, iter,
+    marker::PhantomData,
     os::windows::{ffi::OsStrExt, io::RawHandle},
     path::Path,
     ptr,
@@ -11,7 +12,14 @@ use widestring::U16CStr;
 use winapi::{
     shared::{
         guiddef::GUID,
+        ifdef::NET_LUID,
         minwindef::{BOOL, FARPROC, HINSTANCE, HMODULE},
+        netioapi::{
+            GetIfEntry2, GetIpInterfaceEntry, InitializeIpInterfaceEntry, SetIpInterfaceEntry,
+            MIB_IF_ROW2, MIB_IPINTERFACE_ROW,
+        },
+        winerror::{ERROR_BUFFER_OVERFLOW, ERROR_FILE_NOT_FOUND, NO_ERROR},
+        ws2def::{ADDRESS_FAMILY, AF_INET, AF_INET6},
     },
     um::libloaderapi::{
         FreeLibrary, GetProcAddress, LoadLibraryExW, LOAD_WITH_ALTERED_SEARCH_PATH,
@@ -37,17 +45,85 @@ type WintunDeleteAdapterFn = unsafe extern "stdcall" fn(
     reboot_required: *mut BOOL,
 ) -> BOOL;
 
+type WintunGetAdapterLuidFn = unsafe extern "stdcall" fn(adapter: RawHandle, luid: *mut NET_LUID);
+
+type WintunStartSessionFn =
+    unsafe extern "stdcall" fn(adapter: RawHandle, capacity: u32) -> RawHandle;
+
+type WintunEndSessionFn = unsafe extern "stdcall" fn(session: RawHandle);
+
+type WintunAllocateSendPacketFn =
+    unsafe extern "stdcall" fn(session: RawHandle, packet_size: u32) -> *mut u8;
+
+type WintunSendPacketFn = unsafe extern "stdcall" fn(session: RawHandle, packet: *const u8);
+
+/// Maximum size of a packet that can be sent or received by a Wintun session.
+pub const WINTUN_MAX_IP_PACKET_SIZE: usize = 0xFFFF;
+
+/// Exports that `WintunDll` requires.
+const REQUIRED_EXPORTS: &[&str] = &[
+    "WintunOpenAdapter",
+    "WintunCreateAdapter",
+    "WintunDeleteAdapter",
+    "WintunFreeAdapter",
+    "WintunGetAdapterLUID",
+];
+
+/// Exports that are not required, but whose presence helps identify the version of the DLL.
+const OPTIONAL_EXPORTS: &[&str] = &[
+    "WintunGetRunningDriverVersion",
+    "WintunStartSession",
+    "WintunEndSession",
+    "WintunAllocateSendPacket",
+    "WintunSendPacket",
+];
+
 
 pub struct WintunDll {
-    handle: HINSTANCE,
+    _handle: DllHandle,
     func_open: WintunOpenAdapterFn,
     func_create: WintunCreateAdapterFn,
     func_free: WintunFreeAdapterFn,
     func_delete: WintunDeleteAdapterFn,
+    func_get_luid: WintunGetAdapterLuidFn,
+    session_funcs: Option<SessionFuncs>,
+    loaded_symbols: Vec<&'static str>,
+}
+
+/// Functions for exchanging packets, which older versions of the DLL don't export.
+struct SessionFuncs {
+    start: WintunStartSessionFn,
+    end: WintunEndSessionFn,
+    allocate_send_packet: WintunAllocateSendPacketFn,
+    send_packet: WintunSendPacketFn,
 }
 
 unsafe impl Sync for WintunDll {}
 
+/// Module handle of the loaded DLL. Only handles loaded by `WintunDll` itself are freed on drop.
+enum DllHandle {
+    /// Loaded by `WintunDll::new` and freed when dropped.
+    Owned(HINSTANCE),
+    /// Provided by the caller, who remains responsible for freeing it.
+    Borrowed(HINSTANCE),
+}
+
+impl DllHandle {
+    fn raw(&self) -> HINSTANCE {
+        match *self {
+            DllHandle::Owned(handle) | DllHandle::Borrowed(handle) => handle,
+        }
+    }
+}
+
+impl Drop for DllHandle {
+    fn drop(&mut self) {
+        if let DllHandle::Owned(handle) = *self {
+            unsafe { FreeLibrary(handle) };
+        }
+    }
+}
+
 type RebootRequired = bool;
 
 /// A new Wintun adapter that is destroyed when dropped.
@@ -124,6 +200,78 @@ impl WintunAdapter {
                 .delete_adapter(self.handle, force_close_sessions)
         }
     }
+
+    /// Returns the LUID of the adapter.
+    pub fn luid(&self) -> NET_LUID {
+        unsafe { self.dll_handle.get_adapter_luid(self.handle) }
+    }
+
+    /// Returns the traffic counters of the adapter. Fails with `io::ErrorKind::NotFound` if the
+    /// adapter has been removed from the system.
+    pub fn statistics(&self) -> io::Result<AdapterStats> {
+        let mut row: MIB_IF_ROW2 = unsafe { std::mem::zeroed() };
+        row.InterfaceLuid = self.luid();
+
+        let status = unsafe { GetIfEntry2(&mut row) };
+        if status == ERROR_FILE_NOT_FOUND {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "The Wintun adapter no longer exists",
+            ));
+        }
+        if status != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(status as i32));
+        }
+        Ok(AdapterStats::from(&row))
+    }
+
+    /// Sets the MTU of both the IPv4 and IPv6 interfaces of the adapter.
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        self.set_ipv4_mtu(mtu)?;
+        self.set_ipv6_mtu(mtu)
+    }
+
+    /// Sets the MTU of the IPv4 interface of the adapter.
+    pub fn set_ipv4_mtu(&self, mtu: u32) -> io::Result<()> {
+        self.set_family_mtu(AF_INET as ADDRESS_FAMILY, mtu)
+    }
+
+    /// Sets the MTU of the IPv6 interface of the adapter. Note that IPv6 requires an MTU of at
+    /// least 1280.
+    pub fn set_ipv6_mtu(&self, mtu: u32) -> io::Result<()> {
+        self.set_family_mtu(AF_INET6 as ADDRESS_FAMILY, mtu)
+    }
+
+    /// Starts a session for exchanging packets with the adapter. `capacity` is the size of the
+    /// rings in bytes, and must be a power of two between 128 KiB and 64 MiB.
+    pub fn start_session(&self, capacity: u32) -> io::Result<WintunSession<'_>> {
+        Ok(WintunSession {
+            handle: unsafe { self.dll_handle.start_session(self.handle, capacity)? },
+            dll_handle: self.dll_handle.clone(),
+            _adapter: PhantomData,
+        })
+    }
+
+    fn set_family_mtu(&self, family: ADDRESS_FAMILY, mtu: u32) -> io::Result<()> {
+        let mut row: MIB_IPINTERFACE_ROW = unsafe { std::mem::zeroed() };
+        unsafe { InitializeIpInterfaceEntry(&mut row) };
+        row.Family = family;
+        row.InterfaceLuid = self.luid();
+
+        let status = unsafe { GetIpInterfaceEntry(&mut row) };
+        if status != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(status as i32));
+        }
+
+        row.NlMtu = mtu;
+        // SitePrefixLength must be zero when setting an IPv4 interface entry
+        row.SitePrefixLength = 0;
+        let status = unsafe { SetIpInterfaceEntry(&mut row) };
+        if status != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(status as i32));
+        }
+        Ok(())
+    }
 }
 
 impl Drop for WintunAdapter {
@@ -132,6 +280,107 @@ impl Drop for WintunAdapter {
     }
 }
 
+/// Traffic counters of a Wintun adapter, counted since the adapter was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterStats {
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    /// Number of received packets, including multicast and broadcast packets.
+    pub in_packets: u64,
+    /// Number of sent packets, including multicast and broadcast packets.
+    pub out_packets: u64,
+    pub in_errors: u64,
+    pub out_errors: u64,
+    pub in_discards: u64,
+    pub out_discards: u64,
+}
+
+impl From<&MIB_IF_ROW2> for AdapterStats {
+    fn from(row: &MIB_IF_ROW2) -> Self {
+        Self {
+            in_bytes: row.InOctets,
+            out_bytes: row.OutOctets,
+            in_packets: row.InUcastPkts + row.InNUcastPkts,
+            out_packets: row.OutUcastPkts + row.OutNUcastPkts,
+            in_errors: row.InErrors,
+            out_errors: row.OutErrors,
+            in_discards: row.InDiscards,
+            out_discards: row.OutDiscards,
+        }
+    }
+}
+
+/// Errors that can occur when sending a packet through a Wintun session.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum SendPacketError {
+    /// The packet is empty or larger than `WINTUN_MAX_IP_PACKET_SIZE`.
+    #[error(display = "Invalid packet size: {} bytes", _0)]
+    InvalidPacketSize(usize),
+
+    /// The send ring is full. The packet may be sent once the driver has consumed queued packets.
+    #[error(display = "The Wintun send ring is full")]
+    RingFull,
+
+    /// The packet could not be allocated for any other reason, such as the adapter going away.
+    #[error(display = "Failed to allocate Wintun packet")]
+    AllocatePacket(#[error(source)] io::Error),
+}
+
+/// A session for exchanging packets with a Wintun adapter, which is ended when dropped.
+pub struct WintunSession<'a> {
+    dll_handle: Arc<WintunDll>,
+    handle: RawHandle,
+    _adapter: PhantomData<&'a WintunAdapter>,
+}
+
+impl fmt::Debug for WintunSession<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WintunSession")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+unsafe impl Send for WintunSession<'_> {}
+
+impl WintunSession<'_> {
+    /// Sends an IP packet through the adapter. Fails with `SendPacketError::RingFull` if the send
+    /// ring is full, in which case the caller should back off and retry.
+    pub fn send_packet(&self, packet: &[u8]) -> Result<(), SendPacketError> {
+        let size = validate_packet_size(packet.len())?;
+        unsafe {
+            let buffer = self
+                .dll_handle
+                .allocate_send_packet(self.handle, size)
+                .map_err(|error| {
+                    if error.raw_os_error() == Some(ERROR_BUFFER_OVERFLOW as i32) {
+                        SendPacketError::RingFull
+                    } else {
+                        SendPacketError::AllocatePacket(error)
+                    }
+                })?;
+            ptr::copy_nonoverlapping(packet.as_ptr(), buffer, packet.len());
+            self.dll_handle.send_packet(self.handle, buffer);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WintunSession<'_> {
+    fn drop(&mut self) {
+        unsafe { self.dll_handle.end_session(self.handle) };
+    }
+}
+
+/// Returns the size of a packet of `len` bytes as expected by Wintun, if it can be sent.
+fn validate_packet_size(len: usize) -> Result<u32, SendPacketError> {
+    if len == 0 || len > WINTUN_MAX_IP_PACKET_SIZE {
+        return Err(SendPacketError::InvalidPacketSize(len));
+    }
+    Ok(len as u32)
+}
+
 impl WintunDll {
     pub fn new(resource_dir: &Path) -> io::Result<Self> {
         let wintun_dll: Vec<u16> = resource_dir
@@ -152,8 +401,19 @@ impl WintunDll {
             return Err(io::Error::last_os_error());
         }
 
+        Self::new_inner(DllHandle::Owned(handle))
+    }
+
+    /// Resolves the Wintun functions from a module that has already been loaded by the caller.
+    /// The module is not freed when the returned instance is dropped.
+    pub fn from_handle(handle: HINSTANCE) -> io::Result<Self> {
+        Self::new_inner(DllHandle::Borrowed(handle))
+    }
+
+    fn new_inner(dll_handle: DllHandle) -> io::Result<Self> {
+        let handle = dll_handle.raw();
+        let loaded_symbols = Self::find_exports(handle);
         Ok(WintunDll {
-            handle,
             func_open: unsafe {
                 std::mem::transmute(Self::get_proc_address(
                     handle,
@@ -178,9 +438,67 @@ impl WintunDll {
                     CStr::from_bytes_with_nul(b"WintunFreeAdapter\0").unwrap(),
                 )?)
             },
+            func_get_luid: unsafe {
+                std::mem::transmute(Self::get_proc_address(
+                    handle,
+                    CStr::from_bytes_with_nul(b"WintunGetAdapterLUID\0").unwrap(),
+                )?)
+            },
+            session_funcs: Self::get_session_funcs(handle),
+            loaded_symbols,
+            _handle: dll_handle,
+        })
+    }
+
+    fn get_session_funcs(handle: HMODULE) -> Option<SessionFuncs> {
+        let get = |name: &[u8]| unsafe {
+            Self::get_proc_address(handle, CStr::from_bytes_with_nul(name).unwrap()).ok()
+        };
+        unsafe {
+            Some(SessionFuncs {
+                start: std::mem::transmute(get(b"WintunStartSession\0")?),
+                end: std::mem::transmute(get(b"WintunEndSession\0")?),
+                allocate_send_packet: std::mem::transmute(get(b"WintunAllocateSendPacket\0")?),
+                send_packet: std::mem::transmute(get(b"WintunSendPacket\0")?),
+            })
+        }
+    }
+
+    fn session_funcs(&self) -> io::Result<&SessionFuncs> {
+        self.session_funcs.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "The Wintun DLL does not support sessions",
+            )
         })
     }
 
+    /// Returns the names of the required and optional exports that were found in the DLL.
+    pub fn loaded_symbols(&self) -> &[&'static str] {
+        &self.loaded_symbols
+    }
+
+    fn find_exports(handle: HMODULE) -> Vec<&'static str> {
+        let mut found = vec![];
+        for (name, required) in REQUIRED_EXPORTS
+            .iter()
+            .map(|name| (name, true))
+            .chain(OPTIONAL_EXPORTS.iter().map(|name| (name, false)))
+        {
+            let c_name = CString::new(*name).unwrap();
+            let exists = unsafe { GetProcAddress(handle, c_name.as_ptr()) } != ptr::null_mut();
+            match (exists, required) {
+                (true, _) => {
+                    log::debug!("Found Wintun export {}", name);
+                    found.push(*name);
+                }
+                (false, true) => log::debug!("Missing required Wintun export {}", name),
+                (false, false) => log::debug!("Missing optional Wintun export {}", name),
+            }
+        }
+        found
+    }
+
     unsafe fn get_proc_address(handle: HMODULE, name: &CStr) -> io::Result<FARPROC> {
         let handle = GetProcAddress(handle, name.as_ptr());
         if handle == ptr::null_mut() {
@@ -189,6 +507,12 @@ impl WintunDll {
         Ok(handle)
     }
 
+    pub unsafe fn get_adapter_luid(&self, adapter: RawHandle) -> NET_LUID {
+        let mut luid: NET_LUID = std::mem::zeroed();
+        (self.func_get_luid)(adapter, &mut luid);
+        luid
+    }
+
     pub fn open_adapter(&self, pool: &U16CStr, name: &U16CStr) -> io::Result<RawHandle> {
         let handle = unsafe { (self.func_open)(pool.as_ptr(), name.as_ptr()) };
         if handle == ptr::null_mut() {
@@ -234,10 +558,133 @@ impl WintunDll {
     pub unsafe fn free_adapter(&self, adapter: RawHandle) {
         (self.func_free)(adapter);
     }
+
+    /// Opens and deletes the adapter `name` in `pool`. Returns whether a reboot is required to
+    /// complete the deletion, or `false` if there is no such adapter.
+    pub fn delete_adapter_by_name(
+        &self,
+        pool: &U16CStr,
+        name: &U16CStr,
+        force_close_sessions: bool,
+    ) -> io::Result<RebootRequired> {
+        let adapter = match self.open_adapter(pool, name) {
+            Ok(adapter) => adapter,
+            Err(error) if error.raw_os_error() == Some(ERROR_FILE_NOT_FOUND as i32) => {
+                return Ok(false);
+            }
+            Err(error) => return Err(error),
+        };
+        let result = unsafe { self.delete_adapter(adapter, force_close_sessions) };
+        unsafe { self.free_adapter(adapter) };
+        result
+    }
+
+    pub unsafe fn start_session(&self, adapter: RawHandle, capacity: u32) -> io::Result<RawHandle> {
+        let handle = (self.session_funcs()?.start)(adapter, capacity);
+        if handle == ptr::null_mut() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(handle)
+    }
+
+    pub unsafe fn end_session(&self, session: RawHandle) {
+        if let Some(funcs) = &self.session_funcs {
+            (funcs.end)(session);
+        }
+    }
+
+    pub unsafe fn allocate_send_packet(
+        &self,
+        session: RawHandle,
+        packet_size: u32,
+    ) -> io::Result<*mut u8> {
+        let packet = (self.session_funcs()?.allocate_send_packet)(session, packet_size);
+        if packet.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(packet)
+    }
+
+    pub unsafe fn send_packet(&self, session: RawHandle, packet: *const u8) {
+        if let Some(funcs) = &self.session_funcs {
+            (funcs.send_packet)(session, packet);
+        }
+    }
 }
 
-impl Drop for WintunDll {
-    fn drop(&mut self) {
-        unsafe { FreeLibrary(self.handle) };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winapi::um::libloaderapi::GetModuleHandleW;
+
+    fn to_wide(s: &str) -> Vec<u16> {
+        std::ffi::OsStr::new(s)
+            .encode_wide()
+            .chain(iter::once(0u16))
+            .collect()
+    }
+
+    #[test]
+    fn test_borrowed_handle_is_not_freed() {
+        let name = to_wide("version.dll");
+        let module = unsafe { LoadLibraryExW(name.as_ptr(), ptr::null_mut(), 0) };
+        assert!(!module.is_null());
+
+        drop(DllHandle::Borrowed(module));
+        assert!(!unsafe { GetModuleHandleW(name.as_ptr()) }.is_null());
+
+        drop(DllHandle::Owned(module));
+    }
+
+    #[test]
+    fn test_find_exports_without_wintun() {
+        let name = to_wide("version.dll");
+        let module = unsafe { LoadLibraryExW(name.as_ptr(), ptr::null_mut(), 0) };
+        assert!(!module.is_null());
+
+        assert!(WintunDll::find_exports(module).is_empty());
+        assert!(WintunDll::from_handle(module).is_err());
+
+        unsafe { FreeLibrary(module) };
+    }
+
+    #[test]
+    fn test_adapter_stats_from_row() {
+        let mut row: MIB_IF_ROW2 = unsafe { std::mem::zeroed() };
+        row.InOctets = 1000;
+        row.OutOctets = 2000;
+        row.InUcastPkts = 10;
+        row.InNUcastPkts = 2;
+        row.OutUcastPkts = 20;
+        row.OutNUcastPkts = 1;
+        row.InDiscards = 3;
+        row.OutErrors = 4;
+
+        assert_eq!(
+            AdapterStats::from(&row),
+            AdapterStats {
+                in_bytes: 1000,
+                out_bytes: 2000,
+                in_packets: 12,
+                out_packets: 21,
+                in_errors: 0,
+                out_errors: 4,
+                in_discards: 3,
+                out_discards: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_packet_size_validation() {
+        assert_eq!(validate_packet_size(1500).unwrap(), 1500);
+        assert!(matches!(
+            validate_packet_size(WINTUN_MAX_IP_PACKET_SIZE + 1),
+            Err(SendPacketError::InvalidPacketSize(size)) if size == WINTUN_MAX_IP_PACKET_SIZE + 1
+        ));
+        assert!(matches!(
+            validate_packet_size(0),
+            Err(SendPacketError::InvalidPacketSize(0))
+        ));
     }
 }